@@ -0,0 +1,346 @@
+use actix_web::{web, HttpMessage, HttpRequest};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::Deserialize;
+
+use crate::api_tokens::revoke_all_api_tokens;
+use crate::auth::{hash_password, verify_password, SessionUser};
+use crate::blobs::BlobStore;
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::i18n::{Locale, Message, PreferredLocale};
+use crate::rate_limit;
+use crate::repository::{Accounts, Repositories};
+use crate::sessions::revoke_other_sessions;
+use crate::uploads::release_blobs;
+use crate::validation::{
+    normalize_email, normalize_username, validate_email, validate_password, validate_username, Validate,
+    ValidationErrors,
+};
+
+// Answered instead of the generic conflict when the unique username index rejects a write
+pub const USERNAME_TAKEN: AppError = AppError::Conflict("username_taken");
+
+// Request structures
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    old_password: String,
+    new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeUsernameRequest {
+    new_username: String,
+}
+
+impl Validate for ChangeUsernameRequest {
+    fn validate(&self) -> std::result::Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_username("new_username", &self.new_username, &mut errors);
+        errors.into_result()
+    }
+}
+
+// A null email removes it from the account
+#[derive(Debug, Deserialize)]
+pub struct ChangeEmailRequest {
+    email: Option<String>,
+}
+
+impl Validate for ChangeEmailRequest {
+    fn validate(&self) -> std::result::Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(email) = &self.email {
+            validate_email("email", email, &mut errors);
+        }
+        errors.into_result()
+    }
+}
+
+// A null language goes back to following Accept-Language
+#[derive(Debug, Deserialize)]
+pub struct ChangeLanguageRequest {
+    language: Option<String>,
+}
+
+impl Validate for ChangeLanguageRequest {
+    fn validate(&self) -> std::result::Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if self.language.as_deref().is_some_and(|language| Locale::from_tag(language).is_none()) {
+            errors.add("language", "language_unsupported");
+        }
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountRequest {
+    password: String,
+}
+
+// Handler functions
+pub async fn change_password(
+    user: SessionUser,
+    change_info: web::Json<ChangePasswordRequest>,
+    accounts: web::Data<Accounts>,
+    config: web::Data<Config>,
+) -> AppResult<Message> {
+    let ChangePasswordRequest { old_password, new_password } = change_info.into_inner();
+    if !password_matches(&accounts, user.user_id, old_password).await? {
+        return Err(AppError::Unauthorized("wrong_password"));
+    }
+
+    let username = accounts.users.username(user.user_id).await?.unwrap_or_default();
+    let mut errors = ValidationErrors::default();
+    validate_password("new_password", &new_password, &username, &mut errors);
+    errors.into_result()?;
+
+    let password_hash = hash_password(new_password, config.bcrypt_cost).await?;
+    accounts.users.update_password(user.user_id, password_hash, user.session_id).await?;
+    Ok(Message("password_changed"))
+}
+
+pub async fn change_username(
+    user: SessionUser,
+    change_info: web::Json<ChangeUsernameRequest>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<Message> {
+    change_info.validate()?;
+
+    let new_username = normalize_username(&change_info.new_username);
+    match accounts.users.update_username(user.user_id, new_username).await.map_err(AppError::from) {
+        Ok(_) => Ok(Message("username_changed")),
+        Err(AppError::Conflict(..)) => Err(USERNAME_TAKEN),
+        Err(error) => Err(error),
+    }
+}
+
+pub async fn change_email(
+    user: SessionUser,
+    change_info: web::Json<ChangeEmailRequest>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<Message> {
+    change_info.validate()?;
+
+    let email = change_info.email.as_deref().map(normalize_email);
+    accounts.users.update_email(user.user_id, email).await?;
+    Ok(Message("email_changed"))
+}
+
+pub async fn change_language(
+    req: HttpRequest,
+    user: SessionUser,
+    change_info: web::Json<ChangeLanguageRequest>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<Message> {
+    change_info.validate()?;
+
+    let locale = change_info.language.as_deref().and_then(Locale::from_tag);
+    accounts.users.update_locale(user.user_id, locale).await?;
+
+    // The confirmation already comes in the new language
+    match locale {
+        Some(locale) => req.extensions_mut().insert(PreferredLocale(locale)),
+        None => req.extensions_mut().remove::<PreferredLocale>(),
+    };
+    Ok(Message("language_changed"))
+}
+
+pub async fn delete_account(
+    user: SessionUser,
+    delete_info: web::Json<DeleteAccountRequest>,
+    accounts: web::Data<Accounts>,
+    repos: web::Data<Repositories>,
+    blobs: web::Data<dyn BlobStore>,
+) -> AppResult<Message> {
+    if !password_matches(&accounts, user.user_id, delete_info.into_inner().password).await? {
+        return Err(AppError::Unauthorized("wrong_password"));
+    }
+
+    // Removing the user cascades to its subjects' files, so their blobs have to be known before
+    let hashes = repos.files.hashes_for_user(user.user_id).await?;
+    accounts.users.delete(user.user_id).await?;
+    release_blobs(&repos, &blobs, hashes).await;
+    Ok(Message("account_deleted"))
+}
+
+// Guesses at the current password are limited per user like logins, so a stolen session cannot
+// be used to find it out
+async fn password_matches(accounts: &Accounts, user_id: i32, password: String) -> AppResult<bool> {
+    let key = rate_limit::password_key(user_id);
+    rate_limit::reserve(accounts, &[(&key, &rate_limit::LOGIN_PER_USERNAME)]).await?;
+    let password_hash = accounts.users.password_hash(user_id).await?.unwrap_or_default();
+    if !verify_password(password, password_hash).await {
+        return Ok(false);
+    }
+    accounts.login_attempts.clear(key).await?;
+    Ok(true)
+}
+
+// Database functions
+pub fn find_password_hash(conn: &Connection, user_id: i32) -> Result<Option<String>> {
+    conn.query_row("SELECT password_hash FROM users WHERE id = ?1", [user_id], |row| row.get(0))
+        .optional()
+}
+
+pub fn find_username(conn: &Connection, user_id: i32) -> Result<Option<String>> {
+    conn.query_row("SELECT username FROM users WHERE id = ?1", [user_id], |row| row.get(0))
+        .optional()
+}
+
+// Every other device has to log in again with the new password, and API tokens have to be created anew
+pub fn modify_password(conn: &Connection, user_id: i32, keep_session_id: i64, password_hash: &str) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+        params![password_hash, user_id],
+    )?;
+    revoke_other_sessions(&tx, user_id, keep_session_id)?;
+    revoke_all_api_tokens(&tx, user_id)?;
+    tx.commit()
+}
+
+pub fn modify_username(conn: &Connection, user_id: i32, new_username: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET username = ?1 WHERE id = ?2",
+        params![new_username, user_id],
+    )?;
+    Ok(())
+}
+
+pub fn modify_email(conn: &Connection, user_id: i32, email: Option<&str>) -> Result<()> {
+    conn.execute("UPDATE users SET email = ?1 WHERE id = ?2", params![email, user_id])?;
+    Ok(())
+}
+
+// Delete the user; everything it owns goes with it through ON DELETE CASCADE
+pub fn remove_user(conn: &Connection, user_id: i32) -> Result<()> {
+    conn.execute("DELETE FROM users WHERE id = ?1", [user_id])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::JwtKeys;
+    use crate::error::RequestId;
+    use crate::sessions::tests::login_token;
+    use crate::tests::{app, bearer, fixture, PASSWORD};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body, call_and_read_body_json, call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn stored_language_beats_accept_language() {
+        let pool = crate::db::test_pool();
+        let keys = web::Data::new(JwtKeys::from_secret(b"test-secret"));
+        let token = {
+            let conn = pool.get().unwrap();
+            conn.execute("INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', 'x')", [])
+                .unwrap();
+            login_token(&conn, &keys, 1)
+        };
+        let app = init_service(
+            App::new()
+                .wrap(RequestId)
+                .app_data(web::Data::new(Repositories::sqlite(pool.clone())))
+                .app_data(web::Data::new(Accounts::sqlite(pool.clone())))
+                .app_data(keys.clone())
+                .configure(crate::configure_routes),
+        )
+        .await;
+        let bearer = ("Authorization", format!("Bearer {}", token));
+
+        // Without a preference the header decides
+        let req = TestRequest::delete()
+            .uri("/delete_task/999")
+            .insert_header(("Accept-Language", "en-US,en;q=0.9"))
+            .insert_header(bearer.clone())
+            .to_request();
+        let body: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(body["code"], "resource_not_found");
+        assert_eq!(body["message"], "Resource not found");
+
+        let req = TestRequest::post()
+            .uri("/change_language")
+            .insert_header(bearer.clone())
+            .set_json(serde_json::json!({ "language": "klingon" }))
+            .to_request();
+        let body: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(body["errors"][0]["code"], "language_unsupported");
+        assert_eq!(body["errors"][0]["message"], "El idioma no está disponible");
+
+        let req = TestRequest::post()
+            .uri("/change_language")
+            .insert_header(bearer.clone())
+            .set_json(serde_json::json!({ "language": "en" }))
+            .to_request();
+        assert_eq!(call_and_read_body(&app, req).await, "Language updated successfully");
+
+        let req = TestRequest::delete()
+            .uri("/delete_task/999")
+            .insert_header(("Accept-Language", "es"))
+            .insert_header(bearer)
+            .to_request();
+        let body: serde_json::Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(body["message"], "Resource not found");
+    }
+
+    #[actix_web::test]
+    async fn changing_the_password_signs_out_everything_else() {
+        let f = fixture();
+        let other_session = login_token(&f.pool.get().unwrap(), &f.keys, 1);
+        let app = app!(f);
+        let req = TestRequest::post()
+            .uri("/api_tokens")
+            .insert_header(bearer(&f.alice_token))
+            .set_json(serde_json::json!({ "name": "script", "access": "read", "area": "tasks" }))
+            .to_request();
+        let created: serde_json::Value = call_and_read_body_json(&app, req).await;
+        let api_token = created["token"].as_str().unwrap().to_string();
+
+        let change = |old_password: &str| {
+            TestRequest::post()
+                .uri("/change_password")
+                .insert_header(bearer(&f.alice_token))
+                .set_json(serde_json::json!({ "old_password": old_password, "new_password": "otra-clave-larga-42" }))
+                .to_request()
+        };
+        assert_eq!(call_service(&app, change(PASSWORD)).await.status(), StatusCode::OK);
+        let tasks = |token: &str| TestRequest::get().uri("/get_tasks").insert_header(bearer(token)).to_request();
+        assert_eq!(call_service(&app, tasks(&f.alice_token)).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, tasks(&other_session)).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call_service(&app, tasks(&api_token)).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Guessing the current password runs into the same limit as logging in
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            statuses.push(call_service(&app, change("adivinanza")).await.status());
+        }
+        assert_eq!(statuses[..4], [StatusCode::UNAUTHORIZED; 4]);
+        assert_eq!(statuses[4], StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn remove_user_cascades_to_owned_rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::migrations::run(&conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', 'x'), (2, 'bob', 'x');
+             INSERT INTO subjects (id, name, user_id) VALUES (10, 'Algebra', 1), (11, 'Fisica', 2);
+             INSERT INTO tasks (title, status, note, user_id) VALUES ('TP', 'Pendiente', '', 1), ('TP', 'Pendiente', '', 2);
+             INSERT INTO notes (subject_id, content) VALUES (10, 'a'), (11, 'b');
+             INSERT INTO exam_dates (subject_id, date) VALUES (10, 'a'), (11, 'b');
+             INSERT INTO file_links (subject_id, url) VALUES (10, 'a'), (11, 'b');",
+        )
+        .unwrap();
+
+        remove_user(&conn, 1).unwrap();
+
+        for table in ["users", "subjects", "tasks", "notes", "exam_dates", "file_links"] {
+            let remaining: i64 = conn
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(remaining, 1, "{}", table);
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest, HttpResponse};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::account::{find_username, remove_user};
+use crate::api_tokens::revoke_all_api_tokens;
+use crate::auth::{now_secs, AuthFuture, SessionUser};
+use crate::blobs::BlobStore;
+use crate::error::{AppError, AppResult};
+use crate::i18n::{Locale, Message};
+use crate::mail::MailTransport;
+use crate::password_reset::reset_email;
+use crate::repository::{AccountStatus, Accounts, Repositories};
+use crate::sessions::revoke_all_sessions;
+use crate::uploads::release_blobs;
+
+pub const ROLE_ADMIN: &str = "admin";
+
+pub const AUDIT_LOG_PAGE_SIZE: i64 = 100;
+
+const USER_NOT_FOUND: AppError = AppError::NotFound("user_not_found");
+
+// A logged-in user with the admin role; add it as a handler argument to restrict a route to admins.
+// API tokens are never accepted here.
+#[derive(Debug, Clone, Copy)]
+pub struct AdminUser {
+    pub user_id: i32,
+}
+
+impl FromRequest for AdminUser {
+    type Error = AppError;
+    type Future = AuthFuture<Self>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = SessionUser::from_request(req, payload);
+        let accounts = req.app_data::<web::Data<Accounts>>().cloned();
+        Box::pin(async move {
+            let user = user.await?;
+            let accounts = accounts.ok_or_else(|| AppError::internal("Accounts are not registered as app data"))?;
+            match accounts.users.status(user.user_id).await? {
+                Some(status) if status.role == ROLE_ADMIN => Ok(AdminUser { user_id: user.user_id }),
+                _ => Err(AppError::Forbidden("admin_required")),
+            }
+        })
+    }
+}
+
+// UserSummary data structure, as listed to admins
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub role: String,
+    pub disabled: bool,
+    pub must_reset_password: bool,
+    pub task_count: i64,
+    pub subject_count: i64,
+    pub active_session_count: i64,
+    pub last_seen_at: Option<i64>,
+}
+
+// AuditEntry data structure
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub admin_id: i32,
+    pub action: String,
+    pub target_user_id: Option<i32>,
+    pub details: Option<String>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditLogQuery {
+    before_id: Option<i64>,
+}
+
+// Handler functions
+pub async fn list_users(
+    _admin: AdminUser,
+    accounts: web::Data<Accounts>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    let mut users = accounts.admin.user_summaries().await?;
+    for user in &mut users {
+        user.task_count = repos.tasks.count_for_user(user.id).await?;
+        user.subject_count = repos.subjects.count_for_user(user.id).await?;
+    }
+    Ok(HttpResponse::Ok().json(users))
+}
+
+pub async fn disable_user(
+    admin: AdminUser,
+    user_id: web::Path<i32>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<Message> {
+    let target_id = user_id.into_inner();
+    if target_id == admin.user_id {
+        return Err(AppError::BadRequest("cannot_disable_self"));
+    }
+
+    if !accounts.admin.disable(admin.user_id, target_id).await? {
+        return Err(USER_NOT_FOUND);
+    }
+    Ok(Message("user_disabled"))
+}
+
+pub async fn enable_user(
+    admin: AdminUser,
+    user_id: web::Path<i32>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<Message> {
+    let target_id = user_id.into_inner();
+    if !accounts.admin.enable(admin.user_id, target_id).await? {
+        return Err(USER_NOT_FOUND);
+    }
+    Ok(Message("user_enabled"))
+}
+
+pub async fn force_password_reset(
+    admin: AdminUser,
+    user_id: web::Path<i32>,
+    accounts: web::Data<Accounts>,
+    mailer: web::Data<dyn MailTransport>,
+) -> AppResult<Message> {
+    let target_id = user_id.into_inner();
+
+    let (username, email) = accounts
+        .admin
+        .require_password_reset(admin.user_id, target_id)
+        .await?
+        .ok_or(USER_NOT_FOUND)?;
+    let email = match email {
+        Some(email) => email,
+        None => return Ok(Message("password_reset_forced_without_email")),
+    };
+
+    let token = accounts.password_resets.create(target_id).await?;
+    // Written for the user, not for the admin who asked for it
+    let locale = accounts.users.locale(target_id).await?.unwrap_or(Locale::DEFAULT);
+    let email = reset_email(&email, &username, &token, locale);
+    web::block(move || mailer.send(&email))
+        .await
+        .map_err(AppError::internal)?
+        .map_err(AppError::internal)?;
+    Ok(Message("password_reset_forced"))
+}
+
+pub async fn delete_user(
+    admin: AdminUser,
+    user_id: web::Path<i32>,
+    accounts: web::Data<Accounts>,
+    repos: web::Data<Repositories>,
+    blobs: web::Data<dyn BlobStore>,
+) -> AppResult<Message> {
+    let target_id = user_id.into_inner();
+    if target_id == admin.user_id {
+        return Err(AppError::BadRequest("cannot_delete_self"));
+    }
+
+    // Deleting the account cascades to its subjects' files, so their blobs have to be known before
+    let hashes = repos.files.hashes_for_user(target_id).await?;
+    if !accounts.admin.delete_user(admin.user_id, target_id).await? {
+        return Err(USER_NOT_FOUND);
+    }
+    release_blobs(&repos, &blobs, hashes).await;
+    Ok(Message("user_deleted"))
+}
+
+pub async fn get_audit_log(
+    _admin: AdminUser,
+    query: web::Query<AuditLogQuery>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<HttpResponse> {
+    let entries = accounts.admin.audit_log(query.before_id).await?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+// Why the user may not start a new session, if anything stops them
+pub fn login_blocked(status: &AccountStatus) -> Option<AppError> {
+    if status.disabled {
+        Some(AppError::Forbidden("account_disabled"))
+    } else if status.must_reset_password {
+        Some(AppError::Forbidden("password_reset_required"))
+    } else {
+        None
+    }
+}
+
+// Database functions
+
+// Give the admin role to ADMIN_USERNAME, so the first admin does not need the database by hand
+pub fn promote(conn: &Connection, username: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET role = ?1 WHERE username = ?2 COLLATE NOCASE",
+        params![ROLE_ADMIN, username],
+    )?;
+    Ok(())
+}
+
+pub fn find_status(conn: &Connection, user_id: i32) -> Result<Option<AccountStatus>> {
+    conn.query_row(
+        "SELECT role, disabled, must_reset_password FROM users WHERE id = ?1",
+        [user_id],
+        |row| {
+            Ok(AccountStatus {
+                role: row.get(0)?,
+                disabled: row.get(1)?,
+                must_reset_password: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Returns (username, email) of the user, if it exists
+fn find_recipient(conn: &Connection, user_id: i32) -> Result<Option<(String, Option<String>)>> {
+    conn.query_row(
+        "SELECT username, email FROM users WHERE id = ?1",
+        [user_id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )
+    .optional()
+}
+
+// Task and subject counts are filled in by the caller from the repositories
+pub fn find_user_summaries(conn: &Connection) -> Result<Vec<UserSummary>> {
+    let mut stmt = conn.prepare(
+        "SELECT users.id, users.username, users.email, users.role, users.disabled, users.must_reset_password,
+                (SELECT COUNT(*) FROM sessions
+                 WHERE sessions.user_id = users.id AND revoked_at IS NULL AND expires_at > ?1),
+                (SELECT MAX(last_seen_at) FROM sessions WHERE sessions.user_id = users.id)
+         FROM users
+         ORDER BY users.id",
+    )?;
+    let user_iter = stmt.query_map([now_secs()], |row| {
+        Ok(UserSummary {
+            id: row.get(0)?,
+            username: row.get(1)?,
+            email: row.get(2)?,
+            role: row.get(3)?,
+            disabled: row.get(4)?,
+            must_reset_password: row.get(5)?,
+            task_count: 0,
+            subject_count: 0,
+            active_session_count: row.get(6)?,
+            last_seen_at: row.get(7)?,
+        })
+    })?;
+    user_iter.collect()
+}
+
+// Disabling also ends every session and API token, so the account is locked out right away.
+// Returns false if the user does not exist.
+pub fn disable_account(conn: &Connection, admin_id: i32, user_id: i32) -> Result<bool> {
+    let tx = conn.unchecked_transaction()?;
+    if tx.execute("UPDATE users SET disabled = 1 WHERE id = ?1", [user_id])? == 0 {
+        return Ok(false);
+    }
+    revoke_all_sessions(&tx, user_id)?;
+    revoke_all_api_tokens(&tx, user_id)?;
+    record_audit(&tx, admin_id, "disable_user", Some(user_id), None)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+pub fn enable_account(conn: &Connection, admin_id: i32, user_id: i32) -> Result<bool> {
+    let tx = conn.unchecked_transaction()?;
+    if tx.execute("UPDATE users SET disabled = 0 WHERE id = ?1", [user_id])? == 0 {
+        return Ok(false);
+    }
+    record_audit(&tx, admin_id, "enable_user", Some(user_id), None)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+// Log the user out everywhere and keep them out until they reset their password.
+// Returns (username, email) of the user, or None if it does not exist.
+pub fn require_password_reset(
+    conn: &Connection,
+    admin_id: i32,
+    user_id: i32,
+) -> Result<Option<(String, Option<String>)>> {
+    let tx = conn.unchecked_transaction()?;
+    let recipient = match find_recipient(&tx, user_id)? {
+        Some(recipient) => recipient,
+        None => return Ok(None),
+    };
+    tx.execute("UPDATE users SET must_reset_password = 1 WHERE id = ?1", [user_id])?;
+    revoke_all_sessions(&tx, user_id)?;
+    revoke_all_api_tokens(&tx, user_id)?;
+    record_audit(&tx, admin_id, "force_password_reset", Some(user_id), None)?;
+    tx.commit()?;
+    Ok(Some(recipient))
+}
+
+// The audit row outlives the account, so it keeps the username it had.
+// Returns false if the user does not exist.
+pub fn delete_account(conn: &Connection, admin_id: i32, user_id: i32) -> Result<bool> {
+    let tx = conn.unchecked_transaction()?;
+    let username = match find_username(&tx, user_id)? {
+        Some(username) => username,
+        None => return Ok(false),
+    };
+    remove_user(&tx, user_id)?;
+    record_audit(&tx, admin_id, "delete_user", Some(user_id), Some(&username))?;
+    tx.commit()?;
+    Ok(true)
+}
+
+fn record_audit(
+    conn: &Connection,
+    admin_id: i32,
+    action: &str,
+    target_user_id: Option<i32>,
+    details: Option<&str>,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO audit_log (admin_id, action, target_user_id, details, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![admin_id, action, target_user_id, details, now_secs()],
+    )?;
+    Ok(())
+}
+
+// Newest first, one page at a time
+pub fn find_audit_entries(conn: &Connection, before_id: Option<i64>) -> Result<Vec<AuditEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, admin_id, action, target_user_id, details, created_at FROM audit_log
+         WHERE id < ?1
+         ORDER BY id DESC
+         LIMIT ?2",
+    )?;
+    let entry_iter = stmt.query_map(params![before_id.unwrap_or(i64::MAX), AUDIT_LOG_PAGE_SIZE], |row| {
+        Ok(AuditEntry {
+            id: row.get(0)?,
+            admin_id: row.get(1)?,
+            action: row.get(2)?,
+            target_user_id: row.get(3)?,
+            details: row.get(4)?,
+            created_at: row.get(5)?,
+        })
+    })?;
+    entry_iter.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{app, bearer, fixture};
+    use actix_web::{http::StatusCode, test};
+
+    #[actix_web::test]
+    async fn admin_actions_are_restricted_and_audited() {
+        // Alice administers, bob is the one acted on
+        let f = fixture();
+        f.pool
+            .get()
+            .unwrap()
+            .execute_batch(
+                "UPDATE users SET role = 'admin' WHERE id = 1;
+                 UPDATE users SET email = 'bob@example.com' WHERE id = 2;
+                 INSERT INTO tasks (title, status, note, user_id) VALUES ('TP', 'Pendiente', '', 2);",
+            )
+            .unwrap();
+        let app = app!(f);
+        let call = |method: test::TestRequest, uri: &str, token: &str| {
+            method.uri(uri).insert_header(bearer(token)).to_request()
+        };
+        let (admin_token, bob_token) = (f.alice_token.as_str(), f.bob_token.as_str());
+
+        let resp = test::call_service(&app, call(test::TestRequest::get(), "/admin/users", bob_token)).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let users: serde_json::Value =
+            test::call_and_read_body_json(&app, call(test::TestRequest::get(), "/admin/users", admin_token)).await;
+        assert_eq!(users[1]["username"], "bob");
+        assert_eq!(users[1]["task_count"], 1);
+        assert_eq!(users[1]["active_session_count"], 1);
+
+        let resp = test::call_service(&app, call(test::TestRequest::post(), "/admin/users/2/disable", admin_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // Bob's session died with the account
+        let resp = test::call_service(&app, call(test::TestRequest::get(), "/get_tasks", bob_token)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let status = || find_status(&f.pool.get().unwrap(), 2).unwrap().unwrap();
+        assert_eq!(login_blocked(&status()).map(|error| error.code()), Some("account_disabled"));
+
+        let resp = test::call_service(&app, call(test::TestRequest::post(), "/admin/users/2/enable", admin_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(
+            &app,
+            call(test::TestRequest::post(), "/admin/users/2/force_password_reset", admin_token),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(std::fs::read_dir(f.dir.join("outbox")).unwrap().count(), 1);
+        assert!(login_blocked(&status()).is_some());
+
+        let resp = test::call_service(&app, call(test::TestRequest::delete(), "/admin/users/2", admin_token)).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let log: serde_json::Value =
+            test::call_and_read_body_json(&app, call(test::TestRequest::get(), "/admin/audit_log", admin_token)).await;
+        let actions: Vec<&str> = log.as_array().unwrap().iter().map(|e| e["action"].as_str().unwrap()).collect();
+        assert_eq!(actions, vec!["delete_user", "force_password_reset", "enable_user", "disable_user"]);
+    }
+}
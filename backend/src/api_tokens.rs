@@ -0,0 +1,317 @@
+use actix_web::{web, HttpResponse};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{now_secs, SessionUser};
+use crate::error::{AppError, AppResult};
+use crate::i18n::Message;
+use crate::repository::Accounts;
+use crate::sessions::{generate_token, hash_token};
+use crate::validation::{validate_label, Validate, ValidationErrors};
+
+// Every personal access token starts with this, so it can be told apart from a session token
+pub const API_TOKEN_PREFIX: &str = "cmpat_";
+
+// last_used_at is only rewritten when it is older than this, to avoid a write per request
+pub const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+// Which part of the data a token can reach; Subjects also covers notes, exam dates and file links
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Area {
+    All,
+    Tasks,
+    Subjects,
+}
+
+// What a handler needs from the caller's credentials
+#[derive(Debug, Clone, Copy)]
+pub enum Permission {
+    ReadTasks,
+    WriteTasks,
+    ReadSubjects,
+    WriteSubjects,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenScope {
+    pub access: Access,
+    pub area: Area,
+}
+
+impl TokenScope {
+    pub fn allows(&self, permission: Permission) -> bool {
+        let (area, write) = match permission {
+            Permission::ReadTasks => (Area::Tasks, false),
+            Permission::WriteTasks => (Area::Tasks, true),
+            Permission::ReadSubjects => (Area::Subjects, false),
+            Permission::WriteSubjects => (Area::Subjects, true),
+        };
+        (self.area == Area::All || self.area == area) && (!write || self.access == Access::ReadWrite)
+    }
+
+    // None for a stored value this build does not know
+    pub fn parse(access: &str, area: &str) -> Option<TokenScope> {
+        Some(TokenScope {
+            access: Access::parse(access)?,
+            area: Area::parse(area)?,
+        })
+    }
+}
+
+impl Access {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::ReadWrite => "read_write",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Access> {
+        match value {
+            "read" => Some(Access::Read),
+            "read_write" => Some(Access::ReadWrite),
+            _ => None,
+        }
+    }
+}
+
+impl Area {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Area::All => "all",
+            Area::Tasks => "tasks",
+            Area::Subjects => "subjects",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Area> {
+        match value {
+            "all" => Some(Area::All),
+            "tasks" => Some(Area::Tasks),
+            "subjects" => Some(Area::Subjects),
+            _ => None,
+        }
+    }
+}
+
+// ApiToken data structure, as listed to its owner; the token itself is never stored
+#[derive(Debug, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub access: Access,
+    pub area: Area,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+// A fresh token, with the prefix that tells it apart
+pub fn new_api_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, generate_token())
+}
+
+// Request structures
+#[derive(Debug, Deserialize)]
+pub struct CreateApiTokenRequest {
+    name: String,
+    access: Access,
+    #[serde(default = "default_area")]
+    area: Area,
+}
+
+fn default_area() -> Area {
+    Area::All
+}
+
+impl Validate for CreateApiTokenRequest {
+    fn validate(&self) -> std::result::Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_label("name", &self.name, "token_name_invalid", &mut errors);
+        errors.into_result()
+    }
+}
+
+// Handler functions
+pub async fn create_api_token(
+    user: SessionUser,
+    create_info: web::Json<CreateApiTokenRequest>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<HttpResponse> {
+    create_info.validate()?;
+
+    let name = create_info.name.trim().to_string();
+
+    let scope = TokenScope {
+        access: create_info.access,
+        area: create_info.area,
+    };
+    let (id, token) = accounts.api_tokens.create(user.user_id, name.clone(), scope).await?;
+    // The token is only ever shown in this response
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "id": id,
+        "name": name,
+        "access": scope.access,
+        "area": scope.area,
+        "token": token,
+    })))
+}
+
+pub async fn get_api_tokens(user: SessionUser, accounts: web::Data<Accounts>) -> AppResult<HttpResponse> {
+    let tokens = accounts.api_tokens.list(user.user_id).await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
+pub async fn delete_api_token(
+    user: SessionUser,
+    token_id: web::Path<i64>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<Message> {
+    if !accounts.api_tokens.revoke(user.user_id, token_id.into_inner()).await? {
+        return Err(AppError::NotFound("token_not_found"));
+    }
+    Ok(Message("api_token_revoked"))
+}
+
+// Database functions
+pub fn insert_api_token(conn: &Connection, user_id: i32, name: &str, scope: TokenScope) -> Result<(i64, String)> {
+    let token = new_api_token();
+    conn.execute(
+        "INSERT INTO api_tokens (user_id, name, token_hash, access, area, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![user_id, name, hash_token(&token), scope.access.as_str(), scope.area.as_str(), now_secs()],
+    )?;
+    Ok((conn.last_insert_rowid(), token))
+}
+
+// Resolve a presented token to its user and scope, and record that it was just used
+pub fn find_api_token(conn: &Connection, token: &str) -> Result<Option<(i32, TokenScope)>> {
+    let found = conn
+        .query_row(
+            "SELECT id, user_id, access, area FROM api_tokens WHERE token_hash = ?1 AND revoked_at IS NULL",
+            [hash_token(token)],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i32>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            },
+        )
+        .optional()?;
+
+    let (id, user_id, access, area) = match found {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let scope = match TokenScope::parse(&access, &area) {
+        Some(scope) => scope,
+        None => return Ok(None),
+    };
+
+    let now = now_secs();
+    conn.execute(
+        "UPDATE api_tokens SET last_used_at = ?1
+         WHERE id = ?2 AND (last_used_at IS NULL OR last_used_at < ?1 - ?3)",
+        params![now, id, LAST_USED_RESOLUTION_SECS],
+    )?;
+    Ok(Some((user_id, scope)))
+}
+
+pub fn list_api_tokens(conn: &Connection, user_id: i32) -> Result<Vec<ApiToken>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, access, area, created_at, last_used_at FROM api_tokens
+         WHERE user_id = ?1 AND revoked_at IS NULL
+         ORDER BY created_at DESC",
+    )?;
+    let token_iter = stmt.query_map([user_id], |row| {
+        let access: String = row.get(2)?;
+        let area: String = row.get(3)?;
+        Ok(ApiToken {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            access: Access::parse(&access).unwrap_or(Access::Read),
+            area: Area::parse(&area).unwrap_or(Area::All),
+            created_at: row.get(4)?,
+            last_used_at: row.get(5)?,
+        })
+    })?;
+    token_iter.collect()
+}
+
+pub fn revoke_api_token(conn: &Connection, user_id: i32, token_id: i64) -> Result<bool> {
+    let revoked = conn.execute(
+        "UPDATE api_tokens SET revoked_at = ?1 WHERE id = ?2 AND user_id = ?3 AND revoked_at IS NULL",
+        params![now_secs(), token_id, user_id],
+    )?;
+    Ok(revoked > 0)
+}
+
+// Used when the account may have been taken over, so nothing the intruder created keeps working
+pub fn revoke_all_api_tokens(conn: &Connection, user_id: i32) -> Result<usize> {
+    conn.execute(
+        "UPDATE api_tokens SET revoked_at = ?1 WHERE user_id = ?2 AND revoked_at IS NULL",
+        params![now_secs(), user_id],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{app, bearer, fixture};
+    use actix_web::{http::StatusCode, test};
+
+    #[actix_web::test]
+    async fn scoped_token_only_reaches_its_area() {
+        let f = fixture();
+        let app = app!(f);
+        let session_token = &f.alice_token;
+
+        let create = |name: &str| {
+            test::TestRequest::post()
+                .uri("/api_tokens")
+                .insert_header(bearer(session_token))
+                .set_json(serde_json::json!({ "name": name, "access": "read", "area": "tasks" }))
+                .to_request()
+        };
+        let resp = test::call_service(&app, create("  ")).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["errors"][0]["field"], "name");
+        assert_eq!(body["errors"][0]["code"], "token_name_invalid");
+
+        let req = create("syllabus script");
+        let created: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let token = created["token"].as_str().unwrap().to_string();
+        assert!(token.starts_with(API_TOKEN_PREFIX));
+
+        let call = |method: test::TestRequest, uri: &str| method.uri(uri).insert_header(bearer(&token)).to_request();
+        let resp = test::call_service(&app, call(test::TestRequest::get(), "/get_tasks")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = test::call_service(&app, call(test::TestRequest::get(), "/get_subjects")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let add_task = test::TestRequest::post().set_json(serde_json::json!({ "title": "TP", "status": "Pendiente" }));
+        let resp = test::call_service(&app, call(add_task, "/add_task")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        // Tokens cannot manage sessions or mint more tokens
+        let resp = test::call_service(&app, call(test::TestRequest::get(), "/api_tokens")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let id = created["id"].as_i64().unwrap();
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api_tokens/{}", id))
+            .insert_header(bearer(session_token))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let resp = test::call_service(&app, call(test::TestRequest::get(), "/get_tasks")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
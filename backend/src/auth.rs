@@ -0,0 +1,223 @@
+use actix_web::{dev::Payload, web, FromRequest, HttpMessage, HttpRequest};
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api_tokens::{Permission, TokenScope, API_TOKEN_PREFIX};
+use crate::error::AppError;
+use crate::i18n::PreferredLocale;
+use crate::repository::Accounts;
+
+// What the extractors below resolve to
+pub type AuthFuture<T> = Pin<Box<dyn Future<Output = Result<T, AppError>>>>;
+
+// How long an access token stays valid (15 minutes); clients renew it with their refresh token
+pub const ACCESS_TOKEN_TTL_SECS: i64 = 15 * 60;
+
+// How long the second login step may take once the password was accepted (5 minutes)
+const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+
+// Purpose stamped on challenge tokens so they cannot be confused with anything else
+const TWO_FACTOR_PURPOSE: &str = "2fa";
+
+// Claims carried inside the signed access token
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: i32,
+    pub sid: i64,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+// Claims carried by the short-lived token that links both login steps when 2FA is enabled
+#[derive(Debug, Serialize, Deserialize)]
+struct ChallengeClaims {
+    sub: i32,
+    purpose: String,
+    iat: i64,
+    exp: i64,
+}
+
+// Keys used to sign and validate access tokens
+pub struct JwtKeys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl JwtKeys {
+    pub fn from_secret(secret: &[u8]) -> Self {
+        JwtKeys {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+}
+
+// How the caller proved who they are
+#[derive(Debug, Clone, Copy)]
+pub enum Credential {
+    Session(i64),
+    ApiToken(TokenScope),
+}
+
+// The user behind a validated access token or personal API token; add it as a handler argument
+// to require authentication, then call `require` with what the handler touches
+#[derive(Debug, Clone, Copy)]
+pub struct AuthenticatedUser {
+    pub user_id: i32,
+    pub credential: Credential,
+}
+
+impl AuthenticatedUser {
+    // Sessions can do everything; API tokens only what their scope grants
+    pub fn require(&self, permission: Permission) -> Result<(), AppError> {
+        match self.credential {
+            Credential::ApiToken(scope) if !scope.allows(permission) => {
+                Err(AppError::Forbidden("token_scope_insufficient"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Like AuthenticatedUser, but only accepts a login session; used for account and credential management
+#[derive(Debug, Clone, Copy)]
+pub struct SessionUser {
+    pub user_id: i32,
+    pub session_id: i64,
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+pub fn create_token(keys: &JwtKeys, user_id: i32, session_id: i64) -> jsonwebtoken::errors::Result<String> {
+    let iat = now_secs();
+    let claims = Claims {
+        sub: user_id,
+        sid: session_id,
+        iat,
+        exp: iat + ACCESS_TOKEN_TTL_SECS,
+    };
+    encode(&Header::default(), &claims, &keys.encoding)
+}
+
+pub fn validate_token(keys: &JwtKeys, token: &str) -> jsonwebtoken::errors::Result<Claims> {
+    decode::<Claims>(token, &keys.decoding, &Validation::default()).map(|data| data.claims)
+}
+
+pub fn create_challenge_token(keys: &JwtKeys, user_id: i32) -> jsonwebtoken::errors::Result<String> {
+    let iat = now_secs();
+    let claims = ChallengeClaims {
+        sub: user_id,
+        purpose: TWO_FACTOR_PURPOSE.to_string(),
+        iat,
+        exp: iat + CHALLENGE_TTL_SECS,
+    };
+    encode(&Header::default(), &claims, &keys.encoding)
+}
+
+// Returns the user a challenge token was issued to, if it is valid and unexpired
+pub fn validate_challenge_token(keys: &JwtKeys, token: &str) -> Option<i32> {
+    decode::<ChallengeClaims>(token, &keys.decoding, &Validation::default())
+        .ok()
+        .filter(|data| data.claims.purpose == TWO_FACTOR_PURPOSE)
+        .map(|data| data.claims.sub)
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+// bcrypt is slow on purpose, so hashing and verifying run on the blocking thread pool
+// instead of holding up an actix worker
+pub async fn hash_password(password: String, cost: u32) -> Result<String, AppError> {
+    web::block(move || bcrypt::hash(password, cost))
+        .await
+        .map_err(AppError::internal)?
+        .map_err(AppError::internal)
+}
+
+pub async fn verify_password(password: String, password_hash: String) -> bool {
+    web::block(move || bcrypt::verify(password, &password_hash).unwrap_or(false))
+        .await
+        .unwrap_or(false)
+}
+
+fn authenticate(req: &HttpRequest) -> AuthFuture<AuthenticatedUser> {
+    let req = req.clone();
+    let token = bearer_token(&req).map(str::to_string);
+    let accounts = req.app_data::<web::Data<Accounts>>().cloned();
+    let keys = req.app_data::<web::Data<JwtKeys>>().cloned();
+
+    Box::pin(async move {
+        let token = token.ok_or(AppError::Unauthorized("token_missing"))?;
+        let accounts = accounts.ok_or_else(|| AppError::internal("Accounts are not registered as app data"))?;
+
+        let user = if token.starts_with(API_TOKEN_PREFIX) {
+            let (user_id, scope) =
+                accounts.api_tokens.find(token).await?.ok_or(AppError::Unauthorized("token_revoked"))?;
+            AuthenticatedUser {
+                user_id,
+                credential: Credential::ApiToken(scope),
+            }
+        } else {
+            let keys = keys.ok_or_else(|| AppError::internal("JwtKeys are not registered as app data"))?;
+            let claims = validate_token(&keys, &token).map_err(|_| AppError::Unauthorized("token_invalid"))?;
+
+            // The token is only honoured while its session has not been revoked
+            let (session_id, user_id) = (claims.sid, claims.sub);
+            if !accounts.sessions.touch(session_id, user_id).await? {
+                return Err(AppError::Unauthorized("session_expired"));
+            }
+            AuthenticatedUser {
+                user_id,
+                credential: Credential::Session(session_id),
+            }
+        };
+
+        // Messages for the rest of the request follow the user's chosen language
+        if let Some(locale) = accounts.users.locale(user.user_id).await? {
+            req.extensions_mut().insert(PreferredLocale(locale));
+        }
+        Ok(user)
+    })
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AppError;
+    type Future = AuthFuture<Self>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        authenticate(req)
+    }
+}
+
+impl FromRequest for SessionUser {
+    type Error = AppError;
+    type Future = AuthFuture<Self>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let user = authenticate(req);
+        Box::pin(async move {
+            let user = user.await?;
+            match user.credential {
+                Credential::Session(session_id) => Ok(SessionUser {
+                    user_id: user.user_id,
+                    session_id,
+                }),
+                Credential::ApiToken(_) => Err(AppError::Forbidden("session_required")),
+            }
+        })
+    }
+}
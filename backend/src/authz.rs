@@ -0,0 +1,188 @@
+use crate::auth::AuthenticatedUser;
+use crate::error::AppError;
+use crate::repository::{RepoError, Repositories};
+
+// A row that belongs to a user, either directly or through its subject
+#[derive(Debug, Clone, Copy)]
+pub enum Resource {
+    Task(i32),
+    Subject(i32),
+    Note(i32),
+    ExamDate(i32),
+    FileLink(i32),
+    StoredFile(i32),
+}
+
+// Resolve a resource to the user that owns it, or None when the row does not exist
+pub async fn owner_of(repos: &Repositories, resource: Resource) -> Result<Option<i32>, RepoError> {
+    match resource {
+        Resource::Task(id) => repos.tasks.owner(id).await,
+        Resource::Subject(id) => repos.subjects.owner(id).await,
+        Resource::Note(id) => repos.notes.owner(id).await,
+        Resource::ExamDate(id) => repos.exam_dates.owner(id).await,
+        Resource::FileLink(id) => repos.file_links.owner(id).await,
+        Resource::StoredFile(id) => repos.files.owner(id).await,
+    }
+}
+
+// Check that the authenticated user owns the resource.
+// Missing rows are answered with 404 and rows owned by someone else with 403.
+pub async fn authorize(repos: &Repositories, user: &AuthenticatedUser, resource: Resource) -> Result<(), AppError> {
+    match owner_of(repos, resource).await? {
+        Some(owner_id) if owner_id == user.user_id => Ok(()),
+        Some(_) => Err(AppError::Forbidden("resource_forbidden")),
+        None => Err(AppError::NotFound("resource_not_found")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{app, bearer, count, fixture};
+    use actix_web::{http::StatusCode, test};
+    use rusqlite::params;
+
+    #[actix_web::test]
+    async fn resolves_owner_through_subject() {
+        let f = fixture();
+        let repos = Repositories::sqlite(f.pool.clone());
+        for resource in [
+            Resource::Task(f.task_id),
+            Resource::Subject(f.subject_id),
+            Resource::Note(f.note_id),
+            Resource::ExamDate(f.exam_date_id),
+            Resource::FileLink(f.file_link_id),
+        ] {
+            assert_eq!(owner_of(&repos, resource).await.unwrap(), Some(1));
+        }
+        assert_eq!(owner_of(&repos, Resource::Note(999)).await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn other_user_cannot_read_subject_data() {
+        let f = fixture();
+        let app = app!(f);
+        for path in ["get_notes", "get_exam_dates", "get_file_links"] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/{}/{}", path, f.subject_id))
+                .insert_header(bearer(&f.bob_token))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+    }
+
+    #[actix_web::test]
+    async fn other_user_cannot_delete() {
+        let f = fixture();
+        let app = app!(f);
+        let paths = [
+            format!("/delete_task/{}", f.task_id),
+            format!("/delete_note/{}", f.note_id),
+            format!("/delete_subject/{}", f.subject_id),
+        ];
+        for path in &paths {
+            let req = test::TestRequest::delete()
+                .uri(path)
+                .insert_header(bearer(&f.bob_token))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+        assert_eq!(count(&f.pool, "tasks"), 1);
+        assert_eq!(count(&f.pool, "notes"), 1);
+        assert_eq!(count(&f.pool, "subjects"), 1);
+    }
+
+    #[actix_web::test]
+    async fn other_user_cannot_update_task() {
+        let f = fixture();
+        let app = app!(f);
+        let req = test::TestRequest::post()
+            .uri("/update_task_status")
+            .insert_header(bearer(&f.bob_token))
+            .set_json(serde_json::json!({ "task_id": f.task_id, "new_status": "Tarea finalizada" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri("/update_task_note")
+            .insert_header(bearer(&f.bob_token))
+            .set_json(serde_json::json!({ "task_id": f.task_id, "new_note": "hackeado" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let conn = f.pool.get().unwrap();
+        let (status, note): (String, String) = conn
+            .query_row("SELECT status, note FROM tasks WHERE id = ?1", params![f.task_id], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(status, "Pendiente");
+        assert_eq!(note, "");
+    }
+
+    #[actix_web::test]
+    async fn other_user_cannot_add_to_subject() {
+        let f = fixture();
+        let app = app!(f);
+        let requests = [
+            ("/add_note", serde_json::json!({ "subject_id": f.subject_id, "content": "spam" })),
+            ("/add_exam_date", serde_json::json!({ "subject_id": f.subject_id, "date": "2024-08-01" })),
+            ("/add_file_link", serde_json::json!({ "subject_id": f.subject_id, "url": "https://spam.example" })),
+        ];
+        for (path, body) in requests {
+            let req = test::TestRequest::post()
+                .uri(path)
+                .insert_header(bearer(&f.bob_token))
+                .set_json(body)
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN, "{}", path);
+        }
+        assert_eq!(count(&f.pool, "notes"), 1);
+        assert_eq!(count(&f.pool, "exam_dates"), 1);
+        assert_eq!(count(&f.pool, "file_links"), 1);
+    }
+
+    #[actix_web::test]
+    async fn missing_rows_are_not_found() {
+        let f = fixture();
+        let app = app!(f);
+        let req = test::TestRequest::delete()
+            .uri("/delete_task/999")
+            .insert_header(bearer(&f.alice_token))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn owner_gets_created_row_back() {
+        let f = fixture();
+        let app = app!(f);
+        let req = test::TestRequest::post()
+            .uri("/add_note")
+            .insert_header(bearer(&f.alice_token))
+            .set_json(serde_json::json!({ "subject_id": f.subject_id, "content": "Resumen" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let note: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(note["content"], "Resumen");
+        let note_id = note["id"].as_i64().unwrap() as i32;
+        let repos = Repositories::sqlite(f.pool.clone());
+        assert_eq!(owner_of(&repos, Resource::Note(note_id)).await.unwrap(), Some(1));
+    }
+
+    #[actix_web::test]
+    async fn owner_can_delete() {
+        let f = fixture();
+        let app = app!(f);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/delete_subject/{}", f.subject_id))
+            .insert_header(bearer(&f.alice_token))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(count(&f.pool, "subjects"), 0);
+        assert_eq!(count(&f.pool, "notes"), 0);
+    }
+}
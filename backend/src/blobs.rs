@@ -0,0 +1,153 @@
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::sessions::generate_token;
+
+// Where the contents of uploaded files live, each under the SHA-256 of its bytes, so the same file
+// uploaded twice is stored once. The metadata rows that point at a blob are kept in the repositories.
+pub trait BlobStore: Send + Sync {
+    // A new, empty upload to write into before its hash is known
+    fn stage(&self) -> io::Result<StagedBlob>;
+    // Keeps a fully written upload under its hash, which it answers; identical content already stored wins
+    fn commit(&self, staged: StagedBlob) -> io::Result<String>;
+    fn open(&self, sha256: &str) -> io::Result<File>;
+    // Deleting a blob that is not there is not an error
+    fn delete(&self, sha256: &str) -> io::Result<()>;
+}
+
+// An upload being written to a temporary file, hashed as it goes. Dropping it without
+// committing removes the file, so a failed or rejected upload leaves nothing behind.
+pub struct StagedBlob {
+    path: PathBuf,
+    file: File,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl StagedBlob {
+    fn create(path: PathBuf) -> io::Result<Self> {
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        Ok(StagedBlob {
+            path,
+            file,
+            hasher: Sha256::new(),
+            size: 0,
+        })
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all(chunk)?;
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Of everything written so far, which is where the blob goes once committed
+    pub fn sha256(&self) -> String {
+        hex::encode(self.hasher.clone().finalize())
+    }
+}
+
+impl Drop for StagedBlob {
+    fn drop(&mut self) {
+        // Already gone once committed
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Blobs on the local disk, under blobs/ab/abcd... with the first two hex digits as a directory
+// so no single directory grows too large
+pub struct DiskBlobStore {
+    root: PathBuf,
+}
+
+impl DiskBlobStore {
+    pub fn new(root: &Path) -> io::Result<Self> {
+        fs::create_dir_all(root.join("blobs"))?;
+        fs::create_dir_all(root.join("staging"))?;
+        Ok(DiskBlobStore { root: root.to_path_buf() })
+    }
+
+    fn blob_path(&self, sha256: &str) -> io::Result<PathBuf> {
+        // Hashes come from the database, but never let one walk out of the store
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a SHA-256 hash"));
+        }
+        Ok(self.root.join("blobs").join(&sha256[..2]).join(sha256))
+    }
+}
+
+impl BlobStore for DiskBlobStore {
+    fn stage(&self) -> io::Result<StagedBlob> {
+        StagedBlob::create(self.root.join("staging").join(generate_token()))
+    }
+
+    fn commit(&self, staged: StagedBlob) -> io::Result<String> {
+        staged.file.sync_all()?;
+        let sha256 = staged.sha256();
+        let path = self.blob_path(&sha256)?;
+        if !path.exists() {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(&staged.path, &path)?;
+        }
+        Ok(sha256)
+    }
+
+    fn open(&self, sha256: &str) -> io::Result<File> {
+        File::open(self.blob_path(sha256)?)
+    }
+
+    fn delete(&self, sha256: &str) -> io::Result<()> {
+        match fs::remove_file(self.blob_path(sha256)?) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+pub fn store_from_dir(upload_dir: &Path) -> io::Result<Arc<dyn BlobStore>> {
+    Ok(Arc::new(DiskBlobStore::new(upload_dir)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn identical_uploads_share_one_blob() {
+        let root = std::env::temp_dir().join(format!("classmate-blobs-{}", generate_token()));
+        let store = DiskBlobStore::new(&root).unwrap();
+        let upload = |content: &[u8]| {
+            let mut staged = store.stage().unwrap();
+            staged.write(content).unwrap();
+            store.commit(staged).unwrap()
+        };
+
+        let first = upload(b"apuntes");
+        assert_eq!(first, hex::encode(Sha256::digest(b"apuntes")));
+        assert_eq!(upload(b"apuntes"), first);
+        let mut content = String::new();
+        store.open(&first).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "apuntes");
+
+        // Abandoned uploads and deleted blobs leave nothing behind
+        store.stage().unwrap().write(b"a medias").unwrap();
+        assert_eq!(fs::read_dir(root.join("staging")).unwrap().count(), 0);
+        store.delete(&first).unwrap();
+        store.delete(&first).unwrap();
+        assert!(store.open(&first).is_err());
+        assert!(store.open("../../etc/passwd").is_err());
+
+        fs::remove_dir_all(root).ok();
+    }
+}
@@ -0,0 +1,385 @@
+use actix_cors::Cors;
+use serde::Deserialize;
+use std::fmt;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+// Read when CLASSMATE_CONFIG does not name another file; it is fine for it not to exist
+const DEFAULT_CONFIG_PATH: &str = "classmate.toml";
+
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+
+// Everything the server can be tuned with. Each field can come from the TOML file
+// and be overridden by the environment variable named next to it.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub database_path: String,         // DATABASE_PATH
+    pub database_url: Option<String>,  // DATABASE_URL, needs the postgres feature; replaces DATABASE_PATH
+    pub bind_address: String,          // BIND_ADDRESS
+    pub cors_origins: Vec<String>,     // CORS_ORIGINS, comma separated; "*" allows any origin
+    pub jwt_secret: String,            // JWT_SECRET
+    pub bcrypt_cost: u32,              // BCRYPT_COST
+    pub workers: Option<usize>,        // WORKERS, one per CPU when unset
+    pub upload_dir: PathBuf,           // UPLOAD_DIR
+    pub max_upload_bytes: u64,         // MAX_UPLOAD_BYTES, per uploaded file
+    pub max_upload_files: u64,         // MAX_UPLOAD_FILES, per request
+    pub max_upload_request_bytes: u64, // MAX_UPLOAD_REQUEST_BYTES, all the files of one request together
+    pub log_level: String,             // LOG_LEVEL
+    pub admin_username: Option<String>, // ADMIN_USERNAME
+    pub mail: MailConfig,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MailTransportKind {
+    // Writes every email to the outbox directory
+    File,
+    Smtp,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub transport: MailTransportKind, // MAIL_TRANSPORT
+    pub from: String,                 // MAIL_FROM
+    pub outbox_dir: PathBuf,          // MAIL_OUTBOX_DIR
+    pub smtp_host: Option<String>,    // SMTP_HOST
+    pub smtp_port: u16,               // SMTP_PORT
+    pub smtp_username: Option<String>, // SMTP_USERNAME
+    pub smtp_password: Option<String>, // SMTP_PASSWORD
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            database_path: "classmate.db".to_string(),
+            database_url: None,
+            bind_address: "127.0.0.1:8080".to_string(),
+            // The frontend's development server
+            cors_origins: vec!["http://localhost:5173".to_string()],
+            jwt_secret: String::new(),
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            workers: None,
+            upload_dir: PathBuf::from("uploads"),
+            max_upload_bytes: 25 * 1024 * 1024,
+            max_upload_files: 20,
+            max_upload_request_bytes: 100 * 1024 * 1024,
+            log_level: "info".to_string(),
+            admin_username: None,
+            mail: MailConfig::default(),
+        }
+    }
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            transport: MailTransportKind::File,
+            from: "ClassMate <no-reply@classmate.local>".to_string(),
+            outbox_dir: PathBuf::from("outbox"),
+            smtp_host: None,
+            smtp_port: 465,
+            smtp_username: None,
+            smtp_password: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, error: std::io::Error },
+    Parse { path: PathBuf, error: toml::de::Error },
+    DotEnv(dotenv::Error),
+    Env { name: &'static str, reason: String },
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => write!(f, "cannot read {}: {}", path.display(), error),
+            ConfigError::Parse { path, error } => write!(f, "cannot parse {}: {}", path.display(), error),
+            ConfigError::DotEnv(error) => write!(f, "cannot load .env: {}", error),
+            ConfigError::Env { name, reason } => write!(f, "{} {}", name, reason),
+            ConfigError::Invalid(problems) => write!(f, "{}", problems.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // Defaults, then the TOML file, then .env, then the process environment
+    pub fn load() -> Result<Config, ConfigError> {
+        // Variables that are already set win over .env
+        match dotenv::dotenv() {
+            Ok(_) => {}
+            Err(error) if error.not_found() => {}
+            Err(error) => return Err(ConfigError::DotEnv(error)),
+        }
+
+        let mut config = match std::env::var("CLASSMATE_CONFIG") {
+            Ok(path) => Config::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            Err(_) => Config::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        toml::from_str(&contents).map_err(|error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error,
+        })
+    }
+
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let parse = |name: &'static str| -> Result<Option<u64>, ConfigError> {
+            match var(name) {
+                Some(value) => value.trim().parse().map(Some).map_err(|_| ConfigError::Env {
+                    name,
+                    reason: "must be a whole number".to_string(),
+                }),
+                None => Ok(None),
+            }
+        };
+
+        if let Some(value) = var("DATABASE_PATH") {
+            self.database_path = value;
+        }
+        if let Some(value) = var("DATABASE_URL") {
+            self.database_url = Some(value);
+        }
+        if let Some(value) = var("BIND_ADDRESS") {
+            self.bind_address = value;
+        }
+        if let Some(value) = var("CORS_ORIGINS") {
+            self.cors_origins = value
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(value) = var("JWT_SECRET") {
+            self.jwt_secret = value;
+        }
+        if let Some(value) = parse("BCRYPT_COST")? {
+            self.bcrypt_cost = u32::try_from(value).unwrap_or(u32::MAX);
+        }
+        if let Some(value) = parse("WORKERS")? {
+            self.workers = Some(usize::try_from(value).unwrap_or(usize::MAX));
+        }
+        if let Some(value) = var("UPLOAD_DIR") {
+            self.upload_dir = PathBuf::from(value);
+        }
+        if let Some(value) = parse("MAX_UPLOAD_BYTES")? {
+            self.max_upload_bytes = value;
+        }
+        if let Some(value) = parse("MAX_UPLOAD_FILES")? {
+            self.max_upload_files = value;
+        }
+        if let Some(value) = parse("MAX_UPLOAD_REQUEST_BYTES")? {
+            self.max_upload_request_bytes = value;
+        }
+        if let Some(value) = var("LOG_LEVEL") {
+            self.log_level = value;
+        }
+        if let Some(value) = var("ADMIN_USERNAME") {
+            self.admin_username = Some(value);
+        }
+
+        let mail = &mut self.mail;
+        if let Some(value) = var("MAIL_TRANSPORT") {
+            mail.transport = match value.trim() {
+                "smtp" => MailTransportKind::Smtp,
+                "file" => MailTransportKind::File,
+                _ => {
+                    return Err(ConfigError::Env {
+                        name: "MAIL_TRANSPORT",
+                        reason: "must be \"file\" or \"smtp\"".to_string(),
+                    })
+                }
+            };
+        }
+        if let Some(value) = var("MAIL_FROM") {
+            mail.from = value;
+        }
+        if let Some(value) = var("MAIL_OUTBOX_DIR") {
+            mail.outbox_dir = PathBuf::from(value);
+        }
+        if let Some(value) = var("SMTP_HOST") {
+            mail.smtp_host = Some(value);
+        }
+        if let Some(value) = parse("SMTP_PORT")? {
+            mail.smtp_port = u16::try_from(value).map_err(|_| ConfigError::Env {
+                name: "SMTP_PORT",
+                reason: "must be a port number".to_string(),
+            })?;
+        }
+        if let Some(value) = var("SMTP_USERNAME") {
+            mail.smtp_username = Some(value);
+        }
+        if let Some(value) = var("SMTP_PASSWORD") {
+            mail.smtp_password = Some(value);
+        }
+        Ok(())
+    }
+
+    // Report every problem at once, so a broken deployment is fixed in one go
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.jwt_secret.trim().is_empty() {
+            problems.push("JWT_SECRET must be set to sign access tokens".to_string());
+        }
+        if self.database_path.trim().is_empty() {
+            problems.push("DATABASE_PATH must not be empty".to_string());
+        }
+        if self.database_url.is_some() && !cfg!(feature = "postgres") {
+            problems.push("DATABASE_URL is set but this build has no postgres feature".to_string());
+        }
+        #[cfg(feature = "postgres")]
+        if let Some(url) = &self.database_url {
+            match url.parse::<tokio_postgres::Config>() {
+                Ok(database) => problems.extend(crate::repository::check_postgres_tls(&database).err()),
+                Err(error) => problems.push(format!("DATABASE_URL is not a valid connection string: {}", error)),
+            }
+        }
+        if self.bind_address.to_socket_addrs().is_err() {
+            problems.push(format!("BIND_ADDRESS {:?} is not a host:port address", self.bind_address));
+        }
+        for origin in &self.cors_origins {
+            let valid = origin == "*"
+                || ((origin.starts_with("http://") || origin.starts_with("https://")) && !origin.ends_with('/'));
+            if !valid {
+                problems.push(format!("CORS origin {:?} must look like https://example.com or be \"*\"", origin));
+            }
+        }
+        if !(4..=31).contains(&self.bcrypt_cost) {
+            problems.push("BCRYPT_COST must be between 4 and 31".to_string());
+        }
+        if self.workers == Some(0) {
+            problems.push("WORKERS must be at least 1".to_string());
+        }
+        if self.max_upload_bytes == 0 {
+            problems.push("MAX_UPLOAD_BYTES must be at least 1".to_string());
+        }
+        if self.max_upload_files == 0 {
+            problems.push("MAX_UPLOAD_FILES must be at least 1".to_string());
+        }
+        if self.max_upload_request_bytes == 0 {
+            problems.push("MAX_UPLOAD_REQUEST_BYTES must be at least 1".to_string());
+        }
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            problems.push(format!("LOG_LEVEL must be one of {}", LOG_LEVELS.join(", ")));
+        }
+        if self.mail.transport == MailTransportKind::Smtp && self.mail.smtp_host.is_none() {
+            problems.push("SMTP_HOST must be set when MAIL_TRANSPORT is smtp".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    pub fn cors(&self) -> Cors {
+        let cors = Cors::default().allow_any_method().allow_any_header().max_age(3600);
+        if self.cors_origins.iter().any(|origin| origin == "*") {
+            return cors.allow_any_origin();
+        }
+        self.cors_origins
+            .iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn environment_overrides_file() {
+        let mut config: Config = toml::from_str(
+            r#"
+            bind_address = "0.0.0.0:9000"
+            cors_origins = ["https://classmate.example.edu"]
+            jwt_secret = "from-file"
+            workers = 2
+
+            [mail]
+            transport = "smtp"
+            smtp_host = "smtp.example.edu"
+            "#,
+        )
+        .unwrap();
+        let env: HashMap<&str, &str> = [
+            ("JWT_SECRET", "from-env"),
+            ("CORS_ORIGINS", "https://a.example.edu, https://b.example.edu"),
+            ("SMTP_PORT", "587"),
+        ]
+        .into_iter()
+        .collect();
+        config.apply_env(|name| env.get(name).map(|value| value.to_string())).unwrap();
+
+        assert_eq!(config.bind_address, "0.0.0.0:9000");
+        assert_eq!(config.jwt_secret, "from-env");
+        assert_eq!(config.cors_origins, ["https://a.example.edu", "https://b.example.edu"]);
+        assert_eq!(config.workers, Some(2));
+        assert_eq!(config.database_path, "classmate.db");
+        assert_eq!(config.mail.transport, MailTransportKind::Smtp);
+        assert_eq!(config.mail.smtp_port, 587);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        // The shipped example has to stay loadable
+        toml::from_str::<Config>(include_str!("../classmate.example.toml")).unwrap();
+        assert!(toml::from_str::<Config>("jwt_secrte = \"typo\"").is_err());
+
+        let mut config = Config::default();
+        assert!(matches!(
+            config.apply_env(|name| (name == "WORKERS").then(|| "many".to_string())),
+            Err(ConfigError::Env { name: "WORKERS", .. })
+        ));
+
+        config.cors_origins = vec!["https://classmate.example.edu/".to_string()];
+        config.bcrypt_cost = 2;
+        config.log_level = "verbose".to_string();
+        config.max_upload_bytes = 0;
+        config.max_upload_files = 0;
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 6, "{:?}", problems),
+            _ => panic!("expected validation errors"),
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn remote_postgres_needs_tls() {
+        let mut config = Config {
+            jwt_secret: "secret".to_string(),
+            ..Config::default()
+        };
+        for url in [
+            "postgres://classmate@localhost/classmate",
+            "postgres://classmate@127.0.0.1/classmate",
+            "postgres://classmate@db.example.edu/classmate?sslmode=require",
+        ] {
+            config.database_url = Some(url.to_string());
+            assert!(config.validate().is_ok(), "{}", url);
+        }
+        config.database_url = Some("postgres://classmate@db.example.edu/classmate?sslmode=prefer".to_string());
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use actix_web::{error::BlockingError, web};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use std::fmt;
+use std::time::Duration;
+
+pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+
+// How long a connection waits for another writer before giving up with SQLITE_BUSY
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const POOL_SIZE: u32 = 8;
+
+#[derive(Debug)]
+pub enum DbError {
+    Pool(r2d2::Error),
+    Sqlite(rusqlite::Error),
+    Blocking(BlockingError),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Pool(error) => write!(f, "could not get a database connection: {}", error),
+            DbError::Sqlite(error) => write!(f, "database error: {}", error),
+            DbError::Blocking(error) => write!(f, "database task was cancelled: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+// Settings every pooled connection starts with
+fn init_connection(conn: &mut Connection) -> rusqlite::Result<()> {
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // Readers no longer block the writer and vice versa; in-memory databases stay in "memory" mode
+    conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get::<_, String>(0))?;
+    conn.execute_batch("PRAGMA synchronous = NORMAL; PRAGMA foreign_keys = ON;")
+}
+
+pub fn open_pool(path: &str) -> Result<DbPool, r2d2::Error> {
+    let manager = SqliteConnectionManager::file(path).with_init(init_connection);
+    r2d2::Pool::builder().max_size(POOL_SIZE).build(manager)
+}
+
+// Run `f` with a pooled connection on the blocking thread pool, so SQLite never stalls an actix worker
+pub async fn run<F, T>(pool: &DbPool, f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let conn = pool.get().map_err(DbError::Pool)?;
+        f(&conn).map_err(DbError::Sqlite)
+    })
+    .await
+    .map_err(DbError::Blocking)?
+}
+
+// A private in-memory database shared by every connection of the pool, already migrated
+#[cfg(test)]
+pub fn test_pool() -> DbPool {
+    let name = format!("file:classmate-test-{}?mode=memory&cache=shared", crate::sessions::generate_token());
+    let manager = SqliteConnectionManager::file(name).with_init(init_connection);
+    let pool = r2d2::Pool::builder().max_size(4).build(manager).unwrap();
+    crate::migrations::run(&pool.get().unwrap()).unwrap();
+    pool
+}
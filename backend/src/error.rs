@@ -0,0 +1,310 @@
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_LANGUAGE, CONTENT_LENGTH};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use rand::RngCore;
+use std::fmt;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+
+use crate::db::DbError;
+use crate::i18n::Locale;
+use crate::repository::RepoError;
+use crate::validation::ValidationErrors;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+// Longest X-Request-Id accepted from a proxy in front of the server
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+// Every way a request can fail. Each variant carries a stable, machine-readable code, which is
+// also the key of the message shown to the user in the catalogs under locales/.
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(&'static str),
+    Unauthorized(&'static str),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    Conflict(&'static str),
+    PayloadTooLarge(&'static str),
+    Validation(ValidationErrors),
+    TooManyRequests { retry_after: i64 },
+    // Any other status, kept as it is, for responses that did not come from an AppError
+    Status(StatusCode, &'static str),
+    // The detail is logged, never sent to the client
+    Internal(String),
+}
+
+pub type AppResult<T> = Result<T, AppError>;
+
+impl AppError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(code)
+            | AppError::Unauthorized(code)
+            | AppError::Forbidden(code)
+            | AppError::NotFound(code)
+            | AppError::Conflict(code)
+            | AppError::PayloadTooLarge(code)
+            | AppError::Status(_, code) => code,
+            AppError::Validation(_) => "validation_failed",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn internal(error: impl fmt::Display) -> AppError {
+        AppError::Internal(error.to_string())
+    }
+
+    // Stand-in for error responses that did not come from an AppError, such as unknown routes
+    fn from_status(status: StatusCode) -> AppError {
+        match status {
+            StatusCode::NOT_FOUND => AppError::NotFound("route_not_found"),
+            StatusCode::METHOD_NOT_ALLOWED => AppError::Status(status, "method_not_allowed"),
+            StatusCode::UNAUTHORIZED => AppError::Unauthorized("unauthorized"),
+            StatusCode::FORBIDDEN => AppError::Forbidden("forbidden"),
+            StatusCode::PAYLOAD_TOO_LARGE => AppError::Status(status, "payload_too_large"),
+            StatusCode::PRECONDITION_FAILED => AppError::Status(status, "precondition_failed"),
+            StatusCode::RANGE_NOT_SATISFIABLE => AppError::Status(status, "range_not_satisfiable"),
+            status if status.is_client_error() => AppError::Status(status, "bad_request"),
+            status => AppError::Internal(format!("unexpected {} response", status)),
+        }
+    }
+
+    fn to_response(&self, request_id: &str, locale: Locale) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        response.insert_header((REQUEST_ID_HEADER, request_id));
+        response.insert_header((CONTENT_LANGUAGE, locale.tag()));
+        if let AppError::TooManyRequests { retry_after } = self {
+            response.insert_header(("Retry-After", retry_after.to_string()));
+        }
+        let mut body = serde_json::json!({
+            "code": self.code(),
+            "message": locale.text(self.code()),
+            "request_id": request_id,
+        });
+        if let AppError::Validation(errors) = self {
+            body["errors"] = errors.to_json(locale);
+        }
+        response.json(body)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Internal(detail) => write!(f, "{}: {}", self.code(), detail),
+            _ => write!(f, "{}", self.code()),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(..) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(..) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(..) => StatusCode::FORBIDDEN,
+            AppError::NotFound(..) => StatusCode::NOT_FOUND,
+            AppError::Conflict(..) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(..) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Status(status, _) => *status,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // RequestId swaps this id and language for the ones of the request
+    fn error_response(&self) -> HttpResponse {
+        self.to_response(&new_request_id(), Locale::DEFAULT)
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(error: rusqlite::Error) -> Self {
+        match error {
+            rusqlite::Error::QueryReturnedNoRows => AppError::NotFound("not_found"),
+            rusqlite::Error::SqliteFailure(failure, _) if failure.code == rusqlite::ErrorCode::ConstraintViolation => {
+                AppError::Conflict("conflict")
+            }
+            error => AppError::internal(error),
+        }
+    }
+}
+
+impl From<DbError> for AppError {
+    fn from(error: DbError) -> Self {
+        match error {
+            DbError::Sqlite(error) => AppError::from(error),
+            error => AppError::internal(error),
+        }
+    }
+}
+
+impl From<RepoError> for AppError {
+    fn from(error: RepoError) -> Self {
+        match error {
+            RepoError::Db(error) => AppError::from(error),
+            #[cfg(feature = "postgres")]
+            RepoError::Postgres(error) => {
+                use tokio_postgres::error::SqlState;
+                match error.code() {
+                    Some(state) if *state == SqlState::UNIQUE_VIOLATION || *state == SqlState::FOREIGN_KEY_VIOLATION => {
+                        AppError::Conflict("conflict")
+                    }
+                    _ => AppError::internal(error),
+                }
+            }
+            #[cfg(any(test, feature = "postgres"))]
+            error => AppError::internal(error),
+        }
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(errors: ValidationErrors) -> Self {
+        AppError::Validation(errors)
+    }
+}
+
+fn new_request_id() -> String {
+    format!("{:016x}", rand::thread_rng().next_u64())
+}
+
+// Ids set by a proxy in front of the server are kept, so both logs line up
+fn incoming_request_id(req: &ServiceRequest) -> Option<String> {
+    let id = req.headers().get(REQUEST_ID_HEADER)?.to_str().ok()?;
+    let valid = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| id.to_string())
+}
+
+// Tags every request with an id, sent back in X-Request-Id, and turns every error response into the
+// JSON body of its AppError carrying that same id, in the caller's language. Internal errors are
+// logged here with the id.
+pub struct RequestId;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestId
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdMiddleware { service }))
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = incoming_request_id(&req).unwrap_or_else(new_request_id);
+        let response = self.service.call(req);
+
+        Box::pin(async move {
+            let mut response = response.await?;
+            let status = response.status();
+            if !status.is_client_error() && !status.is_server_error() {
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+                }
+                return Ok(response.map_into_left_body());
+            }
+
+            let locale = Locale::of(response.request());
+            if let Some(error) = response.response().error().and_then(|error| error.as_error::<AppError>()) {
+                if let AppError::Internal(detail) = error {
+                    let request = response.request();
+                    log::error!("[{}] {} {}: {}", request_id, request.method(), request.path(), detail);
+                }
+                let body = error.to_response(&request_id, locale).map_into_right_body();
+                return Ok(response.into_response(body));
+            }
+
+            // Responses built elsewhere, such as a 416 for a download, keep their status and headers
+            // like Content-Range; only the body is swapped for the JSON one
+            let error = AppError::from_status(status);
+            if let AppError::Internal(detail) = &error {
+                let request = response.request();
+                log::error!("[{}] {} {}: {}", request_id, request.method(), request.path(), detail);
+            }
+            let (json, body) = error.to_response(&request_id, locale).into_parts();
+            Ok(response.map_body(|head, _| {
+                head.headers.remove(CONTENT_LENGTH);
+                for (name, value) in json.headers() {
+                    head.headers.insert(name.clone(), value.clone());
+                }
+                EitherBody::right(body)
+            }))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App};
+
+    async fn missing_row() -> AppResult<HttpResponse> {
+        Err(rusqlite::Error::QueryReturnedNoRows.into())
+    }
+
+    #[actix_web::test]
+    async fn errors_are_json_with_the_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestId)
+                .route("/missing", web::get().to(missing_row))
+                .service(web::resource("/ok").route(web::get().to(|| async { HttpResponse::Ok().finish() }))),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/missing").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let request_id = resp.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["request_id"], request_id.as_str());
+
+        // Routes that do not exist answer in the same shape, and a proxy's id is kept
+        let req = test::TestRequest::get()
+            .uri("/nowhere")
+            .insert_header((REQUEST_ID_HEADER, "edge-42"))
+            .to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["code"], "route_not_found");
+        assert_eq!(body["request_id"], "edge-42");
+
+        // Statuses without a variant of their own are kept, not turned into 400
+        let resp = test::call_service(&app, test::TestRequest::post().uri("/ok").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "method_not_allowed");
+
+        let req = test::TestRequest::get().uri("/ok").to_request();
+        assert!(test::call_service(&app, req).await.headers().contains_key(REQUEST_ID_HEADER));
+    }
+}
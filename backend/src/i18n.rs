@@ -0,0 +1,151 @@
+use actix_web::body::BoxBody;
+use actix_web::http::header::{AcceptLanguage, Header, Preference, CONTENT_LANGUAGE};
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Responder};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// Message catalogs compiled into the binary, keyed by the stable codes sent to clients.
+// The first one is the default. Adding a language only takes a new file under locales/ and a line here.
+const CATALOGS: [(&str, &str); 2] = [
+    ("es", include_str!("../locales/es.toml")),
+    ("en", include_str!("../locales/en.toml")),
+];
+
+type Catalog = HashMap<String, String>;
+
+fn catalogs() -> &'static [(&'static str, Catalog)] {
+    static PARSED: OnceLock<Vec<(&'static str, Catalog)>> = OnceLock::new();
+    PARSED.get_or_init(|| {
+        CATALOGS
+            .iter()
+            .map(|(language, source)| {
+                let catalog = toml::from_str(source)
+                    .unwrap_or_else(|error| panic!("locales/{}.toml is not a valid catalog: {}", language, error));
+                (*language, catalog)
+            })
+            .collect()
+    })
+}
+
+// One of the languages in CATALOGS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale(usize);
+
+// The language the authenticated user picked, left in the request extensions by the auth extractors
+#[derive(Debug, Clone, Copy)]
+pub struct PreferredLocale(pub Locale);
+
+impl Locale {
+    pub const DEFAULT: Locale = Locale(0);
+
+    // Accepts a primary tag ("en") as well as a regional one ("en-US"), in any case
+    pub fn from_tag(tag: &str) -> Option<Locale> {
+        let primary = tag.split(['-', '_']).next()?.trim();
+        CATALOGS
+            .iter()
+            .position(|(language, _)| language.eq_ignore_ascii_case(primary))
+            .map(Locale)
+    }
+
+    pub fn tag(self) -> &'static str {
+        CATALOGS[self.0].0
+    }
+
+    // The user's own choice wins; otherwise the best match from Accept-Language
+    pub fn of(req: &HttpRequest) -> Locale {
+        if let Some(PreferredLocale(locale)) = req.extensions().get::<PreferredLocale>() {
+            return *locale;
+        }
+        AcceptLanguage::parse(req)
+            .ok()
+            .and_then(|accepted| {
+                accepted.ranked().into_iter().find_map(|preference| match preference {
+                    Preference::Any => Some(Locale::DEFAULT),
+                    Preference::Specific(tag) => Locale::from_tag(tag.primary_language()),
+                })
+            })
+            .unwrap_or(Locale::DEFAULT)
+    }
+
+    // Codes missing from a catalog fall back to the default language, then to the code itself
+    pub fn text(self, code: &str) -> &str {
+        let catalogs = catalogs();
+        catalogs[self.0]
+            .1
+            .get(code)
+            .or_else(|| catalogs[Locale::DEFAULT.0].1.get(code))
+            .map(String::as_str)
+            .unwrap_or(code)
+    }
+}
+
+// A plain-text success reply, written in the caller's language when it is sent
+pub struct Message(pub &'static str);
+
+impl Responder for Message {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        let locale = Locale::of(req);
+        HttpResponse::Ok()
+            .insert_header((CONTENT_LANGUAGE, locale.tag()))
+            .body(locale.text(self.0).to_string())
+    }
+}
+
+// Database functions
+pub fn find_user_locale(conn: &Connection, user_id: i32) -> Result<Option<Locale>> {
+    let language: Option<String> = conn
+        .query_row("SELECT language FROM users WHERE id = ?1", [user_id], |row| row.get(0))
+        .optional()?
+        .flatten();
+    Ok(language.as_deref().and_then(Locale::from_tag))
+}
+
+pub fn set_user_locale(conn: &Connection, user_id: i32, locale: Option<Locale>) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET language = ?1 WHERE id = ?2",
+        params![locale.map(Locale::tag), user_id],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn every_catalog_has_the_same_codes() {
+        let catalogs = catalogs();
+        let (_, default) = &catalogs[Locale::DEFAULT.0];
+        for (language, catalog) in catalogs {
+            let missing: Vec<_> = default.keys().filter(|code| !catalog.contains_key(*code)).collect();
+            let extra: Vec<_> = catalog.keys().filter(|code| !default.contains_key(*code)).collect();
+            assert!(missing.is_empty() && extra.is_empty(), "{}: missing {:?}, extra {:?}", language, missing, extra);
+        }
+    }
+
+    #[test]
+    fn negotiates_from_accept_language() {
+        let english = Locale::from_tag("en").unwrap();
+        let locale = |header: &str| {
+            Locale::of(&TestRequest::default().insert_header(("Accept-Language", header)).to_http_request())
+        };
+
+        assert_eq!(locale("en-US,en;q=0.9"), english);
+        assert_eq!(locale("fr-FR, en;q=0.5, es;q=0.8"), Locale::DEFAULT);
+        assert_eq!(locale("de, EN-gb;q=0.7"), english);
+        assert_eq!(locale("de"), Locale::DEFAULT);
+        assert_eq!(Locale::of(&TestRequest::default().to_http_request()), Locale::DEFAULT);
+
+        // A stored preference beats the header
+        let req = TestRequest::default().insert_header(("Accept-Language", "en")).to_http_request();
+        req.extensions_mut().insert(PreferredLocale(Locale::DEFAULT));
+        assert_eq!(Locale::of(&req), Locale::DEFAULT);
+
+        assert_eq!(english.text("task_not_found"), "Task not found");
+        assert_eq!(english.text("no_such_code"), "no_such_code");
+    }
+}
@@ -0,0 +1,131 @@
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, Transport};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::auth::now_secs;
+use crate::config::{MailConfig, MailTransportKind};
+use crate::sessions::generate_token;
+
+// A plain-text email to a single recipient
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum MailError {
+    Address(String),
+    Build(String),
+    Send(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailError::Address(address) => write!(f, "invalid email address: {}", address),
+            MailError::Build(reason) => write!(f, "could not build email: {}", reason),
+            MailError::Send(reason) => write!(f, "could not send email: {}", reason),
+            MailError::Io(error) => write!(f, "could not write email: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for MailError {}
+
+// Anything that can deliver an Email; pick one with `transport_from_env`
+pub trait MailTransport: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+fn parse_mailbox(address: &str) -> Result<Mailbox, MailError> {
+    address.parse().map_err(|_| MailError::Address(address.to_string()))
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message, MailError> {
+    Message::builder()
+        .from(from.clone())
+        .to(parse_mailbox(&email.to)?)
+        .subject(email.subject.clone())
+        .body(email.body.clone())
+        .map_err(|error| MailError::Build(error.to_string()))
+}
+
+// Delivers through an SMTP relay over TLS
+pub struct SmtpTransport {
+    from: Mailbox,
+    relay: lettre::SmtpTransport,
+}
+
+impl SmtpTransport {
+    pub fn new(host: &str, port: u16, credentials: Option<(String, String)>, from: &str) -> Result<Self, MailError> {
+        let mut builder = lettre::SmtpTransport::relay(host)
+            .map_err(|error| MailError::Send(error.to_string()))?
+            .port(port);
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(SmtpTransport {
+            from: parse_mailbox(from)?,
+            relay: builder.build(),
+        })
+    }
+}
+
+impl MailTransport for SmtpTransport {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        self.relay
+            .send(&message)
+            .map(|_| ())
+            .map_err(|error| MailError::Send(error.to_string()))
+    }
+}
+
+// Writes every email as an .eml file into an outbox directory instead of sending it
+pub struct FileTransport {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl FileTransport {
+    pub fn new(dir: impl Into<PathBuf>, from: &str) -> Result<Self, MailError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(MailError::Io)?;
+        Ok(FileTransport {
+            from: parse_mailbox(from)?,
+            dir,
+        })
+    }
+}
+
+impl MailTransport for FileTransport {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        let file_name = format!("{}-{}.eml", now_secs(), &generate_token()[..8]);
+        fs::write(self.dir.join(file_name), message.formatted()).map_err(MailError::Io)
+    }
+}
+
+pub fn transport_from_config(config: &MailConfig) -> Result<Arc<dyn MailTransport>, MailError> {
+    match config.transport {
+        MailTransportKind::Smtp => {
+            let host = config
+                .smtp_host
+                .as_deref()
+                .ok_or_else(|| MailError::Send("SMTP_HOST is not set".to_string()))?;
+            let credentials = match (&config.smtp_username, &config.smtp_password) {
+                (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+                _ => None,
+            };
+            Ok(Arc::new(SmtpTransport::new(host, config.smtp_port, credentials, &config.from)?))
+        }
+        MailTransportKind::File => Ok(Arc::new(FileTransport::new(&config.outbox_dir, &config.from)?)),
+    }
+}
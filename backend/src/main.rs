@@ -1,65 +1,80 @@
-use actix_web::{web, HttpResponse, Responder, App, HttpServer};
-use actix_cors::Cors;
-use serde::{Serialize, Deserialize};
-use bcrypt::{hash, verify, DEFAULT_COST};
-use rusqlite::{Connection, Result};
-use std::sync::{Arc, Mutex};
-
-// User data structure
-#[derive(Debug, Serialize, Deserialize)]
-struct User {
-    id: i32,
-    username: String,
-    password_hash: String,
-}
-
-// Task data structure
-#[derive(Debug, Serialize, Deserialize)]
-struct Task {
-    id: i32,
-    title: String,
-    status: String, 
-    note: Option<String>,
-    user_id: i32,
-}
-
-// Subject data structure
-#[derive(Debug, Serialize, Deserialize)]
-struct Subject {
-    id: i32,
-    name: String,
-    user_id: i32,
-}
-
-// ExamDate data structure
-#[derive(Debug, Serialize, Deserialize)]
-struct ExamDate {
-    id: i32,
-    subject_id: i32,
-    date: String,
-}
-
-// Note data structure
-#[derive(Debug, Serialize, Deserialize)]
-struct Note {
-    id: i32,
-    subject_id: i32,
-    content: String,
-}
-
-// FileLink data structure
-#[derive(Debug, Serialize, Deserialize)]
-struct FileLink {
-    id: i32,
-    subject_id: i32,
-    url: String,
-}
+mod account;
+mod admin;
+mod api_tokens;
+mod auth;
+mod authz;
+mod blobs;
+mod config;
+mod db;
+mod error;
+mod i18n;
+mod mail;
+mod migrations;
+mod password_reset;
+mod rate_limit;
+mod repository;
+mod sessions;
+mod two_factor;
+mod uploads;
+mod validation;
+
+use actix_web::{middleware::Logger, web, HttpRequest, HttpResponse, App, HttpServer};
+use serde::Deserialize;
+use similar::{ChangeTag, TextDiff};
+use account::USERNAME_TAKEN;
+use api_tokens::Permission;
+use auth::{create_challenge_token, hash_password, verify_password, AuthenticatedUser, JwtKeys};
+use config::Config;
+use error::{AppError, AppResult, RequestId};
+use i18n::{Locale, Message};
+use repository::{
+    Accounts, ExamDateChanges, ExamKind, FileLink, FileLinkChanges, LinkKind, NewExamDate, NewFileLink, NoteChanges,
+    Repositories, SubjectChanges,
+};
+use authz::{authorize, Resource};
+use blobs::BlobStore;
+use sessions::{device_name, login_response, start_session};
+use validation::{
+    normalize_datetime, normalize_email, normalize_username, nullable, validate_color, validate_datetime,
+    validate_email, validate_http_url, validate_label, validate_max_len, validate_password, validate_username,
+    Validate, ValidationErrors, LABEL_MAX_LEN,
+};
+
+// Answered when a row passed the ownership check but was gone by the time it was written
+const TASK_NOT_FOUND: AppError = AppError::NotFound("task_not_found");
+const SUBJECT_NOT_FOUND: AppError = AppError::NotFound("subject_not_found");
+const NOTE_NOT_FOUND: AppError = AppError::NotFound("note_not_found");
+const EXAM_DATE_NOT_FOUND: AppError = AppError::NotFound("exam_date_not_found");
+const NOTE_REVISION_NOT_FOUND: AppError = AppError::NotFound("note_revision_not_found");
+const FILE_LINK_NOT_FOUND: AppError = AppError::NotFound("file_link_not_found");
+
+const SUBJECT_ICON_MAX_LEN: usize = 32;
+const COURSE_CODE_MAX_LEN: usize = 32;
+// A whole day, which covers take-home exams
+const EXAM_DURATION_MAX_MINUTES: i32 = 24 * 60;
+const EXAM_NOTES_MAX_LEN: usize = 1000;
+// Room for a whole term of lecture notes, but not for pasting a book
+const NOTE_CONTENT_MAX_LEN: usize = 100_000;
 
 // Request structures
 #[derive(Debug, Deserialize)]
 struct RegisterRequest {
     username: String,
     password: String,
+    #[serde(default)]
+    email: Option<String>,
+}
+
+impl Validate for RegisterRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_username("username", &self.username, &mut errors);
+        validate_password("password", &self.password, &self.username, &mut errors);
+        if let Some(email) = &self.email {
+            validate_email("email", email, &mut errors);
+        }
+        errors.into_result()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -73,7 +88,6 @@ struct AddTaskRequest {
     title: String,
     status: String,
     note: Option<String>,
-    user_id: i32,
 }
 
 #[derive(Debug, Deserialize)]
@@ -91,599 +105,1362 @@ struct UpdateTaskNoteRequest {
 #[derive(Debug, Deserialize)]
 struct AddSubjectRequest {
     name: String,
-    user_id: i32,
+}
+
+impl Validate for AddSubjectRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_label("name", &self.name, "subject_name_invalid", &mut errors);
+        errors.into_result()
+    }
+}
+
+// Fields left out stay as they are; null clears the optional ones
+#[derive(Debug, Deserialize)]
+struct UpdateSubjectRequest {
+    name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    color: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    icon: Option<Option<String>>,
+    display_order: Option<i32>,
+    archived: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    professor: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    course_code: Option<Option<String>>,
+}
+
+impl Validate for UpdateSubjectRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(name) = &self.name {
+            validate_label("name", name, "subject_name_invalid", &mut errors);
+        }
+        if let Some(Some(color)) = &self.color {
+            validate_color("color", color, &mut errors);
+        }
+        if let Some(Some(icon)) = &self.icon {
+            validate_max_len("icon", icon, SUBJECT_ICON_MAX_LEN, "subject_icon_invalid", &mut errors);
+        }
+        if let Some(Some(professor)) = &self.professor {
+            validate_max_len("professor", professor, LABEL_MAX_LEN, "subject_professor_invalid", &mut errors);
+        }
+        if let Some(Some(course_code)) = &self.course_code {
+            let code = "subject_course_code_invalid";
+            validate_max_len("course_code", course_code, COURSE_CODE_MAX_LEN, code, &mut errors);
+        }
+        errors.into_result()
+    }
+}
+
+impl UpdateSubjectRequest {
+    fn into_changes(self) -> SubjectChanges {
+        SubjectChanges {
+            name: self.name.map(|name| name.trim().to_string()),
+            color: self.color.map(optional_text),
+            icon: self.icon.map(optional_text),
+            display_order: self.display_order,
+            archived: self.archived,
+            professor: self.professor.map(optional_text),
+            course_code: self.course_code.map(optional_text),
+        }
+    }
+}
+
+// Text is stored trimmed, and an optional field left blank is cleared
+fn optional_text(value: Option<String>) -> Option<String> {
+    value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+#[derive(Debug, Deserialize)]
+struct SubjectListQuery {
+    #[serde(default)]
+    include_archived: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct AddExamDateRequest {
     subject_id: i32,
     date: String,
+    duration_minutes: Option<i32>,
+    kind: Option<String>,
+    location: Option<String>,
+    notes: Option<String>,
+    weight: Option<f64>,
+}
+
+impl Validate for AddExamDateRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_datetime("date", &self.date, "exam_date_invalid", &mut errors);
+        validate_exam_details(
+            self.duration_minutes,
+            self.kind.as_deref(),
+            self.location.as_deref(),
+            self.notes.as_deref(),
+            self.weight,
+            &mut errors,
+        );
+        errors.into_result()
+    }
+}
+
+impl AddExamDateRequest {
+    // Only called once validated, so the date and kind parse
+    fn into_new_exam_date(self) -> NewExamDate {
+        NewExamDate {
+            date: normalize_datetime(&self.date).unwrap_or(self.date),
+            duration_minutes: self.duration_minutes,
+            kind: self.kind.as_deref().and_then(ExamKind::parse).unwrap_or(ExamKind::Parcial),
+            location: optional_text(self.location),
+            notes: optional_text(self.notes),
+            weight: self.weight,
+        }
+    }
+}
+
+// Fields left out stay as they are; null clears the optional ones
+#[derive(Debug, Deserialize)]
+struct UpdateExamDateRequest {
+    date: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    duration_minutes: Option<Option<i32>>,
+    kind: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    location: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    notes: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    weight: Option<Option<f64>>,
+}
+
+impl Validate for UpdateExamDateRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(date) = &self.date {
+            validate_datetime("date", date, "exam_date_invalid", &mut errors);
+        }
+        validate_exam_details(
+            self.duration_minutes.flatten(),
+            self.kind.as_deref(),
+            self.location.as_ref().and_then(Option::as_deref),
+            self.notes.as_ref().and_then(Option::as_deref),
+            self.weight.flatten(),
+            &mut errors,
+        );
+        errors.into_result()
+    }
+}
+
+impl UpdateExamDateRequest {
+    fn into_changes(self) -> ExamDateChanges {
+        ExamDateChanges {
+            date: self.date.map(|date| normalize_datetime(&date).unwrap_or(date)),
+            duration_minutes: self.duration_minutes,
+            kind: self.kind.as_deref().and_then(ExamKind::parse),
+            location: self.location.map(optional_text),
+            notes: self.notes.map(optional_text),
+            weight: self.weight,
+        }
+    }
+}
+
+// The rules shared by creating and editing an exam date, for the fields that were sent
+fn validate_exam_details(
+    duration_minutes: Option<i32>,
+    kind: Option<&str>,
+    location: Option<&str>,
+    notes: Option<&str>,
+    weight: Option<f64>,
+    errors: &mut ValidationErrors,
+) {
+    if duration_minutes.is_some_and(|minutes| !(1..=EXAM_DURATION_MAX_MINUTES).contains(&minutes)) {
+        errors.add("duration_minutes", "exam_duration_invalid");
+    }
+    if kind.is_some_and(|kind| ExamKind::parse(kind).is_none()) {
+        errors.add("kind", "exam_kind_invalid");
+    }
+    if let Some(location) = location {
+        validate_max_len("location", location, LABEL_MAX_LEN, "exam_location_invalid", errors);
+    }
+    if let Some(notes) = notes {
+        validate_max_len("notes", notes, EXAM_NOTES_MAX_LEN, "exam_notes_invalid", errors);
+    }
+    // A percentage of the final grade
+    if weight.is_some_and(|weight| !(0.0..=100.0).contains(&weight)) {
+        errors.add("weight", "exam_weight_invalid");
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct AddNoteRequest {
     subject_id: i32,
+    #[serde(default)]
+    title: Option<String>,
     content: String,
 }
 
+impl Validate for AddNoteRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_note(self.title.as_deref(), Some(&self.content), &mut errors);
+        errors.into_result()
+    }
+}
+
+// Fields left out stay as they are; a null title removes it
+#[derive(Debug, Deserialize)]
+struct UpdateNoteRequest {
+    #[serde(default, deserialize_with = "nullable")]
+    title: Option<Option<String>>,
+    content: Option<String>,
+}
+
+impl Validate for UpdateNoteRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_note(self.title.as_ref().and_then(Option::as_deref), self.content.as_deref(), &mut errors);
+        errors.into_result()
+    }
+}
+
+impl UpdateNoteRequest {
+    fn into_changes(self) -> NoteChanges {
+        NoteChanges {
+            title: self.title.map(optional_text),
+            content: self.content,
+        }
+    }
+}
+
+fn validate_note(title: Option<&str>, content: Option<&str>, errors: &mut ValidationErrors) {
+    if let Some(title) = title {
+        validate_max_len("title", title, LABEL_MAX_LEN, "note_title_invalid", errors);
+    }
+    if content.is_some_and(|content| content.chars().count() > NOTE_CONTENT_MAX_LEN) {
+        errors.add("content", "note_content_invalid");
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct AddFileLinkRequest {
     subject_id: i32,
     url: String,
+    label: Option<String>,
+    kind: Option<String>,
+}
+
+impl Validate for AddFileLinkRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        validate_http_url("url", &self.url, &mut errors);
+        validate_file_link_details(self.label.as_deref(), self.kind.as_deref(), &mut errors);
+        errors.into_result()
+    }
+}
+
+impl AddFileLinkRequest {
+    fn into_new_file_link(self) -> NewFileLink {
+        NewFileLink {
+            url: self.url.trim().to_string(),
+            label: optional_text(self.label),
+            kind: self.kind.as_deref().and_then(LinkKind::parse),
+        }
+    }
+}
+
+// Fields left out stay as they are; null clears the label and kind
+#[derive(Debug, Deserialize)]
+struct UpdateFileLinkRequest {
+    url: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    label: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    kind: Option<Option<String>>,
+}
+
+impl Validate for UpdateFileLinkRequest {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if let Some(url) = &self.url {
+            validate_http_url("url", url, &mut errors);
+        }
+        validate_file_link_details(
+            self.label.as_ref().and_then(Option::as_deref),
+            self.kind.as_ref().and_then(Option::as_deref),
+            &mut errors,
+        );
+        errors.into_result()
+    }
+}
+
+impl UpdateFileLinkRequest {
+    fn into_changes(self) -> FileLinkChanges {
+        FileLinkChanges {
+            url: self.url.map(|url| url.trim().to_string()),
+            label: self.label.map(optional_text),
+            kind: self.kind.map(|kind| kind.as_deref().and_then(LinkKind::parse)),
+        }
+    }
+}
+
+fn validate_file_link_details(label: Option<&str>, kind: Option<&str>, errors: &mut ValidationErrors) {
+    if let Some(label) = label {
+        validate_max_len("label", label, LABEL_MAX_LEN, "file_link_label_invalid", errors);
+    }
+    if kind.is_some_and(|kind| LinkKind::parse(kind).is_none()) {
+        errors.add("kind", "file_link_kind_invalid");
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FileLinkSort {
+    #[default]
+    CreatedAt,
+    Label,
+    Kind,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileLinkListQuery {
+    #[serde(default)]
+    sort: FileLinkSort,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+impl FileLinkListQuery {
+    // Ties keep the order the links were added in
+    fn sort(&self, file_links: &mut [FileLink]) {
+        match self.sort {
+            FileLinkSort::CreatedAt => file_links.sort_by_key(|file_link| (file_link.created_at, file_link.id)),
+            // Links without a label show their URL instead, so they sort by it
+            FileLinkSort::Label => file_links
+                .sort_by_cached_key(|file_link| file_link.label.as_ref().unwrap_or(&file_link.url).to_lowercase()),
+            // Links without a kind go last
+            FileLinkSort::Kind => {
+                file_links.sort_by_key(|file_link| (file_link.kind.is_none(), file_link.kind.map(LinkKind::as_str)))
+            }
+        }
+        if self.order == SortOrder::Desc {
+            file_links.reverse();
+        }
+    }
 }
 
 // Handler functions
 async fn register(
+    req: HttpRequest,
     register_info: web::Json<RegisterRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
-    let username = &register_info.username;
-    let password = &register_info.password;
-
-    let password_hash = match hash(password, DEFAULT_COST) {
-        Ok(hash) => hash,
-        Err(_) => return HttpResponse::InternalServerError().body("Error al hashear la contraseña"),
-    };
-
-    match insert_user(&db_conn, username, &password_hash) {
-        Ok(_) => HttpResponse::Ok().body("Usuario registrado exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al registrar el usuario"),
+    accounts: web::Data<Accounts>,
+    repos: web::Data<Repositories>,
+    config: web::Data<Config>,
+) -> AppResult<HttpResponse> {
+    // Every attempt counts, so one client cannot mass-create accounts
+    let ip_key = rate_limit::register_ip_key(&req);
+    rate_limit::check(&accounts, &[&ip_key]).await?;
+    accounts.login_attempts.record(ip_key, &rate_limit::REGISTER_PER_IP).await?;
+
+    register_info.validate()?;
+
+    let register_info = register_info.into_inner();
+    let username = normalize_username(&register_info.username);
+    let email = register_info.email.as_deref().map(normalize_email);
+    let password_hash = hash_password(register_info.password, config.bcrypt_cost).await?;
+
+    match repos.users.create(username, password_hash, email).await.map_err(AppError::from) {
+        Ok(user) => Ok(HttpResponse::Created().json(user)),
+        Err(AppError::Conflict(..)) => Err(USERNAME_TAKEN),
+        Err(error) => Err(error),
     }
 }
 
 async fn login(
+    req: HttpRequest,
     login_info: web::Json<LoginRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
-    let username = &login_info.username;
-    let password = &login_info.password;
-
-    match find_user(&db_conn, username) {
-        Ok(user) => {
-            if verify(password, &user.password_hash).unwrap_or(false) {
-                HttpResponse::Ok().json(serde_json::json!({ "message": "Inicio de sesión exitoso", "user_id": user.id }))
-            } else {
-                HttpResponse::Unauthorized().body("Credenciales inválidas")
-            }
+    accounts: web::Data<Accounts>,
+    repos: web::Data<Repositories>,
+    jwt_keys: web::Data<JwtKeys>,
+) -> AppResult<HttpResponse> {
+    let LoginRequest { username, password } = login_info.into_inner();
+    let username = normalize_username(&username);
+    let ip_key = rate_limit::login_ip_key(&req);
+    let user_key = rate_limit::login_user_key(&username);
+
+    // Counted as a failure up front, so parallel guesses cannot outrun the backoff while bcrypt runs
+    rate_limit::reserve(
+        &accounts,
+        &[(&ip_key, &rate_limit::LOGIN_PER_IP), (&user_key, &rate_limit::LOGIN_PER_USERNAME)],
+    )
+    .await?;
+
+    let authenticated = match repos.users.find_by_username(username).await? {
+        Some(user) if verify_password(password, user.password_hash.clone()).await => Some(user),
+        _ => None,
+    };
+
+    if authenticated.is_some() {
+        accounts.login_attempts.clear(user_key).await?;
+        accounts.login_attempts.refund(ip_key).await?;
+    }
+
+    match authenticated {
+        // With 2FA the password only earns a challenge for /login/2fa
+        Some(user) if user.totp_enabled => {
+            let challenge_token = create_challenge_token(&jwt_keys, user.id).map_err(AppError::internal)?;
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "message": Locale::of(&req).text("two_factor_code_required"),
+                "two_factor_required": true,
+                "challenge_token": challenge_token,
+            })))
+        }
+        Some(user) => {
+            let tokens = start_session(&accounts, &jwt_keys, user.id, device_name(&req)).await?;
+            Ok(login_response(Locale::of(&req), user.id, tokens))
         }
-        Err(_) => HttpResponse::Unauthorized().body("Credenciales inválidas"),
+        None => Err(AppError::Unauthorized("invalid_credentials")),
     }
 }
 
 async fn add_task(
+    user: AuthenticatedUser,
     add_task_info: web::Json<AddTaskRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
-    let title = &add_task_info.title;
-    let status = &add_task_info.status;
-    let note = add_task_info.note.as_deref();
-    let user_id = add_task_info.user_id;
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteTasks)?;
 
-    match insert_task(&db_conn, title, status, note, user_id) {
-        Ok(_) => HttpResponse::Ok().body("Tarea agregada exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al agregar la tarea"),
-    }
+    let AddTaskRequest { title, status, note } = add_task_info.into_inner();
+
+    let task = repos.tasks.create(user.user_id, title, status, note).await?;
+    Ok(HttpResponse::Created().json(task))
 }
 
 async fn delete_task(
+    user: AuthenticatedUser,
     task_id: web::Path<i32>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
+    repos: web::Data<Repositories>,
+) -> AppResult<Message> {
+    user.require(Permission::WriteTasks)?;
+
     let id = task_id.into_inner();
 
-    match remove_task(&db_conn, id) {
-        Ok(_) => HttpResponse::Ok().body("Tarea eliminada exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al eliminar la tarea"),
+    authorize(&repos, &user, Resource::Task(id)).await?;
+
+    if !repos.tasks.delete(id).await? {
+        return Err(TASK_NOT_FOUND);
     }
+    Ok(Message("task_deleted"))
 }
 
 async fn update_task_status(
+    user: AuthenticatedUser,
     update_info: web::Json<UpdateTaskStatusRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
-    let task_id = update_info.task_id;
-    let new_status = &update_info.new_status;
+    repos: web::Data<Repositories>,
+) -> AppResult<Message> {
+    user.require(Permission::WriteTasks)?;
+
+    let UpdateTaskStatusRequest { task_id, new_status } = update_info.into_inner();
+
+    authorize(&repos, &user, Resource::Task(task_id)).await?;
 
     match new_status.as_str() {
         "Pendiente" | "En ejecucion" | "Tarea finalizada" => {
-            match modify_task_status(&db_conn, task_id, new_status) {
-                Ok(_) => HttpResponse::Ok().body("Estado de la tarea actualizado exitosamente"),
-                Err(_) => HttpResponse::InternalServerError().body("Error al actualizar el estado de la tarea"),
+            if !repos.tasks.update_status(task_id, new_status).await? {
+                return Err(TASK_NOT_FOUND);
             }
+            Ok(Message("task_status_updated"))
         }
-        _ => HttpResponse::BadRequest().body("Estado de tarea no válido"),
+        _ => Err(AppError::BadRequest("task_status_invalid")),
     }
 }
 
 async fn update_task_note(
+    user: AuthenticatedUser,
     update_info: web::Json<UpdateTaskNoteRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
-    let task_id = update_info.task_id;
-    let new_note = &update_info.new_note;
+    repos: web::Data<Repositories>,
+) -> AppResult<Message> {
+    user.require(Permission::WriteTasks)?;
+
+    let UpdateTaskNoteRequest { task_id, new_note } = update_info.into_inner();
 
-    match modify_task_note(&db_conn, task_id, new_note) {
-        Ok(_) => HttpResponse::Ok().body("Nota de la tarea actualizada exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al actualizar la nota de la tarea"),
+    authorize(&repos, &user, Resource::Task(task_id)).await?;
+
+    if !repos.tasks.update_note(task_id, new_note).await? {
+        return Err(TASK_NOT_FOUND);
     }
+    Ok(Message("task_note_updated"))
 }
 
 async fn add_subject(
+    user: AuthenticatedUser,
     add_subject_info: web::Json<AddSubjectRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
-    let name = &add_subject_info.name;
-    let user_id = add_subject_info.user_id;
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+    add_subject_info.validate()?;
 
-    match insert_subject(&db_conn, name, user_id) {
-        Ok(_) => HttpResponse::Ok().body("Materia agregada exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al agregar la materia"),
-    }
+    let name = add_subject_info.into_inner().name.trim().to_string();
+
+    let subject = repos.subjects.create(user.user_id, name).await?;
+    Ok(HttpResponse::Created().json(subject))
+}
+
+async fn update_subject(
+    user: AuthenticatedUser,
+    subject_id: web::Path<i32>,
+    update_subject_info: web::Json<UpdateSubjectRequest>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
+    let id = subject_id.into_inner();
+
+    authorize(&repos, &user, Resource::Subject(id)).await?;
+    update_subject_info.validate()?;
+
+    let changes = update_subject_info.into_inner().into_changes();
+    let subject = repos.subjects.update(id, changes).await?.ok_or(SUBJECT_NOT_FOUND)?;
+    Ok(HttpResponse::Ok().json(subject))
 }
 
 async fn delete_subject(
+    user: AuthenticatedUser,
     subject_id: web::Path<i32>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
+    repos: web::Data<Repositories>,
+    blobs: web::Data<dyn BlobStore>,
+) -> AppResult<Message> {
+    user.require(Permission::WriteSubjects)?;
+
     let id = subject_id.into_inner();
 
-    match remove_subject(&db_conn, id) {
-        Ok(_) => HttpResponse::Ok().body("Materia eliminada exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al eliminar la materia"),
+    authorize(&repos, &user, Resource::Subject(id)).await?;
+
+    // The uploaded files go with the subject, so their blobs may be left unused
+    let hashes = repos.files.list(id).await?.into_iter().map(|file| file.sha256).collect();
+    if !repos.subjects.delete(id).await? {
+        return Err(SUBJECT_NOT_FOUND);
     }
+    uploads::release_blobs(&repos, &blobs, hashes).await;
+    Ok(Message("subject_deleted"))
 }
 
 async fn add_exam_date(
+    user: AuthenticatedUser,
     add_exam_date_info: web::Json<AddExamDateRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
     let subject_id = add_exam_date_info.subject_id;
-    let date = &add_exam_date_info.date;
 
-    match insert_exam_date(&db_conn, subject_id, date) {
-        Ok(_) => HttpResponse::Ok().body("Fecha de examen agregada exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al agregar la fecha de examen"),
+    authorize(&repos, &user, Resource::Subject(subject_id)).await?;
+    add_exam_date_info.validate()?;
+
+    let exam = add_exam_date_info.into_inner().into_new_exam_date();
+    let exam_date = repos.exam_dates.create(subject_id, exam).await?;
+    Ok(HttpResponse::Created().json(exam_date))
+}
+
+async fn update_exam_date(
+    user: AuthenticatedUser,
+    exam_date_id: web::Path<i32>,
+    update_exam_date_info: web::Json<UpdateExamDateRequest>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
+    let id = exam_date_id.into_inner();
+
+    authorize(&repos, &user, Resource::ExamDate(id)).await?;
+    update_exam_date_info.validate()?;
+
+    let changes = update_exam_date_info.into_inner().into_changes();
+    let exam_date = repos.exam_dates.update(id, changes).await?.ok_or(EXAM_DATE_NOT_FOUND)?;
+    Ok(HttpResponse::Ok().json(exam_date))
+}
+
+async fn delete_exam_date(
+    user: AuthenticatedUser,
+    exam_date_id: web::Path<i32>,
+    repos: web::Data<Repositories>,
+) -> AppResult<Message> {
+    user.require(Permission::WriteSubjects)?;
+
+    let id = exam_date_id.into_inner();
+
+    authorize(&repos, &user, Resource::ExamDate(id)).await?;
+
+    if !repos.exam_dates.delete(id).await? {
+        return Err(EXAM_DATE_NOT_FOUND);
     }
+    Ok(Message("exam_date_deleted"))
 }
 
 async fn add_note(
+    user: AuthenticatedUser,
     add_note_info: web::Json<AddNoteRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
     let subject_id = add_note_info.subject_id;
-    let content = &add_note_info.content;
 
-    match insert_note(&db_conn, subject_id, content) {
-        Ok(_) => HttpResponse::Ok().body("Nota agregada exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al agregar la nota"),
-    }
+    authorize(&repos, &user, Resource::Subject(subject_id)).await?;
+    add_note_info.validate()?;
+
+    let AddNoteRequest { title, content, .. } = add_note_info.into_inner();
+    let note = repos.notes.create(subject_id, optional_text(title), content).await?;
+    Ok(HttpResponse::Created().json(note))
+}
+
+async fn update_note(
+    user: AuthenticatedUser,
+    note_id: web::Path<i32>,
+    update_note_info: web::Json<UpdateNoteRequest>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
+    let id = note_id.into_inner();
+
+    authorize(&repos, &user, Resource::Note(id)).await?;
+    update_note_info.validate()?;
+
+    let changes = update_note_info.into_inner().into_changes();
+    let note = repos.notes.update(id, changes).await?.ok_or(NOTE_NOT_FOUND)?;
+    Ok(HttpResponse::Ok().json(note))
+}
+
+async fn get_note_revisions(
+    user: AuthenticatedUser,
+    note_id: web::Path<i32>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadSubjects)?;
+
+    let id = note_id.into_inner();
+    authorize(&repos, &user, Resource::Note(id)).await?;
+
+    let revisions = repos.notes.revisions(id).await?;
+    Ok(HttpResponse::Ok().json(revisions))
+}
+
+// What changed from a revision to the note as it is now, line by line
+async fn diff_note_revision(
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadSubjects)?;
+
+    let (id, revision_id) = path.into_inner();
+    authorize(&repos, &user, Resource::Note(id)).await?;
+
+    let revision = repos.notes.find_revision(id, revision_id).await?.ok_or(NOTE_REVISION_NOT_FOUND)?;
+    let note = repos.notes.find(id).await?.ok_or(NOTE_NOT_FOUND)?;
+
+    // A last line without a newline would otherwise never match the same line once more text follows it
+    let terminated = |text: &str| if text.ends_with('\n') { text.to_string() } else { format!("{}\n", text) };
+    let (before, after) = (terminated(&revision.content), terminated(&note.content));
+    let lines: Vec<serde_json::Value> = TextDiff::from_lines(&before, &after)
+        .iter_all_changes()
+        .map(|change| {
+            let op = match change.tag() {
+                ChangeTag::Equal => "equal",
+                ChangeTag::Delete => "delete",
+                ChangeTag::Insert => "insert",
+            };
+            serde_json::json!({ "op": op, "text": change.as_str().unwrap_or_default().trim_end_matches('\n') })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "revision_id": revision.id,
+        "title_before": revision.title,
+        "title_after": note.title,
+        "lines": lines,
+    })))
+}
+
+// Puts a revision's text back; the version it replaces becomes a revision itself, so nothing is lost
+async fn restore_note_revision(
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
+    let (id, revision_id) = path.into_inner();
+    authorize(&repos, &user, Resource::Note(id)).await?;
+
+    let revision = repos.notes.find_revision(id, revision_id).await?.ok_or(NOTE_REVISION_NOT_FOUND)?;
+    let changes = NoteChanges {
+        title: Some(revision.title),
+        content: Some(revision.content),
+    };
+    let note = repos.notes.update(id, changes).await?.ok_or(NOTE_NOT_FOUND)?;
+    Ok(HttpResponse::Ok().json(note))
 }
 
 async fn add_file_link(
+    user: AuthenticatedUser,
     add_file_link_info: web::Json<AddFileLinkRequest>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
     let subject_id = add_file_link_info.subject_id;
-    let url = &add_file_link_info.url;
 
-    match insert_file_link(&db_conn, subject_id, url) {
-        Ok(_) => HttpResponse::Ok().body("Enlace de archivo agregado exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al agregar el enlace de archivo"),
-    }
+    authorize(&repos, &user, Resource::Subject(subject_id)).await?;
+    add_file_link_info.validate()?;
+
+    let link = add_file_link_info.into_inner().into_new_file_link();
+    let file_link = repos.file_links.create(subject_id, link).await?;
+    Ok(HttpResponse::Created().json(file_link))
 }
 
-// Getters
-async fn get_tasks(db_conn: web::Data<Arc<Mutex<Connection>>>, user_id: web::Path<i32>) -> impl Responder {
-    let conn = db_conn.lock().unwrap();
-    let mut stmt = conn.prepare("SELECT id, title, status, note, user_id FROM tasks WHERE user_id = ?1").unwrap();
-    let task_iter = stmt.query_map(&[&user_id.into_inner()], |row| {
-        Ok(Task {
-            id: row.get(0)?,
-            title: row.get(1)?,
-            status: row.get(2)?,
-            note: row.get(3)?,
-            user_id: row.get(4)?,
-        })
-    }).unwrap();
+async fn update_file_link(
+    user: AuthenticatedUser,
+    file_link_id: web::Path<i32>,
+    update_file_link_info: web::Json<UpdateFileLinkRequest>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
+    let id = file_link_id.into_inner();
 
-    let tasks: Vec<Task> = task_iter.map(|task| task.unwrap()).collect();
+    authorize(&repos, &user, Resource::FileLink(id)).await?;
+    update_file_link_info.validate()?;
 
-    HttpResponse::Ok().json(tasks)
+    let changes = update_file_link_info.into_inner().into_changes();
+    let file_link = repos.file_links.update(id, changes).await?.ok_or(FILE_LINK_NOT_FOUND)?;
+    Ok(HttpResponse::Ok().json(file_link))
 }
 
-async fn get_subjects(
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-    user_id: web::Path<i32>,
-) -> impl Responder {
-    let conn = db_conn.lock().unwrap();
-    let mut stmt = conn.prepare("SELECT id, name, user_id FROM subjects WHERE user_id = ?1").unwrap();
-    let subject_iter = stmt.query_map(&[&user_id.to_string()], |row| {
-        Ok(Subject {
-            id: row.get(0)?,
-            name: row.get(1)?,
-            user_id: row.get(2)?,
-        })
-    }).unwrap();
+// Getters
+async fn get_tasks(user: AuthenticatedUser, repos: web::Data<Repositories>) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadTasks)?;
 
-    let subjects: Vec<Subject> = subject_iter.map(|subject| subject.unwrap()).collect();
+    let tasks = repos.tasks.list(user.user_id).await?;
+    Ok(HttpResponse::Ok().json(tasks))
+}
 
-    HttpResponse::Ok().json(subjects)
+async fn get_subjects(
+    user: AuthenticatedUser,
+    query: web::Query<SubjectListQuery>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadSubjects)?;
+
+    let subjects = repos.subjects.list(user.user_id, query.include_archived).await?;
+    Ok(HttpResponse::Ok().json(subjects))
 }
 
 async fn get_exam_dates(
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
+    user: AuthenticatedUser,
+    repos: web::Data<Repositories>,
     subject_id: web::Path<i32>,
-) -> impl Responder {
-    let conn = db_conn.lock().unwrap();
-    let mut stmt = conn.prepare("SELECT id, subject_id, date FROM exam_dates WHERE subject_id = ?1").unwrap();
-    let exam_date_iter = stmt.query_map(&[&subject_id.into_inner()], |row| {
-        Ok(ExamDate {
-            id: row.get(0)?,
-            subject_id: row.get(1)?,
-            date: row.get(2)?,
-        })
-    }).unwrap();
+) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadSubjects)?;
 
-    let exam_dates: Vec<ExamDate> = exam_date_iter.map(|exam_date| exam_date.unwrap()).collect();
+    let subject_id = subject_id.into_inner();
+    authorize(&repos, &user, Resource::Subject(subject_id)).await?;
 
-    HttpResponse::Ok().json(exam_dates)
+    let exam_dates = repos.exam_dates.list(subject_id).await?;
+    Ok(HttpResponse::Ok().json(exam_dates))
 }
 
 async fn get_notes(
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
+    user: AuthenticatedUser,
+    repos: web::Data<Repositories>,
     subject_id: web::Path<i32>,
-) -> impl Responder {
-    let conn = db_conn.lock().unwrap();
-    let mut stmt = conn.prepare("SELECT id, subject_id, content FROM notes WHERE subject_id = ?1").unwrap();
-    let note_iter = stmt.query_map(&[&subject_id.into_inner()], |row| {
-        Ok(Note {
-            id: row.get(0)?,
-            subject_id: row.get(1)?,
-            content: row.get(2)?,
-        })
-    }).unwrap();
+) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadSubjects)?;
 
-    let notes: Vec<Note> = note_iter.map(|note| note.unwrap()).collect();
+    let subject_id = subject_id.into_inner();
+    authorize(&repos, &user, Resource::Subject(subject_id)).await?;
 
-    HttpResponse::Ok().json(notes)
+    let notes = repos.notes.list(subject_id).await?;
+    Ok(HttpResponse::Ok().json(notes))
 }
 
 async fn get_file_links(
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
+    user: AuthenticatedUser,
+    repos: web::Data<Repositories>,
     subject_id: web::Path<i32>,
-) -> impl Responder {
-    let conn = db_conn.lock().unwrap();
-    let mut stmt = conn.prepare("SELECT id, subject_id, url FROM file_links WHERE subject_id = ?1").unwrap();
-    let file_link_iter = stmt.query_map(&[&subject_id.into_inner()], |row| {
-        Ok(FileLink {
-            id: row.get(0)?,
-            subject_id: row.get(1)?,
-            url: row.get(2)?,
-        })
-    }).unwrap();
+    query: web::Query<FileLinkListQuery>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadSubjects)?;
 
-    let file_links: Vec<FileLink> = file_link_iter.map(|file_link| file_link.unwrap()).collect();
+    let subject_id = subject_id.into_inner();
+    authorize(&repos, &user, Resource::Subject(subject_id)).await?;
 
-    HttpResponse::Ok().json(file_links)
+    let mut file_links = repos.file_links.list(subject_id).await?;
+    query.sort(&mut file_links);
+    Ok(HttpResponse::Ok().json(file_links))
 }
+
+async fn delete_file_link(
+    user: AuthenticatedUser,
+    file_link_id: web::Path<i32>,
+    repos: web::Data<Repositories>,
+) -> AppResult<Message> {
+    user.require(Permission::WriteSubjects)?;
+
+    let id = file_link_id.into_inner();
+
+    authorize(&repos, &user, Resource::FileLink(id)).await?;
+
+    if !repos.file_links.delete(id).await? {
+        return Err(FILE_LINK_NOT_FOUND);
+    }
+    Ok(Message("file_link_deleted"))
+}
+
 async fn delete_note(
+    user: AuthenticatedUser,
     note_id: web::Path<i32>,
-    db_conn: web::Data<Arc<Mutex<Connection>>>,
-) -> impl Responder {
+    repos: web::Data<Repositories>,
+) -> AppResult<Message> {
+    user.require(Permission::WriteSubjects)?;
+
     let id = note_id.into_inner();
 
-    match remove_note(&db_conn, id) {
-        Ok(_) => HttpResponse::Ok().body("Nota eliminada exitosamente"),
-        Err(_) => HttpResponse::InternalServerError().body("Error al eliminar la nota"),
-    }
-}
-
-fn remove_note(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    note_id: i32,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "DELETE FROM notes WHERE id = ?1",
-        &[&note_id.to_string()],
-    )?;
-    Ok(())
-}
-// Database modification functions
-fn insert_user(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    username: &str,
-    password_hash: &str,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "INSERT INTO users (username, password_hash) VALUES (?1, ?2)",
-        &[username, password_hash],
-    )?;
-    Ok(())
-}
-
-fn find_user(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    username: &str,
-) -> Result<User> {
-    let mut conn = db_conn.lock().unwrap();
-    let mut stmt = conn.prepare(
-        "SELECT id, username, password_hash FROM users WHERE username = ?1",
-    )?;
-    let user_row = stmt.query_row(&[username], |row| {
-        Ok(User {
-            id: row.get(0)?,
-            username: row.get(1)?,
-            password_hash: row.get(2)?,
-        })
-    })?;
-    Ok(user_row)
-}
-
-fn insert_task(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    title: &str,
-    status: &str,
-    note: Option<&str>,
-    user_id: i32,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "INSERT INTO tasks (title, status, note, user_id) VALUES (?1, ?2, ?3, ?4)",
-        &[title, status, &note.unwrap_or(""), &user_id.to_string()],
-    )?;
-    Ok(())
-}
-
-fn remove_task(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    task_id: i32,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "DELETE FROM tasks WHERE id = ?1",
-        &[&task_id.to_string()],
-    )?;
-    Ok(())
+    authorize(&repos, &user, Resource::Note(id)).await?;
+
+    if !repos.notes.delete(id).await? {
+        return Err(NOTE_NOT_FOUND);
+    }
+    Ok(Message("note_deleted"))
 }
 
-fn modify_task_status(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    task_id: i32,
-    new_status: &str,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "UPDATE tasks SET status = ?1 WHERE id = ?2",
-        &[new_status, &task_id.to_string()],
-    )?;
-    Ok(())
-}
-
-fn modify_task_note(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    task_id: i32,
-    new_note: &str,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "UPDATE tasks SET note = ?1 WHERE id = ?2",
-        &[new_note, &task_id.to_string()],
-    )?;
-    Ok(())
-}
-
-fn insert_subject(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    name: &str,
-    user_id: i32,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "INSERT INTO subjects (name, user_id) VALUES (?1, ?2)",
-        &[name, &user_id.to_string()],
-    )?;
-    Ok(())
-}
-
-fn remove_subject(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    subject_id: i32,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "DELETE FROM notes WHERE subject_id = ?1",
-        &[&subject_id.to_string()],
-    )?;
-    conn.execute(
-        "DELETE FROM exam_dates WHERE subject_id = ?1",
-        &[&subject_id.to_string()],
-    )?;
-    conn.execute(
-        "DELETE FROM file_links WHERE subject_id = ?1",
-        &[&subject_id.to_string()],
-    )?;
-    conn.execute(
-        "DELETE FROM subjects WHERE id = ?1",
-        &[&subject_id.to_string()],
-    )?;
-    Ok(())
-}
-
-
-fn insert_exam_date(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    subject_id: i32,
-    date: &str,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "INSERT INTO exam_dates (subject_id, date) VALUES (?1, ?2)",
-        &[&subject_id.to_string(), date],
-    )?;
-    Ok(())
-}
-
-fn insert_note(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    subject_id: i32,
-    content: &str,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "INSERT INTO notes (subject_id, content) VALUES (?1, ?2)",
-        &[&subject_id.to_string(), content],
-    )?;
-    Ok(())
-}
-
-fn insert_file_link(
-    db_conn: &web::Data<Arc<Mutex<Connection>>>,
-    subject_id: i32,
-    url: &str,
-) -> Result<()> {
-    let mut conn = db_conn.lock().unwrap();
-    conn.execute(
-        "INSERT INTO file_links (subject_id, url) VALUES (?1, ?2)",
-        &[&subject_id.to_string(), url],
-    )?;
-    Ok(())
+// Register every API route on the app
+fn configure_routes(cfg: &mut web::ServiceConfig) {
+    // Bodies and paths that do not parse are answered like any other error
+    cfg.app_data(web::JsonConfig::default().error_handler(|_, _| {
+        AppError::BadRequest("invalid_body").into()
+    }))
+    .app_data(web::PathConfig::default().error_handler(|_, _| {
+        AppError::BadRequest("invalid_path").into()
+    }))
+    .app_data(web::QueryConfig::default().error_handler(|_, _| {
+        AppError::BadRequest("invalid_query").into()
+    }));
+
+    cfg
+        .service(web::resource("/register").route(web::post().to(register)))
+        .service(web::resource("/login").route(web::post().to(login)))
+        .service(web::resource("/login/2fa").route(web::post().to(two_factor::login_second_step)))
+        .service(web::resource("/2fa/enroll").route(web::post().to(two_factor::enroll)))
+        .service(web::resource("/2fa/verify").route(web::post().to(two_factor::verify_enrollment)))
+        .service(web::resource("/2fa/disable").route(web::post().to(two_factor::disable)))
+        .service(web::resource("/password_reset/request").route(web::post().to(password_reset::request_reset)))
+        .service(web::resource("/password_reset/confirm").route(web::post().to(password_reset::confirm_reset)))
+        .service(web::resource("/refresh").route(web::post().to(sessions::refresh)))
+        .service(web::resource("/logout").route(web::post().to(sessions::logout)))
+        .service(web::resource("/logout_all").route(web::post().to(sessions::logout_all)))
+        .service(web::resource("/sessions").route(web::get().to(sessions::get_sessions)))
+        .service(web::resource("/sessions/{session_id}").route(web::delete().to(sessions::delete_session)))
+        .service(
+            web::resource("/api_tokens")
+                .route(web::get().to(api_tokens::get_api_tokens))
+                .route(web::post().to(api_tokens::create_api_token)),
+        )
+        .service(web::resource("/api_tokens/{token_id}").route(web::delete().to(api_tokens::delete_api_token)))
+        .service(web::resource("/change_password").route(web::post().to(account::change_password)))
+        .service(web::resource("/change_email").route(web::post().to(account::change_email)))
+        .service(web::resource("/change_username").route(web::post().to(account::change_username)))
+        .service(web::resource("/change_language").route(web::post().to(account::change_language)))
+        .service(web::resource("/delete_account").route(web::delete().to(account::delete_account)))
+        .service(
+            web::scope("/admin")
+                .service(web::resource("/users").route(web::get().to(admin::list_users)))
+                .service(web::resource("/users/{user_id}").route(web::delete().to(admin::delete_user)))
+                .service(web::resource("/users/{user_id}/disable").route(web::post().to(admin::disable_user)))
+                .service(web::resource("/users/{user_id}/enable").route(web::post().to(admin::enable_user)))
+                .service(
+                    web::resource("/users/{user_id}/force_password_reset")
+                        .route(web::post().to(admin::force_password_reset)),
+                )
+                .service(web::resource("/audit_log").route(web::get().to(admin::get_audit_log))),
+        )
+        .service(web::resource("/add_task").route(web::post().to(add_task)))
+        .service(web::resource("/update_task_status").route(web::post().to(update_task_status)))
+        .service(web::resource("/delete_task/{task_id}").route(web::delete().to(delete_task)))
+        .service(web::resource("/get_tasks").route(web::get().to(get_tasks)))
+        .service(web::resource("/update_task_note").route(web::post().to(update_task_note)))
+        .service(web::resource("/add_subject").route(web::post().to(add_subject)))
+        .service(web::resource("/delete_subject/{subject_id}").route(web::delete().to(delete_subject)))
+        .service(web::resource("/subjects/{subject_id}").route(web::patch().to(update_subject)))
+        .service(
+            web::resource("/subjects/{subject_id}/files")
+                .route(web::get().to(uploads::get_files))
+                .route(web::post().to(uploads::upload_files)),
+        )
+        .service(
+            web::resource("/files/{file_id}")
+                .route(web::get().to(uploads::download_file))
+                .route(web::delete().to(uploads::delete_file)),
+        )
+        .service(web::resource("/get_subjects").route(web::get().to(get_subjects)))
+        .service(web::resource("/get_exam_dates/{subject_id}").route(web::get().to(get_exam_dates)))
+        .service(web::resource("/get_notes/{subject_id}").route(web::get().to(get_notes)))
+        .service(web::resource("/get_file_links/{subject_id}").route(web::get().to(get_file_links)))
+        .service(web::resource("/add_exam_date").route(web::post().to(add_exam_date)))
+        .service(
+            web::resource("/exam_dates/{exam_date_id}")
+                .route(web::patch().to(update_exam_date))
+                .route(web::delete().to(delete_exam_date)),
+        )
+        .service(web::resource("/add_note").route(web::post().to(add_note)))
+        .service(web::resource("/add_file_link").route(web::post().to(add_file_link)))
+        .service(web::resource("/delete_note/{note_id}").route(web::delete().to(delete_note)))
+        .service(web::resource("/delete_file_link/{file_link_id}").route(web::delete().to(delete_file_link)))
+        .service(web::resource("/file_links/{file_link_id}").route(web::patch().to(update_file_link)))
+        .service(web::resource("/notes/{note_id}").route(web::patch().to(update_note)))
+        .service(web::resource("/notes/{note_id}/revisions").route(web::get().to(get_note_revisions)))
+        .service(
+            web::resource("/notes/{note_id}/revisions/{revision_id}/diff").route(web::get().to(diff_note_revision)),
+        )
+        .service(
+            web::resource("/notes/{note_id}/revisions/{revision_id}/restore")
+                .route(web::post().to(restore_note_revision)),
+        );
 }
 
 // Main function
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let db_path = "classmate.db";
-    let db_conn = Arc::new(Mutex::new(Connection::open(db_path).expect("Failed to connect to database.")));
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Invalid configuration: {}", error);
+            std::process::exit(1);
+        }
+    };
+    env_logger::Builder::new().parse_filters(&config.log_level).init();
 
-    // Create necessary tables if they don't exist
-    {
-        let mut conn = db_conn.lock().unwrap();
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS users (
-                 id INTEGER PRIMARY KEY,
-                 username TEXT NOT NULL UNIQUE,
-                 password_hash TEXT NOT NULL
-             )",
-            [],
-        )
-        .expect("Failed to create users table.");
+    std::fs::create_dir_all(&config.upload_dir).map_err(|error| {
+        std::io::Error::new(error.kind(), format!("Cannot create {}: {}", config.upload_dir.display(), error))
+    })?;
+    let jwt_keys = web::Data::new(JwtKeys::from_secret(config.jwt_secret.as_bytes()));
+    let mailer = web::Data::from(mail::transport_from_config(&config.mail).expect("Failed to set up the mail transport."));
+    let blob_store = web::Data::from(blobs::store_from_dir(&config.upload_dir)?);
+    let (repos, accounts) = match config.database_url.as_deref() {
+        #[cfg(feature = "postgres")]
+        Some(url) => repository::open_postgres(url).await.expect("Failed to connect to DATABASE_URL."),
+        _ => {
+            let pool = db::open_pool(&config.database_path).expect("Failed to connect to database.");
+            let conn = pool.get().expect("Failed to connect to database.");
+            migrations::run(&conn).map_err(std::io::Error::other)?;
+            let orphans = migrations::remove_orphans(&conn).expect("Failed to check database integrity.");
+            for (table, count) in orphans {
+                log::warn!("Removed {} orphaned rows from {}", count, table);
+            }
+            (Repositories::sqlite(pool.clone()), Accounts::sqlite(pool))
+        }
+    };
+    if let Some(username) = &config.admin_username {
+        accounts.users.promote(username.trim().to_string()).await.expect("Failed to promote ADMIN_USERNAME.");
     }
+    let repos = web::Data::new(repos);
+    let accounts = web::Data::new(accounts);
+    let config = web::Data::new(config);
 
-    {
-        let mut conn = db_conn.lock().unwrap();
+    // Start the server
+    let app_config = config.clone();
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .wrap(RequestId)
+            .wrap(app_config.cors())
+            .wrap(Logger::new(r#"%a "%r" %s %b %T %{x-request-id}o"#))
+            .app_data(app_config.clone())
+            .app_data(repos.clone())
+            .app_data(accounts.clone())
+            .app_data(jwt_keys.clone())
+            .app_data(mailer.clone())
+            .app_data(blob_store.clone())
+            .configure(configure_routes)
+    });
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    server.bind(&config.bind_address)?.run().await
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::blobs::store_from_dir;
+    use crate::mail::{FileTransport, MailTransport};
+    use crate::sessions::generate_token;
+    use crate::sessions::tests::login_token;
+    use actix_web::{http::StatusCode, test};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    // What both fixture users sign in with
+    pub const PASSWORD: &str = "secreto123";
+
+    pub struct Fixture {
+        pub pool: db::DbPool,
+        pub keys: web::Data<JwtKeys>,
+        // Holds the uploads and outbox directories, removed when the fixture drops
+        pub dir: PathBuf,
+        pub blobs: web::Data<dyn BlobStore>,
+        pub mailer: web::Data<dyn MailTransport>,
+        // Replace it before building the app to test other limits
+        pub config: web::Data<Config>,
+        pub alice_token: String,
+        pub bob_token: String,
+        pub task_id: i32,
+        pub subject_id: i32,
+        pub note_id: i32,
+        pub exam_date_id: i32,
+        pub file_link_id: i32,
+    }
+
+    // Two users, where every row belongs to alice
+    pub fn fixture() -> Fixture {
+        let pool = crate::db::test_pool();
+        let conn = pool.get().unwrap();
+        let password_hash = bcrypt::hash(PASSWORD, 4).unwrap();
+        conn.execute("INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', ?1)", [&password_hash])
+            .unwrap();
+        conn.execute("INSERT INTO users (id, username, password_hash) VALUES (2, 'bob', ?1)", [&password_hash])
+            .unwrap();
         conn.execute(
-            "ALTER TABLE tasks ADD COLUMN user_id INTEGER",
+            "INSERT INTO tasks (id, title, status, note, user_id) VALUES (10, 'TP 1', 'Pendiente', '', 1)",
             [],
         )
-        .ok(); // Ignore error if column already exists
+        .unwrap();
+        conn.execute("INSERT INTO subjects (id, name, user_id) VALUES (20, 'Algebra', 1)", [])
+            .unwrap();
+        conn.execute("INSERT INTO notes (id, subject_id, content) VALUES (30, 20, 'Apuntes')", [])
+            .unwrap();
+        conn.execute("INSERT INTO exam_dates (id, subject_id, date) VALUES (40, 20, '2024-07-01T09:00:00')", [])
+            .unwrap();
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS tasks (
-                 id INTEGER PRIMARY KEY,
-                 title TEXT NOT NULL,
-                 status TEXT NOT NULL,
-                 note TEXT,
-                 user_id INTEGER NOT NULL,
-                 FOREIGN KEY (user_id) REFERENCES users(id)
-             )",
+            "INSERT INTO file_links (id, subject_id, url) VALUES (50, 20, 'https://example.com')",
             [],
         )
-        .expect("Failed to create tasks table.");
+        .unwrap();
+
+        let keys = web::Data::new(JwtKeys::from_secret(b"test-secret"));
+        let dir = std::env::temp_dir().join(format!("classmate-test-{}", generate_token()));
+        let mailer: Arc<dyn MailTransport> =
+            Arc::new(FileTransport::new(dir.join("outbox"), "ClassMate <no-reply@classmate.local>").unwrap());
+        Fixture {
+            alice_token: login_token(&conn, &keys, 1),
+            bob_token: login_token(&conn, &keys, 2),
+            pool: pool.clone(),
+            keys,
+            blobs: web::Data::from(store_from_dir(&dir.join("uploads")).unwrap()),
+            mailer: web::Data::from(mailer),
+            config: web::Data::new(Config {
+                bcrypt_cost: 4,
+                ..Config::default()
+            }),
+            dir,
+            task_id: 10,
+            subject_id: 20,
+            note_id: 30,
+            exam_date_id: 40,
+            file_link_id: 50,
+        }
     }
 
-    {
-        let mut conn = db_conn.lock().unwrap();
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    pub fn count(pool: &db::DbPool, table: &str) -> i64 {
+        let conn = pool.get().unwrap();
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    // The routes as the server wires them, over the fixture's database
+    macro_rules! app {
+        ($fixture:expr) => {
+            actix_web::test::init_service(
+                actix_web::App::new()
+                    .wrap(crate::error::RequestId)
+                    .app_data(actix_web::web::Data::new(crate::repository::Repositories::sqlite(
+                        $fixture.pool.clone(),
+                    )))
+                    .app_data(actix_web::web::Data::new(crate::repository::Accounts::sqlite($fixture.pool.clone())))
+                    .app_data($fixture.keys.clone())
+                    .app_data($fixture.blobs.clone())
+                    .app_data($fixture.mailer.clone())
+                    .app_data($fixture.config.clone())
+                    .configure(crate::configure_routes),
+            )
+            .await
+        };
+    }
+    pub(crate) use app;
+
+    pub fn bearer(token: &str) -> (&'static str, String) {
+        ("Authorization", format!("Bearer {}", token))
+    }
+
+    // Real files, since several connections write at once the way they do in production
+    #[actix_web::test]
+    async fn parallel_bad_logins_are_throttled() {
+        let dir = std::env::temp_dir().join(format!("classmate-login-{}", generate_token()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pool = db::open_pool(dir.join("classmate.db").to_str().unwrap()).unwrap();
+        let conn = pool.get().unwrap();
+        migrations::run(&conn).unwrap();
         conn.execute(
-            "ALTER TABLE subjects ADD COLUMN user_id INTEGER",
-            [],
+            "INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', ?1)",
+            [bcrypt::hash("secreto123", 4).unwrap()],
         )
-        .ok(); // Ignore error if column already exists
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS subjects (
-                 id INTEGER PRIMARY KEY,
-                 name TEXT NOT NULL,
-                 user_id INTEGER NOT NULL,
-                 FOREIGN KEY (user_id) REFERENCES users(id)
-             )",
-            [],
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Repositories::sqlite(pool.clone())))
+                .app_data(web::Data::new(Accounts::sqlite(pool.clone())))
+                .app_data(web::Data::new(JwtKeys::from_secret(b"test-secret")))
+                .configure(configure_routes),
         )
-        .expect("Failed to create subjects table.");
+        .await;
+
+        let attempts = (0..12).map(|_| {
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .set_json(serde_json::json!({ "username": "alice", "password": "adivinanza" }))
+                .to_request();
+            test::call_service(&app, req)
+        });
+        let statuses: Vec<_> = futures_util::future::join_all(attempts)
+            .await
+            .iter()
+            .map(|resp| resp.status())
+            .collect();
+        let guessed = statuses.iter().filter(|status| **status == StatusCode::UNAUTHORIZED).count();
+        assert!(guessed <= 4, "{:?}", statuses);
+        assert!(statuses.contains(&StatusCode::TOO_MANY_REQUESTS), "{:?}", statuses);
+
+        drop(conn);
+        drop(pool);
+        std::fs::remove_dir_all(dir).ok();
     }
 
-    {
-        let mut conn = db_conn.lock().unwrap();
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS exam_dates (
-                 id INTEGER PRIMARY KEY,
-                 subject_id INTEGER NOT NULL,
-                 date TEXT NOT NULL,
-                 FOREIGN KEY (subject_id) REFERENCES subjects(id)
-             )",
-            [],
-        )
-        .expect("Failed to create exam_dates table.");
+    #[actix_web::test]
+    async fn owner_can_patch_subject() {
+        let f = fixture();
+        let app = app!(f);
+        let patch = |token: &str, body: serde_json::Value| {
+            test::TestRequest::patch()
+                .uri(&format!("/subjects/{}", f.subject_id))
+                .insert_header(bearer(token))
+                .set_json(body)
+                .to_request()
+        };
+
+        let req = patch(&f.bob_token, serde_json::json!({ "name": "Hackeada" }));
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = patch(&f.alice_token, serde_json::json!({ "name": " ", "color": "red" }));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let req = patch(
+            &f.alice_token,
+            serde_json::json!({ "name": " Álgebra II ", "color": "#3366ff", "professor": "Gómez", "archived": true }),
+        );
+        let subject: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(subject["name"], "Álgebra II");
+        assert_eq!(subject["color"], "#3366ff");
+
+        // Left out fields stay, null clears; notes survive a rename
+        let req = patch(&f.alice_token, serde_json::json!({ "professor": null }));
+        let subject: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(subject["color"], "#3366ff");
+        assert!(subject["professor"].is_null());
+        assert_eq!(count(&f.pool, "notes"), 1);
+
+        let list = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/get_subjects{}", query))
+                .insert_header(bearer(&f.alice_token))
+                .to_request()
+        };
+        let subjects: Vec<serde_json::Value> = test::call_and_read_body_json(&app, list("")).await;
+        assert!(subjects.is_empty());
+        let subjects: Vec<serde_json::Value> = test::call_and_read_body_json(&app, list("?include_archived=true")).await;
+        assert_eq!(subjects.len(), 1);
     }
 
-    {
-        let mut conn = db_conn.lock().unwrap();
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS notes (
-                 id INTEGER PRIMARY KEY,
-                 subject_id INTEGER NOT NULL,
-                 content TEXT NOT NULL,
-                 FOREIGN KEY (subject_id) REFERENCES subjects(id)
-             )",
-            [],
-        )
-        .expect("Failed to create notes table.");
+    #[actix_web::test]
+    async fn owner_can_edit_and_delete_exam_dates() {
+        let f = fixture();
+        let app = app!(f);
+        let uri = format!("/exam_dates/{}", f.exam_date_id);
+        let patch = |token: &str, body: serde_json::Value| {
+            test::TestRequest::patch().uri(&uri).insert_header(bearer(token)).set_json(body).to_request()
+        };
+
+        let req = patch(&f.bob_token, serde_json::json!({ "date": "2024-07-02" }));
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::delete().uri(&uri).insert_header(bearer(&f.bob_token)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = patch(&f.alice_token, serde_json::json!({ "date": "mañana", "kind": "oral", "weight": 120 }));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let req = patch(
+            &f.alice_token,
+            serde_json::json!({ "date": "2024-12-10T14:00", "kind": "final", "location": " Aula 3 ", "duration_minutes": 120 }),
+        );
+        let exam_date: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(exam_date["date"], "2024-12-10T14:00:00");
+        assert_eq!(exam_date["kind"], "final");
+        assert_eq!(exam_date["location"], "Aula 3");
+
+        // Dates are listed soonest first, whatever order they were added in
+        let req = test::TestRequest::post()
+            .uri("/add_exam_date")
+            .insert_header(bearer(&f.alice_token))
+            .set_json(serde_json::json!({ "subject_id": f.subject_id, "date": "2024-09-01", "kind": "tp_deadline" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        let req = test::TestRequest::get()
+            .uri(&format!("/get_exam_dates/{}", f.subject_id))
+            .insert_header(bearer(&f.alice_token))
+            .to_request();
+        let exam_dates: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(exam_dates[0]["date"], "2024-09-01T00:00:00");
+        assert_eq!(exam_dates[1]["id"], f.exam_date_id);
+
+        let req = test::TestRequest::delete().uri(&uri).insert_header(bearer(&f.alice_token)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(count(&f.pool, "exam_dates"), 1);
     }
 
-    {
-        let mut conn = db_conn.lock().unwrap();
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS file_links (
-                 id INTEGER PRIMARY KEY,
-                 subject_id INTEGER NOT NULL,
-                 url TEXT NOT NULL,
-                 FOREIGN KEY (subject_id) REFERENCES subjects(id)
-             )",
-            [],
-        )
-        .expect("Failed to create file_links table.");
+    #[actix_web::test]
+    async fn note_edits_keep_revisions_that_can_be_restored() {
+        let f = fixture();
+        let app = app!(f);
+        let uri = format!("/notes/{}", f.note_id);
+        let patch = |token: &str, body: serde_json::Value| {
+            test::TestRequest::patch().uri(&uri).insert_header(bearer(token)).set_json(body).to_request()
+        };
+        let get = |uri: String, token: &str| test::TestRequest::get().uri(&uri).insert_header(bearer(token)).to_request();
+
+        let req = patch(&f.bob_token, serde_json::json!({ "content": "borrado" }));
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = get(format!("{}/revisions", uri), &f.bob_token);
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = patch(&f.alice_token, serde_json::json!({ "title": "Clase 1", "content": "Apuntes\nLímites" }));
+        let note: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(note["title"], "Clase 1");
+
+        let revisions: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, get(format!("{}/revisions", uri), &f.alice_token)).await;
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0]["content"], "Apuntes");
+        let revision_uri = format!("{}/revisions/{}", uri, revisions[0]["id"]);
+
+        let diff: serde_json::Value =
+            test::call_and_read_body_json(&app, get(format!("{}/diff", revision_uri), &f.alice_token)).await;
+        assert!(diff["title_before"].is_null());
+        assert_eq!(
+            diff["lines"],
+            serde_json::json!([
+                { "op": "equal", "text": "Apuntes" },
+                { "op": "insert", "text": "Límites" },
+            ])
+        );
+
+        // Restoring is itself an edit, so the overwritten text stays recoverable
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/restore", revision_uri))
+            .insert_header(bearer(&f.alice_token))
+            .to_request();
+        let note: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(note["content"], "Apuntes");
+        assert!(note["title"].is_null());
+        assert_eq!(count(&f.pool, "note_revisions"), 2);
+
+        let req = get(format!("{}/revisions/999/diff", uri), &f.alice_token);
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
     }
 
-    // Start the server
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .max_age(3600);
-    
-        App::new()
-            .wrap(cors)
-            .app_data(web::Data::new(db_conn.clone()))
-            .service(web::resource("/register").route(web::post().to(register)))
-            .service(web::resource("/login").route(web::post().to(login)))
-            .service(web::resource("/add_task").route(web::post().to(add_task)))
-            .service(web::resource("/update_task_status").route(web::post().to(update_task_status)))
-            .service(web::resource("/delete_task/{task_id}").route(web::delete().to(delete_task)))
-            .service(web::resource("/get_tasks/{user_id}").route(web::get().to(get_tasks)))
-            .service(web::resource("/update_task_note").route(web::post().to(update_task_note)))
-            .service(web::resource("/add_subject").route(web::post().to(add_subject)))
-            .service(web::resource("/delete_subject/{subject_id}").route(web::delete().to(delete_subject)))
-            .service(web::resource("/get_subjects/{user_id}").route(web::get().to(get_subjects)))
-            .service(web::resource("/get_exam_dates/{subject_id}").route(web::get().to(get_exam_dates)))
-            .service(web::resource("/get_notes/{subject_id}").route(web::get().to(get_notes)))
-            .service(web::resource("/get_file_links/{subject_id}").route(web::get().to(get_file_links)))
-            .service(web::resource("/add_exam_date").route(web::post().to(add_exam_date)))
-            .service(web::resource("/add_note").route(web::post().to(add_note)))
-            .service(web::resource("/add_file_link").route(web::post().to(add_file_link)))
-            .service(web::resource("/delete_note/{note_id}").route(web::delete().to(delete_note))) // Nueva ruta
-    })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
-    
+    #[actix_web::test]
+    async fn owner_can_edit_delete_and_sort_file_links() {
+        let f = fixture();
+        let app = app!(f);
+        let add = |body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/add_file_link")
+                .insert_header(bearer(&f.alice_token))
+                .set_json(body)
+                .to_request()
+        };
+
+        let req = add(serde_json::json!({ "subject_id": f.subject_id, "url": "javascript:alert(1)", "kind": "mp3" }));
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["errors"][0]["code"], "url_invalid");
+        assert_eq!(body["errors"][1]["code"], "file_link_kind_invalid");
+
+        let req = add(
+            serde_json::json!({ "subject_id": f.subject_id, "url": "https://a.example/tp", "label": "TP 1", "kind": "pdf" }),
+        );
+        let added: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(added["kind"], "pdf");
+
+        let uri = format!("/file_links/{}", f.file_link_id);
+        let patch = |token: &str, body: serde_json::Value| {
+            test::TestRequest::patch().uri(&uri).insert_header(bearer(token)).set_json(body).to_request()
+        };
+        let req = patch(&f.bob_token, serde_json::json!({ "label": "spam" }));
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = patch(&f.alice_token, serde_json::json!({ "url": "file:///etc/passwd" }));
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let req = patch(&f.alice_token, serde_json::json!({ "label": " Apuntes ", "kind": "slides" }));
+        let updated: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(updated["label"], "Apuntes");
+        assert_eq!(updated["url"], "https://example.com");
+
+        let list = |query: &str| {
+            test::TestRequest::get()
+                .uri(&format!("/get_file_links/{}{}", f.subject_id, query))
+                .insert_header(bearer(&f.alice_token))
+                .to_request()
+        };
+        let labels = |links: Vec<serde_json::Value>| links.iter().map(|link| link["label"].clone()).collect::<Vec<_>>();
+        let links: Vec<serde_json::Value> = test::call_and_read_body_json(&app, list("")).await;
+        assert_eq!(labels(links), ["Apuntes", "TP 1"]);
+        let links: Vec<serde_json::Value> = test::call_and_read_body_json(&app, list("?sort=label&order=desc")).await;
+        assert_eq!(labels(links), ["TP 1", "Apuntes"]);
+        let links: Vec<serde_json::Value> = test::call_and_read_body_json(&app, list("?sort=kind")).await;
+        assert_eq!(labels(links), ["TP 1", "Apuntes"]);
+        assert_eq!(test::call_service(&app, list("?sort=size")).await.status(), StatusCode::BAD_REQUEST);
+
+        let delete = |token: &str| {
+            test::TestRequest::delete()
+                .uri(&format!("/delete_file_link/{}", f.file_link_id))
+                .insert_header(bearer(token))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, delete(&f.bob_token)).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(test::call_service(&app, delete(&f.alice_token)).await.status(), StatusCode::OK);
+        assert_eq!(count(&f.pool, "file_links"), 1);
+    }
 }
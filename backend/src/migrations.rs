@@ -0,0 +1,733 @@
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::fmt;
+
+use crate::auth::now_secs;
+
+// A numbered schema change; applied at most once, in order, inside its own transaction
+struct Migration {
+    version: i64,
+    name: &'static str,
+    up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+// Append new migrations at the end and never edit one that has shipped
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        up: baseline,
+    },
+    Migration {
+        version: 2,
+        name: "assign_legacy_rows",
+        up: assign_legacy_rows,
+    },
+    Migration {
+        version: 3,
+        name: "cascade_foreign_keys",
+        up: cascade_foreign_keys,
+    },
+    Migration {
+        version: 4,
+        name: "user_language",
+        up: user_language,
+    },
+    Migration {
+        version: 5,
+        name: "subject_details",
+        up: subject_details,
+    },
+    Migration {
+        version: 6,
+        name: "exam_details",
+        up: exam_details,
+    },
+    Migration {
+        version: 7,
+        name: "note_history",
+        up: note_history,
+    },
+    Migration {
+        version: 8,
+        name: "file_link_details",
+        up: file_link_details,
+    },
+    Migration {
+        version: 9,
+        name: "stored_files",
+        up: stored_files,
+    },
+    Migration {
+        version: 10,
+        name: "totp_last_step",
+        up: totp_last_step,
+    },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(rusqlite::Error),
+    // The database was migrated by a newer build than this one
+    NewerSchema { found: i64, supported: i64 },
+    Failed { version: i64, name: &'static str, error: rusqlite::Error },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Sqlite(error) => write!(f, "could not read the schema version: {}", error),
+            MigrationError::NewerSchema { found, supported } => write!(
+                f,
+                "the database is at schema version {} but this build only knows up to {}; refusing to start",
+                found, supported
+            ),
+            MigrationError::Failed { version, name, error } => {
+                write!(f, "migration {} ({}) failed: {}", version, name, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(error: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(error)
+    }
+}
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|migration| migration.version).unwrap_or(0)
+}
+
+// Bring the database up to the latest schema version and return that version.
+// Foreign keys are enforced on the connection afterwards.
+pub fn run(conn: &Connection) -> Result<i64, MigrationError> {
+    // Rebuilding a table would trip its own constraints, and the pragma is ignored inside a transaction
+    conn.execute_batch("PRAGMA foreign_keys = OFF")?;
+    let result = migrate(conn);
+    conn.execute_batch("PRAGMA foreign_keys = ON")?;
+    result
+}
+
+fn migrate(conn: &Connection) -> Result<i64, MigrationError> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+             version INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             applied_at INTEGER NOT NULL
+         )",
+        [],
+    )?;
+
+    let current = current_version(conn)?;
+    if current > latest_version() {
+        return Err(MigrationError::NewerSchema {
+            found: current,
+            supported: latest_version(),
+        });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        apply(conn, migration).map_err(|error| MigrationError::Failed {
+            version: migration.version,
+            name: migration.name,
+            error,
+        })?;
+    }
+    Ok(latest_version())
+}
+
+pub fn current_version(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get::<_, Option<i64>>(0))
+        .map(|version| version.unwrap_or(0))
+}
+
+// Delete rows whose parent no longer exists, which older builds could leave behind because
+// foreign keys were never enforced. Returns how many rows were removed from each table.
+pub fn remove_orphans(conn: &Connection) -> rusqlite::Result<Vec<(String, usize)>> {
+    let mut removed: Vec<(String, usize)> = Vec::new();
+    let tx = conn.unchecked_transaction()?;
+    // Removing an orphaned subject cascades to its notes, so a single pass is enough
+    let orphans = {
+        let mut stmt = tx.prepare("PRAGMA foreign_key_check")?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    for (table, rowid) in orphans {
+        let deleted = tx.execute(&format!("DELETE FROM {} WHERE rowid = ?1", table), [rowid])?;
+        match removed.iter_mut().find(|(name, _)| *name == table) {
+            Some((_, count)) => *count += deleted,
+            None => removed.push((table, deleted)),
+        }
+    }
+    tx.commit()?;
+    Ok(removed)
+}
+
+fn apply(conn: &Connection, migration: &Migration) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    (migration.up)(&tx)?;
+    tx.execute(
+        "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, ?2, ?3)",
+        params![migration.version, migration.name, now_secs()],
+    )?;
+    tx.commit()
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut names = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    names.try_fold(false, |found, name| Ok(found || name? == column))
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    if !has_column(conn, table, column)? {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+    Ok(())
+}
+
+// Usernames are unique regardless of case. Older builds did not enforce that, so a collision stops
+// the upgrade naming the accounts to fix instead of leaving the database without the index.
+fn username_nocase_index(tx: &Transaction) -> rusqlite::Result<()> {
+    let collisions = {
+        let mut stmt = tx.prepare(
+            "SELECT group_concat(username, ', ') FROM users GROUP BY username COLLATE NOCASE HAVING COUNT(*) > 1",
+        )?;
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+        rows.collect::<rusqlite::Result<Vec<_>>>()?
+    };
+    if !collisions.is_empty() {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE),
+            Some(format!(
+                "usernames that only differ in case have to be renamed before upgrading: {}",
+                collisions.join("; ")
+            )),
+        ));
+    }
+    tx.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS users_username_nocase ON users (username COLLATE NOCASE)",
+        [],
+    )?;
+    Ok(())
+}
+
+// Migrations
+
+// Everything the schema had before it was versioned. Databases created by older builds
+// already have some of these tables, possibly without the later columns, so every step
+// only adds what is missing.
+fn baseline(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS users (
+             id INTEGER PRIMARY KEY,
+             username TEXT NOT NULL UNIQUE,
+             password_hash TEXT NOT NULL
+         )",
+        [],
+    )?;
+    add_column_if_missing(tx, "users", "email", "TEXT")?;
+    add_column_if_missing(tx, "users", "totp_secret", "TEXT")?;
+    add_column_if_missing(tx, "users", "totp_enabled", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(tx, "users", "role", "TEXT NOT NULL DEFAULT 'user'")?;
+    add_column_if_missing(tx, "users", "disabled", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(tx, "users", "must_reset_password", "INTEGER NOT NULL DEFAULT 0")?;
+
+    username_nocase_index(tx)?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS tasks (
+             id INTEGER PRIMARY KEY,
+             title TEXT NOT NULL,
+             status TEXT NOT NULL,
+             note TEXT,
+             user_id INTEGER NOT NULL,
+             FOREIGN KEY (user_id) REFERENCES users(id)
+         )",
+        [],
+    )?;
+    add_column_if_missing(tx, "tasks", "user_id", "INTEGER")?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS subjects (
+             id INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             user_id INTEGER NOT NULL,
+             FOREIGN KEY (user_id) REFERENCES users(id)
+         )",
+        [],
+    )?;
+    add_column_if_missing(tx, "subjects", "user_id", "INTEGER")?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS exam_dates (
+             id INTEGER PRIMARY KEY,
+             subject_id INTEGER NOT NULL,
+             date TEXT NOT NULL,
+             FOREIGN KEY (subject_id) REFERENCES subjects(id)
+         )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS notes (
+             id INTEGER PRIMARY KEY,
+             subject_id INTEGER NOT NULL,
+             content TEXT NOT NULL,
+             FOREIGN KEY (subject_id) REFERENCES subjects(id)
+         )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS file_links (
+             id INTEGER PRIMARY KEY,
+             subject_id INTEGER NOT NULL,
+             url TEXT NOT NULL,
+             FOREIGN KEY (subject_id) REFERENCES subjects(id)
+         )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS sessions (
+             id INTEGER PRIMARY KEY,
+             user_id INTEGER NOT NULL,
+             refresh_token_hash TEXT NOT NULL UNIQUE,
+             device TEXT,
+             created_at INTEGER NOT NULL,
+             last_seen_at INTEGER NOT NULL,
+             expires_at INTEGER NOT NULL,
+             revoked_at INTEGER,
+             FOREIGN KEY (user_id) REFERENCES users(id)
+         )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS login_attempts (
+             key TEXT PRIMARY KEY,
+             attempts INTEGER NOT NULL,
+             last_attempt_at INTEGER NOT NULL,
+             blocked_until INTEGER NOT NULL
+         )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS recovery_codes (
+             id INTEGER PRIMARY KEY,
+             user_id INTEGER NOT NULL,
+             code_hash TEXT NOT NULL,
+             used_at INTEGER,
+             FOREIGN KEY (user_id) REFERENCES users(id)
+         )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS password_reset_tokens (
+             id INTEGER PRIMARY KEY,
+             user_id INTEGER NOT NULL,
+             token_hash TEXT NOT NULL UNIQUE,
+             created_at INTEGER NOT NULL,
+             expires_at INTEGER NOT NULL,
+             used_at INTEGER,
+             FOREIGN KEY (user_id) REFERENCES users(id)
+         )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS audit_log (
+             id INTEGER PRIMARY KEY,
+             admin_id INTEGER NOT NULL,
+             action TEXT NOT NULL,
+             target_user_id INTEGER,
+             details TEXT,
+             created_at INTEGER NOT NULL
+         )",
+        [],
+    )?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS api_tokens (
+             id INTEGER PRIMARY KEY,
+             user_id INTEGER NOT NULL,
+             name TEXT NOT NULL,
+             token_hash TEXT NOT NULL UNIQUE,
+             access TEXT NOT NULL,
+             area TEXT NOT NULL,
+             created_at INTEGER NOT NULL,
+             last_used_at INTEGER,
+             revoked_at INTEGER,
+             FOREIGN KEY (user_id) REFERENCES users(id)
+         )",
+        [],
+    )?;
+    Ok(())
+}
+
+// Tasks and subjects created before they had an owner have a NULL user_id and nobody can reach them.
+// If the database only ever had one user they are theirs; otherwise the owner cannot be known
+// and the rows are dropped together with what hangs from those subjects.
+fn assign_legacy_rows(tx: &Transaction) -> rusqlite::Result<()> {
+    let sole_user: Option<i32> = tx
+        .query_row(
+            "SELECT id FROM users WHERE (SELECT COUNT(*) FROM users) = 1",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    match sole_user {
+        Some(user_id) => {
+            tx.execute("UPDATE tasks SET user_id = ?1 WHERE user_id IS NULL", [user_id])?;
+            tx.execute("UPDATE subjects SET user_id = ?1 WHERE user_id IS NULL", [user_id])?;
+        }
+        None => {
+            for table in ["notes", "exam_dates", "file_links"] {
+                tx.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE subject_id IN (SELECT id FROM subjects WHERE user_id IS NULL)",
+                        table
+                    ),
+                    [],
+                )?;
+            }
+            tx.execute("DELETE FROM subjects WHERE user_id IS NULL", [])?;
+            tx.execute("DELETE FROM tasks WHERE user_id IS NULL", [])?;
+        }
+    }
+    Ok(())
+}
+
+// Rebuild every owned table so deleting a user or a subject takes its rows with it.
+// SQLite cannot alter a constraint in place, so each table is copied into a new definition.
+fn cascade_foreign_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    let tables = [
+        (
+            "tasks",
+            "id INTEGER PRIMARY KEY,
+             title TEXT NOT NULL,
+             status TEXT NOT NULL,
+             note TEXT,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE",
+            "id, title, status, note, user_id",
+        ),
+        (
+            "subjects",
+            "id INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE",
+            "id, name, user_id",
+        ),
+        (
+            "exam_dates",
+            "id INTEGER PRIMARY KEY,
+             subject_id INTEGER NOT NULL REFERENCES subjects(id) ON DELETE CASCADE,
+             date TEXT NOT NULL",
+            "id, subject_id, date",
+        ),
+        (
+            "notes",
+            "id INTEGER PRIMARY KEY,
+             subject_id INTEGER NOT NULL REFERENCES subjects(id) ON DELETE CASCADE,
+             content TEXT NOT NULL",
+            "id, subject_id, content",
+        ),
+        (
+            "file_links",
+            "id INTEGER PRIMARY KEY,
+             subject_id INTEGER NOT NULL REFERENCES subjects(id) ON DELETE CASCADE,
+             url TEXT NOT NULL",
+            "id, subject_id, url",
+        ),
+        (
+            "sessions",
+            "id INTEGER PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             refresh_token_hash TEXT NOT NULL UNIQUE,
+             device TEXT,
+             created_at INTEGER NOT NULL,
+             last_seen_at INTEGER NOT NULL,
+             expires_at INTEGER NOT NULL,
+             revoked_at INTEGER",
+            "id, user_id, refresh_token_hash, device, created_at, last_seen_at, expires_at, revoked_at",
+        ),
+        (
+            "recovery_codes",
+            "id INTEGER PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             code_hash TEXT NOT NULL,
+             used_at INTEGER",
+            "id, user_id, code_hash, used_at",
+        ),
+        (
+            "password_reset_tokens",
+            "id INTEGER PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             token_hash TEXT NOT NULL UNIQUE,
+             created_at INTEGER NOT NULL,
+             expires_at INTEGER NOT NULL,
+             used_at INTEGER",
+            "id, user_id, token_hash, created_at, expires_at, used_at",
+        ),
+        (
+            "api_tokens",
+            "id INTEGER PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             name TEXT NOT NULL,
+             token_hash TEXT NOT NULL UNIQUE,
+             access TEXT NOT NULL,
+             area TEXT NOT NULL,
+             created_at INTEGER NOT NULL,
+             last_used_at INTEGER,
+             revoked_at INTEGER",
+            "id, user_id, name, token_hash, access, area, created_at, last_used_at, revoked_at",
+        ),
+    ];
+
+    for (table, definition, columns) in tables {
+        tx.execute_batch(&format!(
+            "CREATE TABLE {table}_new ({definition});
+             INSERT INTO {table}_new ({columns}) SELECT {columns} FROM {table};
+             DROP TABLE {table};
+             ALTER TABLE {table}_new RENAME TO {table};",
+            table = table,
+            definition = definition,
+            columns = columns,
+        ))?;
+    }
+
+    // Lookups by owner, which every list and cascade does
+    tx.execute_batch(
+        "CREATE INDEX tasks_user_id ON tasks (user_id);
+         CREATE INDEX subjects_user_id ON subjects (user_id);
+         CREATE INDEX exam_dates_subject_id ON exam_dates (subject_id);
+         CREATE INDEX notes_subject_id ON notes (subject_id);
+         CREATE INDEX file_links_subject_id ON file_links (subject_id);
+         CREATE INDEX sessions_user_id ON sessions (user_id);
+         CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
+         CREATE INDEX password_reset_tokens_user_id ON password_reset_tokens (user_id);
+         CREATE INDEX api_tokens_user_id ON api_tokens (user_id);",
+    )
+}
+
+// Language the user picked for API messages; NULL follows Accept-Language
+fn user_language(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute("ALTER TABLE users ADD COLUMN language TEXT", [])?;
+    Ok(())
+}
+
+// What the subject list shows besides the name, and archiving instead of deleting
+fn subject_details(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE subjects ADD COLUMN color TEXT;
+         ALTER TABLE subjects ADD COLUMN icon TEXT;
+         ALTER TABLE subjects ADD COLUMN display_order INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE subjects ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE subjects ADD COLUMN professor TEXT;
+         ALTER TABLE subjects ADD COLUMN course_code TEXT;",
+    )
+}
+
+// Exams get a time, a kind and the rest of what the calendar shows; bare dates become midnight
+fn exam_details(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE exam_dates ADD COLUMN duration_minutes INTEGER;
+         ALTER TABLE exam_dates ADD COLUMN kind TEXT NOT NULL DEFAULT 'parcial';
+         ALTER TABLE exam_dates ADD COLUMN location TEXT;
+         ALTER TABLE exam_dates ADD COLUMN notes TEXT;
+         ALTER TABLE exam_dates ADD COLUMN weight REAL;
+         UPDATE exam_dates SET date = date || 'T00:00:00'
+         WHERE date GLOB '[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9]';",
+    )
+}
+
+// Titles and timestamps for notes, and every version an edit replaces.
+// Notes written before this have no known date, so they start at the upgrade.
+fn note_history(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE notes ADD COLUMN title TEXT;
+         ALTER TABLE notes ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+         ALTER TABLE notes ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+         CREATE TABLE note_revisions (
+             id INTEGER PRIMARY KEY,
+             note_id INTEGER NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+             title TEXT,
+             content TEXT NOT NULL,
+             created_at INTEGER NOT NULL
+         );
+         CREATE INDEX note_revisions_note_id ON note_revisions (note_id);",
+    )?;
+    tx.execute("UPDATE notes SET created_at = ?1, updated_at = ?1", [now_secs()])?;
+    Ok(())
+}
+
+// A label and kind to show instead of the bare URL; existing links are dated at the upgrade
+fn file_link_details(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE file_links ADD COLUMN label TEXT;
+         ALTER TABLE file_links ADD COLUMN kind TEXT;
+         ALTER TABLE file_links ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;",
+    )?;
+    tx.execute("UPDATE file_links SET created_at = ?1", [now_secs()])?;
+    Ok(())
+}
+
+// Files uploaded to a subject; the bytes themselves live in the blob store under sha256
+fn stored_files(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE stored_files (
+             id INTEGER PRIMARY KEY,
+             subject_id INTEGER NOT NULL REFERENCES subjects(id) ON DELETE CASCADE,
+             filename TEXT NOT NULL,
+             mime_type TEXT NOT NULL,
+             size INTEGER NOT NULL,
+             sha256 TEXT NOT NULL,
+             created_at INTEGER NOT NULL
+         );
+         CREATE INDEX stored_files_subject_id ON stored_files (subject_id);
+         CREATE INDEX stored_files_sha256 ON stored_files (sha256);",
+    )
+}
+
+// The time step of the last TOTP code accepted, so the same code cannot be used twice
+fn totp_last_step(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute("ALTER TABLE users ADD COLUMN totp_last_step INTEGER", [])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tables as the very first release created them, before tasks and subjects had an owner
+    const LEGACY_SCHEMA: &str = "
+        CREATE TABLE users (id INTEGER PRIMARY KEY, username TEXT NOT NULL UNIQUE, password_hash TEXT NOT NULL);
+        CREATE TABLE tasks (id INTEGER PRIMARY KEY, title TEXT NOT NULL, status TEXT NOT NULL, note TEXT);
+        CREATE TABLE subjects (id INTEGER PRIMARY KEY, name TEXT NOT NULL);
+        CREATE TABLE notes (id INTEGER PRIMARY KEY, subject_id INTEGER NOT NULL, content TEXT NOT NULL);";
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn fresh_database_reaches_latest_version_once() {
+        let conn = Connection::open_in_memory().unwrap();
+        assert_eq!(run(&conn).unwrap(), latest_version());
+        assert_eq!(run(&conn).unwrap(), latest_version());
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM schema_version"), latest_version());
+    }
+
+    #[test]
+    fn legacy_rows_go_to_the_only_user() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', 'x');
+             INSERT INTO tasks (title, status) VALUES ('TP', 'Pendiente');
+             INSERT INTO subjects (id, name) VALUES (10, 'Algebra');",
+        )
+        .unwrap();
+
+        run(&conn).unwrap();
+
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM tasks WHERE user_id = 1"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM subjects WHERE user_id = 1"), 1);
+        assert_eq!(count(&conn, "SELECT role = 'user' FROM users WHERE id = 1"), 1);
+    }
+
+    #[test]
+    fn unowned_legacy_rows_are_dropped_when_owner_is_ambiguous() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', 'x'), (2, 'bob', 'x');
+             INSERT INTO subjects (id, name) VALUES (10, 'Algebra');
+             INSERT INTO notes (subject_id, content) VALUES (10, 'a');",
+        )
+        .unwrap();
+
+        run(&conn).unwrap();
+
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM subjects"), 0);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM notes"), 0);
+    }
+
+    #[test]
+    fn deleting_a_subject_cascades_and_orphans_are_removed() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', 'x');
+             INSERT INTO subjects (id, name) VALUES (10, 'Algebra');
+             INSERT INTO notes (subject_id, content) VALUES (10, 'a'), (99, 'orphan');",
+        )
+        .unwrap();
+
+        run(&conn).unwrap();
+        assert_eq!(remove_orphans(&conn).unwrap(), vec![("notes".to_string(), 1)]);
+        assert!(remove_orphans(&conn).unwrap().is_empty());
+
+        conn.execute("DELETE FROM subjects WHERE id = 10", []).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM notes"), 0);
+        // Enforcement stays on for the connection
+        assert!(conn.execute("INSERT INTO notes (subject_id, content) VALUES (99, 'b')", []).is_err());
+    }
+
+    #[test]
+    fn bare_exam_dates_become_midnight() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', 'x');
+             INSERT INTO subjects (id, name) VALUES (10, 'Algebra');
+             CREATE TABLE exam_dates (id INTEGER PRIMARY KEY, subject_id INTEGER NOT NULL, date TEXT NOT NULL);
+             INSERT INTO exam_dates (subject_id, date) VALUES (10, '2024-07-01'), (10, 'el lunes');",
+        )
+        .unwrap();
+
+        run(&conn).unwrap();
+
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM exam_dates WHERE date = '2024-07-01T00:00:00'"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM exam_dates WHERE date = 'el lunes'"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM exam_dates WHERE kind = 'parcial'"), 2);
+    }
+
+    #[test]
+    fn usernames_differing_in_case_stop_the_upgrade() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(LEGACY_SCHEMA).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', 'x'), (2, 'Alice', 'x');
+             INSERT INTO users (id, username, password_hash) VALUES (3, 'bob', 'x');",
+        )
+        .unwrap();
+
+        let error = run(&conn).unwrap_err().to_string();
+        assert!(error.contains("alice, Alice") || error.contains("Alice, alice"), "{}", error);
+        assert_eq!(current_version(&conn).unwrap(), 0);
+
+        conn.execute("UPDATE users SET username = 'alice2' WHERE id = 2", []).unwrap();
+        run(&conn).unwrap();
+        assert!(conn.execute("INSERT INTO users (username, password_hash) VALUES ('BOB', 'x')", []).is_err());
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        run(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, 'from_the_future', 0)",
+            [latest_version() + 1],
+        )
+        .unwrap();
+
+        assert!(matches!(run(&conn), Err(MigrationError::NewerSchema { .. })));
+    }
+}
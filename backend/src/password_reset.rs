@@ -0,0 +1,236 @@
+use actix_web::{web, HttpRequest};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::Deserialize;
+
+use crate::api_tokens::revoke_all_api_tokens;
+use crate::auth::{hash_password, now_secs};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::i18n::{Locale, Message};
+use crate::mail::{Email, MailTransport};
+use crate::rate_limit;
+use crate::repository::Accounts;
+use crate::sessions::{generate_token, hash_token, revoke_all_sessions};
+use crate::validation::{normalize_username, validate_password, ValidationErrors};
+
+// How long a reset token stays usable (1 hour)
+pub const RESET_TOKEN_TTL_SECS: i64 = 60 * 60;
+
+const INVALID_TOKEN: AppError =
+    AppError::BadRequest("reset_token_invalid");
+
+// Request structures
+#[derive(Debug, Deserialize)]
+pub struct ResetRequest {
+    username: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetConfirmRequest {
+    token: String,
+    new_password: String,
+}
+
+// Handler functions
+pub async fn request_reset(
+    req: HttpRequest,
+    reset_info: web::Json<ResetRequest>,
+    accounts: web::Data<Accounts>,
+    mailer: web::Data<dyn MailTransport>,
+) -> AppResult<Message> {
+    // Same answer whether or not the account exists, so it cannot be used to probe usernames
+    let accepted = Message("password_reset_requested");
+
+    let ip_key = rate_limit::password_reset_ip_key(&req);
+    rate_limit::check(&accounts, &[&ip_key]).await?;
+    accounts.login_attempts.record(ip_key, &rate_limit::PASSWORD_RESET_PER_IP).await?;
+
+    let ResetRequest { username, email } = reset_info.into_inner();
+    let username = username.as_deref().map(normalize_username);
+    let email = email.map(|email| email.trim().to_string());
+    let (user_id, email, username) = match accounts.password_resets.find_recipient(username, email).await? {
+        Some(recipient) => recipient,
+        None => return Ok(accepted),
+    };
+
+    let token = accounts.password_resets.create(user_id).await?;
+    // As for any answer, the language the account picked wins over Accept-Language
+    let locale = accounts.users.locale(user_id).await?.unwrap_or(Locale::of(&req));
+    let email = reset_email(&email, &username, &token, locale);
+    web::block(move || mailer.send(&email))
+        .await
+        .map_err(AppError::internal)?
+        .map_err(AppError::internal)?;
+    Ok(accepted)
+}
+
+pub async fn confirm_reset(
+    confirm_info: web::Json<ResetConfirmRequest>,
+    accounts: web::Data<Accounts>,
+    config: web::Data<Config>,
+) -> AppResult<Message> {
+    let ResetConfirmRequest { token, new_password } = confirm_info.into_inner();
+    let (token_id, user_id, username) = accounts.password_resets.find(token).await?.ok_or(INVALID_TOKEN)?;
+
+    let mut errors = ValidationErrors::default();
+    validate_password("new_password", &new_password, &username, &mut errors);
+    errors.into_result()?;
+
+    let password_hash = hash_password(new_password, config.bcrypt_cost).await?;
+    if !accounts.password_resets.reset_password(token_id, user_id, password_hash).await? {
+        return Err(INVALID_TOKEN);
+    }
+    Ok(Message("password_reset_done"))
+}
+
+// The token goes in first, so a username that happens to contain "{token}" stays as written
+pub fn reset_email(to: &str, username: &str, token: &str, locale: Locale) -> Email {
+    Email {
+        to: to.to_string(),
+        subject: locale.text("password_reset_email_subject").to_string(),
+        body: locale
+            .text("password_reset_email_body")
+            .replace("{token}", token)
+            .replace("{username}", username),
+    }
+}
+
+// Database functions
+
+// Returns (user_id, email, username) of the account the request points to, if it has an email
+pub fn find_recipient(
+    conn: &Connection,
+    username: Option<String>,
+    email: Option<String>,
+) -> Result<Option<(i32, String, String)>> {
+    let (sql, value) = match (username, email) {
+        (Some(username), _) => (
+            "SELECT id, email, username FROM users WHERE username = ?1 COLLATE NOCASE AND email IS NOT NULL",
+            username,
+        ),
+        (None, Some(email)) => ("SELECT id, email, username FROM users WHERE email = ?1 COLLATE NOCASE", email),
+        (None, None) => return Ok(None),
+    };
+    conn.query_row(sql, [value], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .optional()
+}
+
+// Issue a new token for the user; any token sent earlier stops working
+pub fn create_reset_token(conn: &Connection, user_id: i32) -> Result<String> {
+    let token = generate_token();
+    let now = now_secs();
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE password_reset_tokens SET used_at = ?1 WHERE user_id = ?2 AND used_at IS NULL",
+        params![now, user_id],
+    )?;
+    tx.execute(
+        "INSERT INTO password_reset_tokens (user_id, token_hash, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![user_id, hash_token(&token), now, now + RESET_TOKEN_TTL_SECS],
+    )?;
+    tx.commit()?;
+    Ok(token)
+}
+
+// Returns (token_id, user_id, username) for an unused, unexpired token
+pub fn find_reset_token(conn: &Connection, token: &str) -> Result<Option<(i64, i32, String)>> {
+    conn.query_row(
+        "SELECT password_reset_tokens.id, users.id, users.username FROM password_reset_tokens
+         JOIN users ON users.id = password_reset_tokens.user_id
+         WHERE token_hash = ?1 AND used_at IS NULL AND expires_at > ?2",
+        params![hash_token(token), now_secs()],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )
+    .optional()
+}
+
+// Burn the token, store the new password and end every session and API token, all or nothing.
+// Returns false if the token was used in the meantime.
+pub fn reset_password(conn: &Connection, token_id: i64, user_id: i32, password_hash: &str) -> Result<bool> {
+    let tx = conn.unchecked_transaction()?;
+    let burned = tx.execute(
+        "UPDATE password_reset_tokens SET used_at = ?1 WHERE id = ?2 AND used_at IS NULL",
+        params![now_secs(), token_id],
+    )?;
+    if burned == 0 {
+        return Ok(false);
+    }
+    tx.execute(
+        "UPDATE users SET password_hash = ?1, must_reset_password = 0 WHERE id = ?2",
+        params![password_hash, user_id],
+    )?;
+    revoke_all_sessions(&tx, user_id)?;
+    revoke_all_api_tokens(&tx, user_id)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::mail::FileTransport;
+    use actix_web::{http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn reset_flow_through_outbox() {
+        let outbox = std::env::temp_dir().join(format!("classmate-outbox-{}", generate_token()));
+        let mailer: Arc<dyn MailTransport> = Arc::new(FileTransport::new(&outbox, "ClassMate <no-reply@classmate.local>").unwrap());
+
+        let pool = crate::db::test_pool();
+        let conn = pool.get().unwrap();
+        conn.execute(
+            "INSERT INTO users (id, username, password_hash, email) VALUES (1, 'alice', 'old', 'alice@example.com')",
+            [],
+        )
+        .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Accounts::sqlite(pool.clone())))
+                .app_data(web::Data::new(Config::default()))
+                .app_data(web::Data::from(mailer))
+                .configure(crate::configure_routes),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/password_reset/request")
+            .insert_header(("Accept-Language", "en-US,en;q=0.9"))
+            .set_json(serde_json::json!({ "email": "Alice@Example.com" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let sent: Vec<_> = std::fs::read_dir(&outbox).unwrap().collect();
+        assert_eq!(sent.len(), 1);
+        let message = std::fs::read_to_string(sent[0].as_ref().unwrap().path()).unwrap();
+        assert!(message.contains("Reset your ClassMate password"));
+        assert!(message.contains("Hi alice,"));
+        let token = message
+            .lines()
+            .map(str::trim)
+            .find(|line| line.len() == 64 && line.chars().all(|c| c.is_ascii_hexdigit()))
+            .unwrap()
+            .to_string();
+
+        let confirm = |password: &str| {
+            test::TestRequest::post()
+                .uri("/password_reset/confirm")
+                .set_json(serde_json::json!({ "token": token, "new_password": password }))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, confirm("corto")).await.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(test::call_service(&app, confirm("nueva-clave-segura")).await.status(), StatusCode::OK);
+        assert_eq!(test::call_service(&app, confirm("otra-clave-segura")).await.status(), StatusCode::BAD_REQUEST);
+
+        let password_hash: String = pool
+            .get()
+            .unwrap()
+            .query_row("SELECT password_hash FROM users WHERE id = 1", [], |row| row.get(0))
+            .unwrap();
+        assert!(bcrypt::verify("nueva-clave-segura", &password_hash).unwrap());
+
+        std::fs::remove_dir_all(outbox).ok();
+    }
+}
@@ -0,0 +1,252 @@
+use actix_web::HttpRequest;
+use rusqlite::{params, Connection, OptionalExtension, Result, Transaction, TransactionBehavior};
+
+use crate::auth::now_secs;
+use crate::error::AppError;
+use crate::repository::Accounts;
+
+// How many attempts a key gets and what happens once it runs out
+pub struct Policy {
+    // Attempts allowed before any delay is applied
+    free_attempts: i64,
+    // Attempts after which the key is locked for `lockout_secs`
+    lockout_after: i64,
+    lockout_secs: i64,
+    // Cap for the exponential delay between free_attempts and lockout_after
+    max_backoff_secs: i64,
+    // Attempts older than this are forgotten
+    window_secs: i64,
+}
+
+// Failed logins for a single account
+pub const LOGIN_PER_USERNAME: Policy = Policy {
+    free_attempts: 3,
+    lockout_after: 10,
+    lockout_secs: 15 * 60,
+    max_backoff_secs: 5 * 60,
+    window_secs: 60 * 60,
+};
+
+// Failed logins from a single address; looser because a whole lab can share one address
+pub const LOGIN_PER_IP: Policy = Policy {
+    free_attempts: 20,
+    lockout_after: 100,
+    lockout_secs: 15 * 60,
+    max_backoff_secs: 60,
+    window_secs: 60 * 60,
+};
+
+// Account creations from a single address
+pub const REGISTER_PER_IP: Policy = Policy {
+    free_attempts: 5,
+    lockout_after: 5,
+    lockout_secs: 60 * 60,
+    max_backoff_secs: 0,
+    window_secs: 60 * 60,
+};
+
+// Password reset emails requested from a single address
+pub const PASSWORD_RESET_PER_IP: Policy = Policy {
+    free_attempts: 5,
+    lockout_after: 5,
+    lockout_secs: 60 * 60,
+    max_backoff_secs: 0,
+    window_secs: 60 * 60,
+};
+
+pub fn login_ip_key(req: &HttpRequest) -> String {
+    format!("login:ip:{}", client_ip(req))
+}
+
+pub fn login_user_key(username: &str) -> String {
+    format!("login:user:{}", username.to_lowercase())
+}
+
+// Shared by the second login step and turning 2FA off, which both take a code
+pub fn two_factor_key(user_id: i32) -> String {
+    format!("login:2fa:{}", user_id)
+}
+
+// Checks of the current password before changing it or deleting the account
+pub fn password_key(user_id: i32) -> String {
+    format!("password:user:{}", user_id)
+}
+
+pub fn register_ip_key(req: &HttpRequest) -> String {
+    format!("register:ip:{}", client_ip(req))
+}
+
+pub fn password_reset_ip_key(req: &HttpRequest) -> String {
+    format!("password_reset:ip:{}", client_ip(req))
+}
+
+// Address of the peer; forwarding headers are ignored so clients cannot pick their own key
+fn client_ip(req: &HttpRequest) -> String {
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// Reject the request with 429 if any of the keys is still blocked
+pub async fn check(accounts: &Accounts, keys: &[&str]) -> std::result::Result<(), AppError> {
+    let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+    match accounts.login_attempts.blocked_for(keys).await? {
+        0 => Ok(()),
+        retry_after => Err(AppError::TooManyRequests { retry_after }),
+    }
+}
+
+// Count an attempt against every key before it is made, or answer 429 without counting it when one
+// of them is blocked. Checking and counting in one write transaction keeps concurrent attempts from
+// all getting past the check before the first failure is written; a success is handed back with
+// clear or refund.
+pub async fn reserve(accounts: &Accounts, keys: &[(&str, &'static Policy)]) -> std::result::Result<(), AppError> {
+    let keys: Vec<(String, &'static Policy)> = keys.iter().map(|(key, policy)| (key.to_string(), *policy)).collect();
+    match accounts.login_attempts.reserve(keys).await? {
+        0 => Ok(()),
+        retry_after => Err(AppError::TooManyRequests { retry_after }),
+    }
+}
+
+// (attempts, blocked_until) of a key after one more attempt at `now`, given the (attempts, last_attempt_at)
+// it had before
+pub fn count_attempt(policy: &Policy, previous: Option<(i64, i64)>, now: i64) -> (i64, i64) {
+    let attempts = match previous {
+        Some((attempts, last_attempt_at)) if now - last_attempt_at < policy.window_secs => attempts + 1,
+        _ => 1,
+    };
+
+    let blocked_until = if attempts >= policy.lockout_after {
+        now + policy.lockout_secs
+    } else if attempts > policy.free_attempts {
+        let exponent = (attempts - policy.free_attempts - 1).min(30) as u32;
+        now + 2i64.pow(exponent).min(policy.max_backoff_secs)
+    } else {
+        0
+    };
+    (attempts, blocked_until)
+}
+
+// Database functions
+
+// Returns the seconds to wait when a key is blocked, in which case nothing was counted
+pub fn reserve_attempts(conn: &Connection, keys: &[(String, &Policy)]) -> Result<i64> {
+    let tx = Transaction::new_unchecked(conn, TransactionBehavior::Immediate)?;
+    let mut retry_after = 0;
+    for (key, _) in keys {
+        retry_after = retry_after.max(seconds_blocked_for(&tx, key)?);
+    }
+    if retry_after > 0 {
+        return Ok(retry_after);
+    }
+    for (key, policy) in keys {
+        record(&tx, key, policy)?;
+    }
+    tx.commit()?;
+    Ok(0)
+}
+
+// Seconds until every one of the keys is unblocked
+pub fn seconds_blocked(conn: &Connection, keys: &[String]) -> Result<i64> {
+    let mut retry_after = 0;
+    for key in keys {
+        retry_after = retry_after.max(seconds_blocked_for(conn, key)?);
+    }
+    Ok(retry_after)
+}
+
+fn seconds_blocked_for(conn: &Connection, key: &str) -> Result<i64> {
+    let blocked_until: Option<i64> = conn
+        .query_row(
+            "SELECT blocked_until FROM login_attempts WHERE key = ?1",
+            [key],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok((blocked_until.unwrap_or(0) - now_secs()).max(0))
+}
+
+// Count one more attempt against the key and block it according to the policy
+pub fn record_attempt(conn: &Connection, key: &str, policy: &Policy) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    record(&tx, key, policy)?;
+    tx.commit()
+}
+
+fn record(conn: &Connection, key: &str, policy: &Policy) -> Result<()> {
+    let now = now_secs();
+    let previous: Option<(i64, i64)> = conn
+        .query_row(
+            "SELECT attempts, last_attempt_at FROM login_attempts WHERE key = ?1",
+            [key],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let (attempts, blocked_until) = count_attempt(policy, previous, now);
+    conn.execute(
+        "INSERT INTO login_attempts (key, attempts, last_attempt_at, blocked_until)
+         VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(key) DO UPDATE SET
+             attempts = excluded.attempts,
+             last_attempt_at = excluded.last_attempt_at,
+             blocked_until = excluded.blocked_until",
+        params![key, attempts, now, blocked_until],
+    )?;
+    Ok(())
+}
+
+pub fn clear(conn: &Connection, key: &str) -> Result<()> {
+    conn.execute("DELETE FROM login_attempts WHERE key = ?1", [key])?;
+    Ok(())
+}
+
+// Take back one reserved attempt that turned out not to be a failure, leaving any block in place
+pub fn refund(conn: &Connection, key: &str) -> Result<()> {
+    conn.execute("UPDATE login_attempts SET attempts = MAX(attempts - 1, 0) WHERE key = ?1", [key])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Seconds the key was blocked for by its last attempt
+    fn delay(conn: &Connection, key: &str) -> i64 {
+        conn.query_row(
+            "SELECT MAX(blocked_until - last_attempt_at, 0) FROM login_attempts WHERE key = ?1",
+            [key],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn backs_off_then_locks_out() {
+        let pool = crate::db::test_pool();
+        let conn = pool.get().unwrap();
+        let key = login_user_key("Alice");
+
+        for _ in 0..LOGIN_PER_USERNAME.free_attempts {
+            record_attempt(&conn, &key, &LOGIN_PER_USERNAME).unwrap();
+        }
+        assert_eq!(delay(&conn, &key), 0);
+
+        record_attempt(&conn, &key, &LOGIN_PER_USERNAME).unwrap();
+        assert_eq!(delay(&conn, &key), 1);
+        record_attempt(&conn, &key, &LOGIN_PER_USERNAME).unwrap();
+        assert_eq!(delay(&conn, &key), 2);
+
+        for _ in 0..LOGIN_PER_USERNAME.lockout_after {
+            record_attempt(&conn, &key, &LOGIN_PER_USERNAME).unwrap();
+        }
+        assert_eq!(delay(&conn, &key), LOGIN_PER_USERNAME.lockout_secs);
+        let accounts = Accounts::sqlite(pool.clone());
+        match check(&accounts, &[&key]).await {
+            Err(AppError::TooManyRequests { retry_after }) => assert!(retry_after > 0),
+            other => panic!("expected 429, got {:?}", other),
+        }
+
+        clear(&conn, &key).unwrap();
+        assert!(check(&accounts, &[&key]).await.is_ok());
+    }
+}
@@ -0,0 +1,1323 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::admin::{AuditEntry, UserSummary};
+use crate::api_tokens::{ApiToken, TokenScope};
+use crate::db::{DbError, DbPool};
+use crate::i18n::Locale;
+use crate::rate_limit::Policy;
+use crate::sessions::Session;
+use crate::two_factor::TotpState;
+
+#[cfg(test)]
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+
+#[cfg(test)]
+pub use memory::MemoryRepository;
+#[cfg(feature = "postgres")]
+pub use postgres::{check_postgres_tls, PostgresRepository};
+pub use sqlite::SqliteRepository;
+
+// User data structure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    #[serde(skip_serializing)]
+    pub password_hash: String,
+    pub email: Option<String>,
+    pub totp_enabled: bool,
+}
+
+// Task data structure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Task {
+    pub id: i32,
+    pub title: String,
+    pub status: String,
+    pub note: Option<String>,
+    pub user_id: i32,
+}
+
+// Subject data structure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subject {
+    pub id: i32,
+    pub name: String,
+    pub user_id: i32,
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    // Subjects are listed by this, then by id
+    pub display_order: i32,
+    pub archived: bool,
+    pub professor: Option<String>,
+    pub course_code: Option<String>,
+}
+
+impl Subject {
+    fn new(id: i32, name: String, user_id: i32) -> Self {
+        Subject {
+            id,
+            name,
+            user_id,
+            color: None,
+            icon: None,
+            display_order: 0,
+            archived: false,
+            professor: None,
+            course_code: None,
+        }
+    }
+}
+
+// What an update changes on a subject. None leaves a field as it is; for the optional
+// fields Some(None) clears it.
+#[derive(Debug, Clone, Default)]
+pub struct SubjectChanges {
+    pub name: Option<String>,
+    pub color: Option<Option<String>>,
+    pub icon: Option<Option<String>>,
+    pub display_order: Option<i32>,
+    pub archived: Option<bool>,
+    pub professor: Option<Option<String>>,
+    pub course_code: Option<Option<String>>,
+}
+
+impl SubjectChanges {
+    pub fn apply(self, subject: &mut Subject) {
+        if let Some(name) = self.name {
+            subject.name = name;
+        }
+        if let Some(color) = self.color {
+            subject.color = color;
+        }
+        if let Some(icon) = self.icon {
+            subject.icon = icon;
+        }
+        if let Some(display_order) = self.display_order {
+            subject.display_order = display_order;
+        }
+        if let Some(archived) = self.archived {
+            subject.archived = archived;
+        }
+        if let Some(professor) = self.professor {
+            subject.professor = professor;
+        }
+        if let Some(course_code) = self.course_code {
+            subject.course_code = course_code;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExamKind {
+    Parcial,
+    Final,
+    Recuperatorio,
+    TpDeadline,
+}
+
+impl ExamKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExamKind::Parcial => "parcial",
+            ExamKind::Final => "final",
+            ExamKind::Recuperatorio => "recuperatorio",
+            ExamKind::TpDeadline => "tp_deadline",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<ExamKind> {
+        match value {
+            "parcial" => Some(ExamKind::Parcial),
+            "final" => Some(ExamKind::Final),
+            "recuperatorio" => Some(ExamKind::Recuperatorio),
+            "tp_deadline" => Some(ExamKind::TpDeadline),
+            _ => None,
+        }
+    }
+}
+
+// ExamDate data structure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExamDate {
+    pub id: i32,
+    pub subject_id: i32,
+    // Local date and time as YYYY-MM-DDTHH:MM:SS, so sorting the text sorts chronologically
+    pub date: String,
+    pub duration_minutes: Option<i32>,
+    pub kind: ExamKind,
+    pub location: Option<String>,
+    pub notes: Option<String>,
+    // Share of the final grade, in percent
+    pub weight: Option<f64>,
+}
+
+// Everything about an exam date but its ids, for creating one
+#[derive(Debug, Clone)]
+pub struct NewExamDate {
+    pub date: String,
+    pub duration_minutes: Option<i32>,
+    pub kind: ExamKind,
+    pub location: Option<String>,
+    pub notes: Option<String>,
+    pub weight: Option<f64>,
+}
+
+impl ExamDate {
+    fn new(id: i32, subject_id: i32, exam: NewExamDate) -> Self {
+        ExamDate {
+            id,
+            subject_id,
+            date: exam.date,
+            duration_minutes: exam.duration_minutes,
+            kind: exam.kind,
+            location: exam.location,
+            notes: exam.notes,
+            weight: exam.weight,
+        }
+    }
+}
+
+// What an update changes on an exam date, with the same rules as SubjectChanges
+#[derive(Debug, Clone, Default)]
+pub struct ExamDateChanges {
+    pub date: Option<String>,
+    pub duration_minutes: Option<Option<i32>>,
+    pub kind: Option<ExamKind>,
+    pub location: Option<Option<String>>,
+    pub notes: Option<Option<String>>,
+    pub weight: Option<Option<f64>>,
+}
+
+impl ExamDateChanges {
+    pub fn apply(self, exam_date: &mut ExamDate) {
+        if let Some(date) = self.date {
+            exam_date.date = date;
+        }
+        if let Some(duration_minutes) = self.duration_minutes {
+            exam_date.duration_minutes = duration_minutes;
+        }
+        if let Some(kind) = self.kind {
+            exam_date.kind = kind;
+        }
+        if let Some(location) = self.location {
+            exam_date.location = location;
+        }
+        if let Some(notes) = self.notes {
+            exam_date.notes = notes;
+        }
+        if let Some(weight) = self.weight {
+            exam_date.weight = weight;
+        }
+    }
+}
+
+// Note data structure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Note {
+    pub id: i32,
+    pub subject_id: i32,
+    pub title: Option<String>,
+    pub content: String,
+    // Unix seconds
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl Note {
+    fn new(id: i32, subject_id: i32, title: Option<String>, content: String, now: i64) -> Self {
+        Note {
+            id,
+            subject_id,
+            title,
+            content,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+// A version of a note that an edit replaced, kept so it can be compared and brought back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NoteRevision {
+    pub id: i32,
+    pub note_id: i32,
+    pub title: Option<String>,
+    pub content: String,
+    // When this version was written, in Unix seconds
+    pub created_at: i64,
+}
+
+// What an update changes on a note; a title sent as null removes it
+#[derive(Debug, Clone, Default)]
+pub struct NoteChanges {
+    pub title: Option<Option<String>>,
+    pub content: Option<String>,
+}
+
+impl NoteChanges {
+    // Tells whether anything actually changed, which is when a revision is worth keeping
+    pub fn apply(self, note: &mut Note, now: i64) -> bool {
+        let mut changed = false;
+        if let Some(title) = self.title {
+            changed |= note.title != title;
+            note.title = title;
+        }
+        if let Some(content) = self.content {
+            changed |= note.content != content;
+            note.content = content;
+        }
+        if changed {
+            note.updated_at = now;
+        }
+        changed
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkKind {
+    Slides,
+    Pdf,
+    Video,
+    Repo,
+}
+
+impl LinkKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LinkKind::Slides => "slides",
+            LinkKind::Pdf => "pdf",
+            LinkKind::Video => "video",
+            LinkKind::Repo => "repo",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<LinkKind> {
+        match value {
+            "slides" => Some(LinkKind::Slides),
+            "pdf" => Some(LinkKind::Pdf),
+            "video" => Some(LinkKind::Video),
+            "repo" => Some(LinkKind::Repo),
+            _ => None,
+        }
+    }
+}
+
+// FileLink data structure
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileLink {
+    pub id: i32,
+    pub subject_id: i32,
+    pub url: String,
+    pub label: Option<String>,
+    pub kind: Option<LinkKind>,
+    // Unix seconds
+    pub created_at: i64,
+}
+
+impl FileLink {
+    fn new(id: i32, subject_id: i32, link: NewFileLink, now: i64) -> Self {
+        FileLink {
+            id,
+            subject_id,
+            url: link.url,
+            label: link.label,
+            kind: link.kind,
+            created_at: now,
+        }
+    }
+}
+
+// Everything about a file link but its ids and date, for creating one
+#[derive(Debug, Clone)]
+pub struct NewFileLink {
+    pub url: String,
+    pub label: Option<String>,
+    pub kind: Option<LinkKind>,
+}
+
+// What an update changes on a file link, with the same rules as SubjectChanges
+#[derive(Debug, Clone, Default)]
+pub struct FileLinkChanges {
+    pub url: Option<String>,
+    pub label: Option<Option<String>>,
+    pub kind: Option<Option<LinkKind>>,
+}
+
+impl FileLinkChanges {
+    pub fn apply(self, file_link: &mut FileLink) {
+        if let Some(url) = self.url {
+            file_link.url = url;
+        }
+        if let Some(label) = self.label {
+            file_link.label = label;
+        }
+        if let Some(kind) = self.kind {
+            file_link.kind = kind;
+        }
+    }
+}
+
+// A file uploaded to a subject. Its content lives in the blob store under sha256,
+// shared with any other upload of the same bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredFile {
+    pub id: i32,
+    pub subject_id: i32,
+    pub filename: String,
+    pub mime_type: String,
+    // Bytes
+    pub size: i64,
+    pub sha256: String,
+    // Unix seconds
+    pub created_at: i64,
+}
+
+impl StoredFile {
+    fn new(id: i32, subject_id: i32, file: NewStoredFile, now: i64) -> Self {
+        StoredFile {
+            id,
+            subject_id,
+            filename: file.filename,
+            mime_type: file.mime_type,
+            size: file.size,
+            sha256: file.sha256,
+            created_at: now,
+        }
+    }
+}
+
+// Everything about an uploaded file but its ids and date, for creating one
+#[derive(Debug, Clone)]
+pub struct NewStoredFile {
+    pub filename: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub sha256: String,
+}
+
+#[derive(Debug)]
+pub enum RepoError {
+    Db(DbError),
+    #[cfg(feature = "postgres")]
+    Postgres(tokio_postgres::Error),
+    #[cfg(feature = "postgres")]
+    PostgresPool(deadpool_postgres::PoolError),
+    // The in-memory store panicked while a writer held it
+    #[cfg(test)]
+    Poisoned,
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoError::Db(error) => write!(f, "{}", error),
+            #[cfg(feature = "postgres")]
+            RepoError::Postgres(error) => write!(f, "postgres error: {}", error),
+            #[cfg(feature = "postgres")]
+            RepoError::PostgresPool(error) => write!(f, "could not get a postgres connection: {}", error),
+            #[cfg(test)]
+            RepoError::Poisoned => write!(f, "in-memory store is poisoned"),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+impl From<DbError> for RepoError {
+    fn from(error: DbError) -> Self {
+        RepoError::Db(error)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<tokio_postgres::Error> for RepoError {
+    fn from(error: tokio_postgres::Error) -> Self {
+        RepoError::Postgres(error)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<deadpool_postgres::PoolError> for RepoError {
+    fn from(error: deadpool_postgres::PoolError) -> Self {
+        RepoError::PostgresPool(error)
+    }
+}
+
+pub type RepoFuture<T> = Pin<Box<dyn Future<Output = Result<T, RepoError>>>>;
+
+// Creating returns the stored row with its new id. Updates and deletes answer false when no row matched,
+// or None for updates that return the changed row, and ownership lookups answer None when the row does
+// not exist.
+// Rows hanging off a subject are owned by the subject's user.
+pub trait UserRepository: Send + Sync {
+    fn create(&self, username: String, password_hash: String, email: Option<String>) -> RepoFuture<User>;
+    // Usernames are matched case-insensitively
+    fn find_by_username(&self, username: String) -> RepoFuture<Option<User>>;
+}
+
+pub trait TaskRepository: Send + Sync {
+    fn list(&self, user_id: i32) -> RepoFuture<Vec<Task>>;
+    fn create(&self, user_id: i32, title: String, status: String, note: Option<String>) -> RepoFuture<Task>;
+    fn owner(&self, task_id: i32) -> RepoFuture<Option<i32>>;
+    fn update_status(&self, task_id: i32, status: String) -> RepoFuture<bool>;
+    fn update_note(&self, task_id: i32, note: String) -> RepoFuture<bool>;
+    fn delete(&self, task_id: i32) -> RepoFuture<bool>;
+    fn count_for_user(&self, user_id: i32) -> RepoFuture<i64>;
+}
+
+pub trait SubjectRepository: Send + Sync {
+    // In display order; archived subjects only when asked for
+    fn list(&self, user_id: i32, include_archived: bool) -> RepoFuture<Vec<Subject>>;
+    fn create(&self, user_id: i32, name: String) -> RepoFuture<Subject>;
+    fn owner(&self, subject_id: i32) -> RepoFuture<Option<i32>>;
+    fn update(&self, subject_id: i32, changes: SubjectChanges) -> RepoFuture<Option<Subject>>;
+    // Also removes the subject's exam dates, notes, file links and uploaded files
+    fn delete(&self, subject_id: i32) -> RepoFuture<bool>;
+    // Archived subjects included
+    fn count_for_user(&self, user_id: i32) -> RepoFuture<i64>;
+}
+
+pub trait ExamDateRepository: Send + Sync {
+    // Soonest first
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<ExamDate>>;
+    fn create(&self, subject_id: i32, exam: NewExamDate) -> RepoFuture<ExamDate>;
+    fn owner(&self, exam_date_id: i32) -> RepoFuture<Option<i32>>;
+    fn update(&self, exam_date_id: i32, changes: ExamDateChanges) -> RepoFuture<Option<ExamDate>>;
+    fn delete(&self, exam_date_id: i32) -> RepoFuture<bool>;
+}
+
+pub trait NoteRepository: Send + Sync {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<Note>>;
+    fn find(&self, note_id: i32) -> RepoFuture<Option<Note>>;
+    fn create(&self, subject_id: i32, title: Option<String>, content: String) -> RepoFuture<Note>;
+    fn owner(&self, note_id: i32) -> RepoFuture<Option<i32>>;
+    // Keeps the version it replaces as a revision, unless nothing changed
+    fn update(&self, note_id: i32, changes: NoteChanges) -> RepoFuture<Option<Note>>;
+    // Newest first
+    fn revisions(&self, note_id: i32) -> RepoFuture<Vec<NoteRevision>>;
+    fn find_revision(&self, note_id: i32, revision_id: i32) -> RepoFuture<Option<NoteRevision>>;
+    fn delete(&self, note_id: i32) -> RepoFuture<bool>;
+}
+
+pub trait FileLinkRepository: Send + Sync {
+    // Oldest first
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<FileLink>>;
+    fn create(&self, subject_id: i32, link: NewFileLink) -> RepoFuture<FileLink>;
+    fn owner(&self, file_link_id: i32) -> RepoFuture<Option<i32>>;
+    fn update(&self, file_link_id: i32, changes: FileLinkChanges) -> RepoFuture<Option<FileLink>>;
+    fn delete(&self, file_link_id: i32) -> RepoFuture<bool>;
+}
+
+pub trait StoredFileRepository: Send + Sync {
+    // Oldest first
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<StoredFile>>;
+    fn find(&self, file_id: i32) -> RepoFuture<Option<StoredFile>>;
+    fn create(&self, subject_id: i32, file: NewStoredFile) -> RepoFuture<StoredFile>;
+    fn owner(&self, file_id: i32) -> RepoFuture<Option<i32>>;
+    fn delete(&self, file_id: i32) -> RepoFuture<bool>;
+    // The blobs behind every file in the user's subjects, for cleaning up after them
+    fn hashes_for_user(&self, user_id: i32) -> RepoFuture<Vec<String>>;
+    // Whether any file, of any user, still points at the blob
+    fn is_referenced(&self, sha256: String) -> RepoFuture<bool>;
+}
+
+// The storage handlers work against, registered as app data
+#[derive(Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub tasks: Arc<dyn TaskRepository>,
+    pub subjects: Arc<dyn SubjectRepository>,
+    pub exam_dates: Arc<dyn ExamDateRepository>,
+    pub notes: Arc<dyn NoteRepository>,
+    pub file_links: Arc<dyn FileLinkRepository>,
+    pub files: Arc<dyn StoredFileRepository>,
+}
+
+impl Repositories {
+    pub fn sqlite(pool: DbPool) -> Self {
+        Self::from_backend(Arc::new(SqliteRepository::new(pool)))
+    }
+
+    #[cfg(feature = "postgres")]
+    pub fn postgres(pool: postgres::PgPool) -> Self {
+        Self::from_backend(Arc::new(PostgresRepository::new(pool)))
+    }
+
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self::from_backend(Arc::new(MemoryRepository::default()))
+    }
+
+    fn from_backend<R>(backend: Arc<R>) -> Self
+    where
+        R: UserRepository
+            + TaskRepository
+            + SubjectRepository
+            + ExamDateRepository
+            + NoteRepository
+            + FileLinkRepository
+            + StoredFileRepository
+            + 'static,
+    {
+        Repositories {
+            users: backend.clone(),
+            tasks: backend.clone(),
+            subjects: backend.clone(),
+            exam_dates: backend.clone(),
+            notes: backend.clone(),
+            file_links: backend.clone(),
+            files: backend,
+        }
+    }
+}
+
+// Role and flags that decide what an account may do
+#[derive(Debug, Clone)]
+pub struct AccountStatus {
+    pub role: String,
+    pub disabled: bool,
+    pub must_reset_password: bool,
+}
+
+// Accounts and what hangs from them: sessions, API tokens, 2FA, password resets, login attempts and
+// the audit log. Only SQLite and Postgres keep them, always in the same database as the app data, so
+// deleting a user cascades to everything it owns.
+pub trait AccountRepository: Send + Sync {
+    fn status(&self, user_id: i32) -> RepoFuture<Option<AccountStatus>>;
+    fn username(&self, user_id: i32) -> RepoFuture<Option<String>>;
+    fn password_hash(&self, user_id: i32) -> RepoFuture<Option<String>>;
+    // None when the user follows Accept-Language
+    fn locale(&self, user_id: i32) -> RepoFuture<Option<Locale>>;
+    // Every other session and every API token end in the same transaction
+    fn update_password(&self, user_id: i32, password_hash: String, keep_session_id: i64) -> RepoFuture<()>;
+    fn update_username(&self, user_id: i32, username: String) -> RepoFuture<()>;
+    fn update_email(&self, user_id: i32, email: Option<String>) -> RepoFuture<()>;
+    fn update_locale(&self, user_id: i32, locale: Option<Locale>) -> RepoFuture<()>;
+    fn delete(&self, user_id: i32) -> RepoFuture<()>;
+    // Gives the admin role to the account, matched case-insensitively
+    fn promote(&self, username: String) -> RepoFuture<()>;
+}
+
+pub trait SessionRepository: Send + Sync {
+    // Returns the new session's id and refresh token
+    fn create(&self, user_id: i32, device: Option<String>) -> RepoFuture<(i64, String)>;
+    // Whether the session is still active, recording that it was just used
+    fn touch(&self, session_id: i64, user_id: i32) -> RepoFuture<bool>;
+    // Swaps a valid refresh token for a new one and returns (session_id, user_id, refresh_token)
+    fn rotate(&self, refresh_token: String) -> RepoFuture<Option<(i64, i32, String)>>;
+    fn revoke(&self, user_id: i32, session_id: i64) -> RepoFuture<bool>;
+    fn revoke_all(&self, user_id: i32) -> RepoFuture<()>;
+    // Active sessions, most recently used first
+    fn list(&self, user_id: i32, current_session_id: i64) -> RepoFuture<Vec<Session>>;
+}
+
+pub trait ApiTokenRepository: Send + Sync {
+    // Returns the new token's id and the token itself, which is never stored
+    fn create(&self, user_id: i32, name: String, scope: TokenScope) -> RepoFuture<(i64, String)>;
+    // Resolves a presented token to its user and scope, recording that it was just used
+    fn find(&self, token: String) -> RepoFuture<Option<(i32, TokenScope)>>;
+    // Newest first
+    fn list(&self, user_id: i32) -> RepoFuture<Vec<ApiToken>>;
+    fn revoke(&self, user_id: i32, token_id: i64) -> RepoFuture<bool>;
+}
+
+pub trait TwoFactorRepository: Send + Sync {
+    fn state(&self, user_id: i32) -> RepoFuture<Option<TotpState>>;
+    fn set_pending_secret(&self, user_id: i32, secret: String) -> RepoFuture<()>;
+    // Replaces the recovery codes; the step of the code that confirmed enrollment counts as used
+    fn enable(&self, user_id: i32, recovery_code_hashes: Vec<String>, step: i64) -> RepoFuture<()>;
+    fn disable(&self, user_id: i32) -> RepoFuture<()>;
+    // False when the step is not newer than the last one used, which makes it a replay
+    fn use_step(&self, user_id: i32, step: i64) -> RepoFuture<bool>;
+    // (id, code_hash) of every recovery code not used yet
+    fn unused_recovery_codes(&self, user_id: i32) -> RepoFuture<Vec<(i64, String)>>;
+    // False when the code was used in the meantime
+    fn use_recovery_code(&self, code_id: i64) -> RepoFuture<bool>;
+}
+
+pub trait PasswordResetRepository: Send + Sync {
+    // (user_id, email, username) of the account with the username, or else the email, if it has an email
+    fn find_recipient(
+        &self,
+        username: Option<String>,
+        email: Option<String>,
+    ) -> RepoFuture<Option<(i32, String, String)>>;
+    // Any token sent earlier stops working
+    fn create(&self, user_id: i32) -> RepoFuture<String>;
+    // (token_id, user_id, username) for an unused, unexpired token
+    fn find(&self, token: String) -> RepoFuture<Option<(i64, i32, String)>>;
+    // Burns the token, stores the password and ends every session and API token, all or nothing.
+    // False when the token was used in the meantime.
+    fn reset_password(&self, token_id: i64, user_id: i32, password_hash: String) -> RepoFuture<bool>;
+}
+
+// Every change here is written to the audit log in the same transaction, and answers false or None
+// when the user does not exist
+pub trait AdminRepository: Send + Sync {
+    // Task and subject counts are left for the caller to fill in
+    fn user_summaries(&self) -> RepoFuture<Vec<UserSummary>>;
+    // Also ends every session and API token
+    fn disable(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool>;
+    fn enable(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool>;
+    // Logs the user out everywhere until they reset their password; returns (username, email)
+    fn require_password_reset(&self, admin_id: i32, user_id: i32) -> RepoFuture<Option<(String, Option<String>)>>;
+    fn delete_user(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool>;
+    // Newest first, one page of entries older than before_id
+    fn audit_log(&self, before_id: Option<i64>) -> RepoFuture<Vec<AuditEntry>>;
+}
+
+pub trait LoginAttemptRepository: Send + Sync {
+    // Counts an attempt against every key in one write transaction, unless one of them is blocked;
+    // returns the seconds to wait in that case, when nothing was counted
+    fn reserve(&self, keys: Vec<(String, &'static Policy)>) -> RepoFuture<i64>;
+    // Seconds until every one of the keys is unblocked
+    fn blocked_for(&self, keys: Vec<String>) -> RepoFuture<i64>;
+    fn record(&self, key: String, policy: &'static Policy) -> RepoFuture<()>;
+    fn clear(&self, key: String) -> RepoFuture<()>;
+    // Takes back one attempt, leaving any block in place
+    fn refund(&self, key: String) -> RepoFuture<()>;
+}
+
+// The account storage handlers work against, registered as app data next to Repositories
+#[derive(Clone)]
+pub struct Accounts {
+    pub users: Arc<dyn AccountRepository>,
+    pub sessions: Arc<dyn SessionRepository>,
+    pub api_tokens: Arc<dyn ApiTokenRepository>,
+    pub two_factor: Arc<dyn TwoFactorRepository>,
+    pub password_resets: Arc<dyn PasswordResetRepository>,
+    pub admin: Arc<dyn AdminRepository>,
+    pub login_attempts: Arc<dyn LoginAttemptRepository>,
+}
+
+impl Accounts {
+    pub fn sqlite(pool: DbPool) -> Self {
+        Self::from_backend(Arc::new(SqliteRepository::new(pool)))
+    }
+
+    #[cfg(feature = "postgres")]
+    pub fn postgres(pool: postgres::PgPool) -> Self {
+        Self::from_backend(Arc::new(PostgresRepository::new(pool)))
+    }
+
+    fn from_backend<R>(backend: Arc<R>) -> Self
+    where
+        R: AccountRepository
+            + SessionRepository
+            + ApiTokenRepository
+            + TwoFactorRepository
+            + PasswordResetRepository
+            + AdminRepository
+            + LoginAttemptRepository
+            + 'static,
+    {
+        Accounts {
+            users: backend.clone(),
+            sessions: backend.clone(),
+            api_tokens: backend.clone(),
+            two_factor: backend.clone(),
+            password_resets: backend.clone(),
+            admin: backend.clone(),
+            login_attempts: backend,
+        }
+    }
+}
+
+// Connect to DATABASE_URL and bring its schema up to date; accounts and app data share the pool
+#[cfg(feature = "postgres")]
+pub async fn open_postgres(url: &str) -> Result<(Repositories, Accounts), RepoError> {
+    let pool = postgres::open_pool(url).await?;
+    Ok((Repositories::postgres(pool.clone()), Accounts::postgres(pool)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AppError;
+    use crate::rate_limit::REGISTER_PER_IP;
+    use std::future::Future;
+
+    fn exam(date: &str) -> NewExamDate {
+        NewExamDate {
+            date: date.into(),
+            duration_minutes: None,
+            kind: ExamKind::Parcial,
+            location: None,
+            notes: None,
+            weight: None,
+        }
+    }
+
+    fn upload(filename: &str, sha256: &str) -> NewStoredFile {
+        NewStoredFile {
+            filename: filename.into(),
+            mime_type: "application/pdf".into(),
+            size: 3,
+            sha256: sha256.into(),
+        }
+    }
+
+    // A user with one subject, which most rows hang from
+    async fn alice_with_subject(repos: &Repositories) -> (i32, Subject) {
+        let alice = repos.users.create("alice".into(), "hash".into(), None).await.unwrap();
+        let subject = repos.subjects.create(alice.id, "Algebra".into()).await.unwrap();
+        (alice.id, subject)
+    }
+
+    // Every backend has to behave the same for the handlers built on them; each concern below runs
+    // against each backend as its own test, so a failure names both
+
+    async fn users(repos: Repositories) {
+        let created = repos.users.create("alice".into(), "hash".into(), None).await.unwrap();
+        let alice = repos.users.find_by_username("ALICE".into()).await.unwrap().unwrap();
+        assert_eq!((alice.id, alice.username.as_str()), (created.id, "alice"));
+        assert!(repos.users.find_by_username("bob".into()).await.unwrap().is_none());
+    }
+
+    async fn tasks(repos: Repositories) {
+        let alice = repos.users.create("alice".into(), "hash".into(), None).await.unwrap().id;
+        let task = repos.tasks.create(alice, "TP".into(), "Pendiente".into(), None).await.unwrap();
+        assert_eq!(task.note.as_deref(), Some(""));
+        assert_eq!(repos.tasks.list(alice).await.unwrap()[0].id, task.id);
+        assert!(repos.tasks.update_status(task.id, "Tarea finalizada".into()).await.unwrap());
+        assert!(repos.tasks.update_note(task.id, "entregado".into()).await.unwrap());
+        let task = repos.tasks.list(alice).await.unwrap().remove(0);
+        assert_eq!((task.status.as_str(), task.note.as_deref()), ("Tarea finalizada", Some("entregado")));
+        assert_eq!(repos.tasks.owner(task.id).await.unwrap(), Some(alice));
+        assert_eq!(repos.tasks.count_for_user(alice).await.unwrap(), 1);
+        assert!(repos.tasks.delete(task.id).await.unwrap());
+        assert_eq!(repos.tasks.count_for_user(alice).await.unwrap(), 0);
+        assert_eq!(repos.tasks.owner(task.id).await.unwrap(), None);
+        // Nothing left to touch
+        assert!(!repos.tasks.delete(task.id).await.unwrap());
+        assert!(!repos.tasks.update_status(task.id, "Pendiente".into()).await.unwrap());
+        assert!(!repos.tasks.update_note(task.id, "".into()).await.unwrap());
+    }
+
+    async fn subjects(repos: Repositories) {
+        let (alice, subject) = alice_with_subject(&repos).await;
+        assert_eq!(repos.subjects.list(alice, false).await.unwrap()[0].id, subject.id);
+        assert_eq!(repos.subjects.owner(subject.id).await.unwrap(), Some(alice));
+
+        // Archived subjects are hidden unless asked for, and the rest follow display_order
+        let archived = repos.subjects.create(alice, "Quimica".into()).await.unwrap();
+        let first = repos.subjects.create(alice, "Fisica".into()).await.unwrap();
+        let changes = SubjectChanges {
+            archived: Some(true),
+            professor: Some(Some("Perez".into())),
+            ..Default::default()
+        };
+        let updated = repos.subjects.update(archived.id, changes).await.unwrap().unwrap();
+        assert!(updated.archived);
+        assert_eq!(updated.professor.as_deref(), Some("Perez"));
+        let changes = SubjectChanges {
+            name: Some("Fisica I".into()),
+            display_order: Some(-1),
+            color: Some(Some("#ff0000".into())),
+            ..Default::default()
+        };
+        repos.subjects.update(first.id, changes).await.unwrap().unwrap();
+        let changes = SubjectChanges {
+            color: Some(None),
+            ..Default::default()
+        };
+        let first = repos.subjects.update(first.id, changes).await.unwrap().unwrap();
+        assert_eq!((first.name.as_str(), first.color), ("Fisica I", None));
+        let ids = |subjects: &[Subject]| subjects.iter().map(|subject| subject.id).collect::<Vec<_>>();
+        assert_eq!(ids(&repos.subjects.list(alice, false).await.unwrap()), [first.id, subject.id]);
+        let all = repos.subjects.list(alice, true).await.unwrap();
+        assert_eq!(ids(&all), [first.id, subject.id, archived.id]);
+        assert_eq!(all[2].professor.as_deref(), Some("Perez"));
+        assert_eq!(repos.subjects.count_for_user(alice).await.unwrap(), 3);
+        assert!(repos.subjects.delete(archived.id).await.unwrap());
+        assert!(repos.subjects.delete(first.id).await.unwrap());
+        assert!(!repos.subjects.delete(first.id).await.unwrap());
+        assert!(repos.subjects.update(first.id, SubjectChanges::default()).await.unwrap().is_none());
+    }
+
+    async fn exam_dates(repos: Repositories) {
+        let (alice, subject) = alice_with_subject(&repos).await;
+        let exam_date = repos.exam_dates.create(subject.id, exam("2024-07-01T09:00:00")).await.unwrap();
+        assert_eq!(repos.exam_dates.list(subject.id).await.unwrap()[0].id, exam_date.id);
+        assert_eq!(repos.exam_dates.owner(exam_date.id).await.unwrap(), Some(alice));
+
+        // Exam dates come soonest first, and an edit can move one ahead of another
+        let later = repos.exam_dates.create(subject.id, exam("2024-06-15T18:00:00")).await.unwrap();
+        let changes = ExamDateChanges {
+            date: Some("2024-12-01T08:30:00".into()),
+            kind: Some(ExamKind::Final),
+            location: Some(Some("Aula 4".into())),
+            weight: Some(Some(40.0)),
+            ..Default::default()
+        };
+        let later = repos.exam_dates.update(later.id, changes).await.unwrap().unwrap();
+        assert_eq!(later.kind, ExamKind::Final);
+        let listed = repos.exam_dates.list(subject.id).await.unwrap();
+        assert_eq!(listed.iter().map(|exam_date| exam_date.id).collect::<Vec<_>>(), [exam_date.id, later.id]);
+        assert_eq!(listed[1].location.as_deref(), Some("Aula 4"));
+        assert_eq!(listed[1].weight, Some(40.0));
+        assert!(repos.exam_dates.delete(later.id).await.unwrap());
+        assert!(!repos.exam_dates.delete(later.id).await.unwrap());
+        assert!(repos.exam_dates.update(later.id, ExamDateChanges::default()).await.unwrap().is_none());
+    }
+
+    async fn notes(repos: Repositories) {
+        let (alice, subject) = alice_with_subject(&repos).await;
+        let note = repos.notes.create(subject.id, Some("Clase 1".into()), "Apuntes".into()).await.unwrap();
+        let second = repos.notes.create(subject.id, None, "Más apuntes".into()).await.unwrap();
+        let listed: Vec<_> = repos.notes.list(subject.id).await.unwrap().iter().map(|note| note.id).collect();
+        assert_eq!(listed, [note.id, second.id]);
+        assert_eq!(repos.notes.owner(note.id).await.unwrap(), Some(alice));
+
+        // Every edit that changes something keeps the version it replaced, newest first
+        let edit = |content: &str| NoteChanges {
+            content: Some(content.into()),
+            ..Default::default()
+        };
+        assert_eq!(repos.notes.update(note.id, edit("Apuntes v2")).await.unwrap().unwrap().content, "Apuntes v2");
+        repos.notes.update(note.id, edit("Apuntes v2")).await.unwrap().unwrap();
+        let changes = NoteChanges {
+            title: Some(None),
+            content: Some("Apuntes v3".into()),
+        };
+        let edited = repos.notes.update(note.id, changes).await.unwrap().unwrap();
+        assert!(edited.title.is_none());
+        assert!(edited.updated_at >= note.created_at);
+        let revisions = repos.notes.revisions(note.id).await.unwrap();
+        let contents: Vec<_> = revisions.iter().map(|revision| revision.content.as_str()).collect();
+        assert_eq!(contents, ["Apuntes v2", "Apuntes"]);
+        assert_eq!(revisions[1].title.as_deref(), Some("Clase 1"));
+        let found = repos.notes.find_revision(note.id, revisions[1].id).await.unwrap().unwrap();
+        assert_eq!(found.content, "Apuntes");
+        assert!(repos.notes.find_revision(999, revisions[1].id).await.unwrap().is_none());
+        assert_eq!(repos.notes.find(note.id).await.unwrap().unwrap().content, "Apuntes v3");
+        assert!(repos.notes.update(999, edit("x")).await.unwrap().is_none());
+
+        // Deleting a note takes its revisions along
+        assert!(repos.notes.delete(note.id).await.unwrap());
+        assert!(!repos.notes.delete(note.id).await.unwrap());
+        assert!(repos.notes.revisions(note.id).await.unwrap().is_empty());
+    }
+
+    async fn file_links(repos: Repositories) {
+        let (alice, subject) = alice_with_subject(&repos).await;
+        let link = NewFileLink {
+            url: "https://example.com".into(),
+            label: Some("Campus".into()),
+            kind: None,
+        };
+        let file_link = repos.file_links.create(subject.id, link).await.unwrap();
+        assert_eq!(repos.file_links.list(subject.id).await.unwrap()[0].id, file_link.id);
+        assert_eq!(repos.file_links.owner(file_link.id).await.unwrap(), Some(alice));
+
+        let changes = FileLinkChanges {
+            label: Some(None),
+            kind: Some(Some(LinkKind::Slides)),
+            ..Default::default()
+        };
+        let updated = repos.file_links.update(file_link.id, changes).await.unwrap().unwrap();
+        assert_eq!(updated.url, "https://example.com");
+        let listed = &repos.file_links.list(subject.id).await.unwrap()[0];
+        assert_eq!((listed.label.as_deref(), listed.kind), (None, Some(LinkKind::Slides)));
+        assert_eq!(listed.created_at, file_link.created_at);
+        assert!(repos.file_links.delete(file_link.id).await.unwrap());
+        assert!(!repos.file_links.delete(file_link.id).await.unwrap());
+        assert!(repos.file_links.update(file_link.id, FileLinkChanges::default()).await.unwrap().is_none());
+    }
+
+    async fn stored_files(repos: Repositories) {
+        let (alice, subject) = alice_with_subject(&repos).await;
+
+        // Two uploads of the same bytes share a hash, which stays referenced while either is left
+        let sha256 = "ab".repeat(32);
+        let file = repos.files.create(subject.id, upload("tp.pdf", &sha256)).await.unwrap();
+        let copy = repos.files.create(subject.id, upload("copia.pdf", &sha256)).await.unwrap();
+        let listed = repos.files.list(subject.id).await.unwrap();
+        assert_eq!(listed.iter().map(|file| file.id).collect::<Vec<_>>(), [file.id, copy.id]);
+        assert_eq!(repos.files.find(file.id).await.unwrap().unwrap().filename, "tp.pdf");
+        assert_eq!(repos.files.owner(file.id).await.unwrap(), Some(alice));
+        assert_eq!(repos.files.hashes_for_user(alice).await.unwrap(), [sha256.as_str()]);
+        assert!(repos.files.delete(copy.id).await.unwrap());
+        assert!(!repos.files.delete(copy.id).await.unwrap());
+        assert!(repos.files.find(copy.id).await.unwrap().is_none());
+        assert!(repos.files.is_referenced(sha256.clone()).await.unwrap());
+        assert!(repos.files.delete(file.id).await.unwrap());
+        assert!(!repos.files.is_referenced(sha256).await.unwrap());
+    }
+
+    async fn deleting_a_subject_takes_its_rows(repos: Repositories) {
+        let (alice, subject) = alice_with_subject(&repos).await;
+        let exam_date = repos.exam_dates.create(subject.id, exam("2024-07-01T09:00:00")).await.unwrap();
+        let note = repos.notes.create(subject.id, None, "Apuntes".into()).await.unwrap();
+        let edit = NoteChanges {
+            content: Some("Apuntes v2".into()),
+            ..Default::default()
+        };
+        repos.notes.update(note.id, edit).await.unwrap().unwrap();
+        let link = NewFileLink {
+            url: "https://example.com".into(),
+            label: None,
+            kind: None,
+        };
+        let file_link = repos.file_links.create(subject.id, link).await.unwrap();
+        let sha256 = "cd".repeat(32);
+        let file = repos.files.create(subject.id, upload("tp.pdf", &sha256)).await.unwrap();
+
+        assert!(repos.subjects.delete(subject.id).await.unwrap());
+        assert!(repos.subjects.list(alice, true).await.unwrap().is_empty());
+        assert!(repos.notes.list(subject.id).await.unwrap().is_empty());
+        assert!(repos.notes.revisions(note.id).await.unwrap().is_empty());
+        assert_eq!(repos.exam_dates.owner(exam_date.id).await.unwrap(), None);
+        assert_eq!(repos.file_links.owner(file_link.id).await.unwrap(), None);
+        assert_eq!(repos.files.owner(file.id).await.unwrap(), None);
+        assert!(!repos.files.is_referenced(sha256).await.unwrap());
+    }
+
+    async fn account_profiles(repos: Repositories, accounts: Accounts) {
+        let alice = repos.users.create("alice".into(), "hash".into(), None).await.unwrap().id;
+        let status = accounts.users.status(alice).await.unwrap().unwrap();
+        assert_eq!((status.role.as_str(), status.disabled, status.must_reset_password), ("user", false, false));
+        assert!(accounts.users.status(999).await.unwrap().is_none());
+        assert_eq!(accounts.users.password_hash(alice).await.unwrap().as_deref(), Some("hash"));
+
+        accounts.users.update_username(alice, "alicia".into()).await.unwrap();
+        accounts.users.update_email(alice, Some("alicia@example.com".into())).await.unwrap();
+        assert!(accounts.users.locale(alice).await.unwrap().is_none());
+        let english = Locale::from_tag("en");
+        accounts.users.update_locale(alice, english).await.unwrap();
+        assert_eq!(accounts.users.locale(alice).await.unwrap(), english);
+        accounts.users.promote("ALICIA".into()).await.unwrap();
+        assert_eq!(accounts.users.status(alice).await.unwrap().unwrap().role, "admin");
+        let found = repos.users.find_by_username("alicia".into()).await.unwrap().unwrap();
+        assert_eq!((found.id, found.email.as_deref()), (alice, Some("alicia@example.com")));
+        assert_eq!(accounts.users.username(alice).await.unwrap().as_deref(), Some("alicia"));
+
+        // Usernames are unique whatever their case
+        let bob = repos.users.create("bob".into(), "hash".into(), None).await.unwrap().id;
+        let taken = accounts.users.update_username(alice, "BOB".into()).await.unwrap_err();
+        assert!(matches!(AppError::from(taken), AppError::Conflict(..)));
+        let taken = repos.users.create("Alicia".into(), "hash".into(), None).await.unwrap_err();
+        assert!(matches!(AppError::from(taken), AppError::Conflict(..)));
+
+        accounts.users.delete(bob).await.unwrap();
+        assert!(repos.users.find_by_username("bob".into()).await.unwrap().is_none());
+    }
+
+    async fn sessions_and_api_tokens(repos: Repositories, accounts: Accounts) {
+        let alice = repos.users.create("alice".into(), "hash".into(), None).await.unwrap().id;
+        let (session, refresh_token) = accounts.sessions.create(alice, Some("Firefox".into())).await.unwrap();
+        let (other, _) = accounts.sessions.create(alice, None).await.unwrap();
+        let (third, _) = accounts.sessions.create(alice, None).await.unwrap();
+        assert!(accounts.sessions.touch(session, alice).await.unwrap());
+        assert!(!accounts.sessions.touch(session, alice + 1).await.unwrap());
+        let listed = accounts.sessions.list(alice, session).await.unwrap();
+        assert_eq!(listed.len(), 3);
+        let current: Vec<_> = listed.iter().filter(|listed| listed.current).collect();
+        assert_eq!((current.len(), current[0].id, current[0].device.as_deref()), (1, session, Some("Firefox")));
+
+        // Each refresh token works once
+        let (rotated, user_id, new_token) = accounts.sessions.rotate(refresh_token.clone()).await.unwrap().unwrap();
+        assert_eq!((rotated, user_id), (session, alice));
+        assert!(accounts.sessions.rotate(refresh_token).await.unwrap().is_none());
+        assert!(accounts.sessions.revoke(alice, other).await.unwrap());
+        assert!(!accounts.sessions.revoke(alice, other).await.unwrap());
+        assert!(!accounts.sessions.touch(other, alice).await.unwrap());
+
+        let scope = TokenScope {
+            access: crate::api_tokens::Access::Read,
+            area: crate::api_tokens::Area::Tasks,
+        };
+        let (token_id, token) = accounts.api_tokens.create(alice, "script".into(), scope).await.unwrap();
+        let (revoked_id, revoked) = accounts.api_tokens.create(alice, "old".into(), scope).await.unwrap();
+        assert_eq!(accounts.api_tokens.find(token.clone()).await.unwrap(), Some((alice, scope)));
+        assert!(accounts.api_tokens.revoke(alice, revoked_id).await.unwrap());
+        assert!(!accounts.api_tokens.revoke(alice, revoked_id).await.unwrap());
+        assert!(accounts.api_tokens.find(revoked).await.unwrap().is_none());
+        let listed = accounts.api_tokens.list(alice).await.unwrap();
+        assert_eq!((listed.len(), listed[0].id, listed[0].name.as_str()), (1, token_id, "script"));
+        assert!(listed[0].last_used_at.is_some());
+
+        // A new password ends every other session and every API token
+        accounts.users.update_password(alice, "new-hash".into(), session).await.unwrap();
+        assert!(accounts.sessions.touch(session, alice).await.unwrap());
+        assert!(!accounts.sessions.touch(third, alice).await.unwrap());
+        assert!(accounts.api_tokens.find(token).await.unwrap().is_none());
+        assert_eq!(accounts.users.password_hash(alice).await.unwrap().as_deref(), Some("new-hash"));
+
+        accounts.sessions.revoke_all(alice).await.unwrap();
+        assert!(accounts.sessions.rotate(new_token).await.unwrap().is_none());
+        assert!(accounts.sessions.list(alice, session).await.unwrap().is_empty());
+    }
+
+    async fn two_factor(repos: Repositories, accounts: Accounts) {
+        let alice = repos.users.create("alice".into(), "hash".into(), None).await.unwrap().id;
+        accounts.two_factor.set_pending_secret(alice, "SECRET".into()).await.unwrap();
+        let state = accounts.two_factor.state(alice).await.unwrap().unwrap();
+        assert_eq!((state.username.as_str(), state.secret.as_deref(), state.enabled), ("alice", Some("SECRET"), false));
+
+        accounts.two_factor.enable(alice, vec!["a".into(), "b".into()], 10).await.unwrap();
+        assert!(accounts.two_factor.state(alice).await.unwrap().unwrap().enabled);
+        assert!(repos.users.find_by_username("alice".into()).await.unwrap().unwrap().totp_enabled);
+        // Steps only move forward
+        assert!(!accounts.two_factor.use_step(alice, 10).await.unwrap());
+        assert!(accounts.two_factor.use_step(alice, 11).await.unwrap());
+        let codes = accounts.two_factor.unused_recovery_codes(alice).await.unwrap();
+        assert_eq!(codes.iter().map(|(_, code_hash)| code_hash.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert!(accounts.two_factor.use_recovery_code(codes[0].0).await.unwrap());
+        assert!(!accounts.two_factor.use_recovery_code(codes[0].0).await.unwrap());
+        assert_eq!(accounts.two_factor.unused_recovery_codes(alice).await.unwrap().len(), 1);
+
+        accounts.two_factor.disable(alice).await.unwrap();
+        let state = accounts.two_factor.state(alice).await.unwrap().unwrap();
+        assert!(!state.enabled && state.secret.is_none());
+        assert!(accounts.two_factor.unused_recovery_codes(alice).await.unwrap().is_empty());
+        assert!(accounts.two_factor.state(999).await.unwrap().is_none());
+    }
+
+    async fn password_resets(repos: Repositories, accounts: Accounts) {
+        let email = Some("alice@example.com".to_string());
+        let alice = repos.users.create("alice".into(), "hash".into(), email).await.unwrap().id;
+        repos.users.create("bob".into(), "hash".into(), None).await.unwrap();
+        let resets = &accounts.password_resets;
+        let recipient = Some((alice, "alice@example.com".to_string(), "alice".to_string()));
+        assert_eq!(resets.find_recipient(Some("ALICE".into()), None).await.unwrap(), recipient);
+        assert_eq!(resets.find_recipient(None, Some("Alice@Example.com".into())).await.unwrap(), recipient);
+        // Without an email there is nowhere to send the token
+        assert!(resets.find_recipient(Some("bob".into()), None).await.unwrap().is_none());
+        assert!(resets.find_recipient(None, None).await.unwrap().is_none());
+
+        // Only the newest token works, and only once
+        let first = resets.create(alice).await.unwrap();
+        let token = resets.create(alice).await.unwrap();
+        assert!(resets.find(first).await.unwrap().is_none());
+        let (token_id, user_id, username) = resets.find(token.clone()).await.unwrap().unwrap();
+        assert_eq!((user_id, username.as_str()), (alice, "alice"));
+        let (session, _) = accounts.sessions.create(alice, None).await.unwrap();
+        assert!(resets.reset_password(token_id, alice, "new-hash".into()).await.unwrap());
+        assert!(!resets.reset_password(token_id, alice, "other-hash".into()).await.unwrap());
+        assert!(resets.find(token).await.unwrap().is_none());
+        assert_eq!(accounts.users.password_hash(alice).await.unwrap().as_deref(), Some("new-hash"));
+        assert!(!accounts.sessions.touch(session, alice).await.unwrap());
+    }
+
+    async fn admin_actions(repos: Repositories, accounts: Accounts) {
+        let (alice, subject) = alice_with_subject(&repos).await;
+        let email = Some("bob@example.com".to_string());
+        let bob = repos.users.create("bob".into(), "hash".into(), email.clone()).await.unwrap().id;
+        repos.tasks.create(bob, "TP".into(), "Pendiente".into(), None).await.unwrap();
+        let bob_subject = repos.subjects.create(bob, "Fisica".into()).await.unwrap();
+        let sha256 = "ef".repeat(32);
+        repos.files.create(bob_subject.id, upload("final.pdf", &sha256)).await.unwrap();
+        let (session, _) = accounts.sessions.create(bob, None).await.unwrap();
+
+        let users = accounts.admin.user_summaries().await.unwrap();
+        assert_eq!(users.iter().map(|user| user.username.as_str()).collect::<Vec<_>>(), ["alice", "bob"]);
+        assert_eq!((users[0].active_session_count, users[1].active_session_count), (0, 1));
+        assert!(users[1].last_seen_at.is_some());
+
+        assert!(accounts.admin.disable(alice, bob).await.unwrap());
+        assert!(accounts.users.status(bob).await.unwrap().unwrap().disabled);
+        assert!(!accounts.sessions.touch(session, bob).await.unwrap());
+        assert!(accounts.admin.enable(alice, bob).await.unwrap());
+        assert!(!accounts.users.status(bob).await.unwrap().unwrap().disabled);
+        let recipient = accounts.admin.require_password_reset(alice, bob).await.unwrap();
+        assert_eq!(recipient, Some(("bob".to_string(), email)));
+        assert!(accounts.users.status(bob).await.unwrap().unwrap().must_reset_password);
+
+        // The account takes everything it owns along
+        assert!(accounts.admin.delete_user(alice, bob).await.unwrap());
+        assert!(!accounts.admin.delete_user(alice, bob).await.unwrap());
+        assert!(!accounts.admin.disable(alice, bob).await.unwrap());
+        assert!(accounts.admin.require_password_reset(alice, bob).await.unwrap().is_none());
+        assert!(repos.tasks.list(bob).await.unwrap().is_empty());
+        assert_eq!(repos.subjects.owner(bob_subject.id).await.unwrap(), None);
+        assert!(!repos.files.is_referenced(sha256).await.unwrap());
+        assert_eq!(repos.subjects.owner(subject.id).await.unwrap(), Some(alice));
+
+        let log = accounts.admin.audit_log(None).await.unwrap();
+        let actions: Vec<_> = log.iter().map(|entry| entry.action.as_str()).collect();
+        assert_eq!(actions, ["delete_user", "force_password_reset", "enable_user", "disable_user"]);
+        let deletion = (log[0].admin_id, log[0].target_user_id, log[0].details.as_deref());
+        assert_eq!(deletion, (alice, Some(bob), Some("bob")));
+        assert_eq!(accounts.admin.audit_log(Some(log[1].id)).await.unwrap().len(), 2);
+    }
+
+    async fn login_attempts(_repos: Repositories, accounts: Accounts) {
+        let attempts = &accounts.login_attempts;
+        let key = || "register:ip:127.0.0.1".to_string();
+        for _ in 0..4 {
+            attempts.record(key(), &REGISTER_PER_IP).await.unwrap();
+        }
+        assert_eq!(attempts.blocked_for(vec![key()]).await.unwrap(), 0);
+        // A refunded attempt does not count towards the lockout
+        attempts.refund(key()).await.unwrap();
+        attempts.record(key(), &REGISTER_PER_IP).await.unwrap();
+        assert_eq!(attempts.blocked_for(vec![key()]).await.unwrap(), 0);
+
+        // The fifth attempt locks the key out, so the next reservation is turned away
+        assert_eq!(attempts.reserve(vec![(key(), &REGISTER_PER_IP)]).await.unwrap(), 0);
+        assert!(attempts.blocked_for(vec!["other".into(), key()]).await.unwrap() > 0);
+        let keys = vec![("other".to_string(), &REGISTER_PER_IP), (key(), &REGISTER_PER_IP)];
+        assert!(attempts.reserve(keys).await.unwrap() > 0);
+
+        attempts.clear(key()).await.unwrap();
+        assert_eq!(attempts.blocked_for(vec![key()]).await.unwrap(), 0);
+        assert_eq!(attempts.reserve(vec![(key(), &REGISTER_PER_IP)]).await.unwrap(), 0);
+    }
+
+    async fn on_sqlite<F: Future<Output = ()>>(check: impl FnOnce(Repositories) -> F) {
+        check(Repositories::sqlite(crate::db::test_pool())).await;
+    }
+
+    async fn accounts_on_sqlite<F: Future<Output = ()>>(check: impl FnOnce(Repositories, Accounts) -> F) {
+        let pool = crate::db::test_pool();
+        check(Repositories::sqlite(pool.clone()), Accounts::sqlite(pool)).await;
+    }
+
+    async fn on_memory<F: Future<Output = ()>>(check: impl FnOnce(Repositories) -> F) {
+        check(Repositories::in_memory()).await;
+    }
+
+    // Needs a local server, e.g. CLASSMATE_TEST_POSTGRES_URL=postgres://postgres@localhost/classmate_test
+    #[cfg(feature = "postgres")]
+    async fn with_postgres<F: Future<Output = ()>>(check: impl FnOnce(crate::repository::postgres::PgPool) -> F) {
+        let url = match std::env::var("CLASSMATE_TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("CLASSMATE_TEST_POSTGRES_URL is not set, skipping the postgres backend test");
+                return;
+            }
+        };
+
+        // Each test gets its own schema, so tests never see each other's rows
+        let schema = format!("classmate_test_{}", crate::sessions::generate_token());
+        let (admin, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls).await.unwrap();
+        actix_web::rt::spawn(connection);
+        admin.batch_execute(&format!("CREATE SCHEMA {}", schema)).await.unwrap();
+        let mut config: tokio_postgres::Config = url.parse().unwrap();
+        config.options(format!("-c search_path={}", schema));
+        let pool = crate::repository::postgres::open_pool_with(config.clone()).await.unwrap();
+
+        check(pool.clone()).await;
+
+        // A second start finds the schema already in place
+        crate::repository::postgres::open_pool_with(config).await.unwrap();
+        pool.close();
+        admin.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema)).await.unwrap();
+    }
+
+    #[cfg(feature = "postgres")]
+    async fn on_postgres<F: Future<Output = ()>>(check: impl FnOnce(Repositories) -> F) {
+        with_postgres(|pool| check(Repositories::postgres(pool))).await;
+    }
+
+    #[cfg(feature = "postgres")]
+    async fn accounts_on_postgres<F: Future<Output = ()>>(check: impl FnOnce(Repositories, Accounts) -> F) {
+        with_postgres(|pool| check(Repositories::postgres(pool.clone()), Accounts::postgres(pool))).await;
+    }
+
+    macro_rules! backend_tests {
+        ($backend:ident, $run:ident) => {
+            mod $backend {
+                use super::$run;
+
+                #[actix_web::test]
+                async fn users() {
+                    $run(super::users).await;
+                }
+
+                #[actix_web::test]
+                async fn tasks() {
+                    $run(super::tasks).await;
+                }
+
+                #[actix_web::test]
+                async fn subjects() {
+                    $run(super::subjects).await;
+                }
+
+                #[actix_web::test]
+                async fn exam_dates() {
+                    $run(super::exam_dates).await;
+                }
+
+                #[actix_web::test]
+                async fn notes() {
+                    $run(super::notes).await;
+                }
+
+                #[actix_web::test]
+                async fn file_links() {
+                    $run(super::file_links).await;
+                }
+
+                #[actix_web::test]
+                async fn stored_files() {
+                    $run(super::stored_files).await;
+                }
+
+                #[actix_web::test]
+                async fn deleting_a_subject_takes_its_rows() {
+                    $run(super::deleting_a_subject_takes_its_rows).await;
+                }
+            }
+        };
+    }
+
+    // Accounts only live in SQLite and Postgres
+    macro_rules! account_tests {
+        ($backend:ident, $run:ident) => {
+            mod $backend {
+                use super::$run;
+
+                #[actix_web::test]
+                async fn account_profiles() {
+                    $run(super::account_profiles).await;
+                }
+
+                #[actix_web::test]
+                async fn sessions_and_api_tokens() {
+                    $run(super::sessions_and_api_tokens).await;
+                }
+
+                #[actix_web::test]
+                async fn two_factor() {
+                    $run(super::two_factor).await;
+                }
+
+                #[actix_web::test]
+                async fn password_resets() {
+                    $run(super::password_resets).await;
+                }
+
+                #[actix_web::test]
+                async fn admin_actions() {
+                    $run(super::admin_actions).await;
+                }
+
+                #[actix_web::test]
+                async fn login_attempts() {
+                    $run(super::login_attempts).await;
+                }
+            }
+        };
+    }
+
+    backend_tests!(sqlite, on_sqlite);
+    backend_tests!(memory, on_memory);
+    #[cfg(feature = "postgres")]
+    backend_tests!(postgres, on_postgres);
+    account_tests!(sqlite_accounts, accounts_on_sqlite);
+    #[cfg(feature = "postgres")]
+    account_tests!(postgres_accounts, accounts_on_postgres);
+}
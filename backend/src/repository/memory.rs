@@ -0,0 +1,398 @@
+use std::sync::Mutex;
+
+use super::{
+    ExamDate, ExamDateChanges, ExamDateRepository, FileLink, FileLinkChanges, FileLinkRepository, NewExamDate,
+    NewFileLink, NewStoredFile, Note, NoteChanges, NoteRepository, NoteRevision, RepoError, RepoFuture, StoredFile,
+    StoredFileRepository, Subject, SubjectChanges, SubjectRepository, Task, TaskRepository, User, UserRepository,
+};
+use crate::auth::now_secs;
+
+// Keeps every table in a Vec, for tests and for running without a database
+#[derive(Default)]
+pub struct MemoryRepository {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    last_id: i32,
+    users: Vec<User>,
+    tasks: Vec<Task>,
+    subjects: Vec<Subject>,
+    exam_dates: Vec<ExamDate>,
+    notes: Vec<Note>,
+    note_revisions: Vec<NoteRevision>,
+    file_links: Vec<FileLink>,
+    stored_files: Vec<StoredFile>,
+}
+
+impl State {
+    fn next_id(&mut self) -> i32 {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    fn subject_owner(&self, subject_id: i32) -> Option<i32> {
+        self.subjects
+            .iter()
+            .find(|subject| subject.id == subject_id)
+            .map(|subject| subject.user_id)
+    }
+
+    // Drops the matching subjects together with their exam dates, notes, file links and uploaded files
+    fn remove_subjects(&mut self, matches: impl Fn(&Subject) -> bool) -> bool {
+        let removed: Vec<i32> = self.subjects.iter().filter(|subject| matches(subject)).map(|subject| subject.id).collect();
+        self.subjects.retain(|subject| !removed.contains(&subject.id));
+        self.exam_dates.retain(|exam_date| !removed.contains(&exam_date.subject_id));
+        self.remove_notes(|note| removed.contains(&note.subject_id));
+        self.file_links.retain(|file_link| !removed.contains(&file_link.subject_id));
+        self.stored_files.retain(|file| !removed.contains(&file.subject_id));
+        !removed.is_empty()
+    }
+
+    // Drops the matching notes together with their revisions
+    fn remove_notes(&mut self, matches: impl Fn(&Note) -> bool) -> bool {
+        let removed: Vec<i32> = self.notes.iter().filter(|note| matches(note)).map(|note| note.id).collect();
+        self.notes.retain(|note| !removed.contains(&note.id));
+        self.note_revisions.retain(|revision| !removed.contains(&revision.note_id));
+        !removed.is_empty()
+    }
+}
+
+// Like retain, but with the opposite test and telling whether anything went
+fn remove_where<T>(rows: &mut Vec<T>, matches: impl Fn(&T) -> bool) -> bool {
+    let before = rows.len();
+    rows.retain(|row| !matches(row));
+    rows.len() != before
+}
+
+impl MemoryRepository {
+    fn with_state<T, F>(&self, f: F) -> RepoFuture<T>
+    where
+        F: FnOnce(&mut State) -> T,
+        T: 'static,
+    {
+        let result = self.state.lock().map(|mut state| f(&mut state)).map_err(|_| RepoError::Poisoned);
+        Box::pin(std::future::ready(result))
+    }
+}
+
+impl UserRepository for MemoryRepository {
+    fn create(&self, username: String, password_hash: String, email: Option<String>) -> RepoFuture<User> {
+        self.with_state(move |state| {
+            let user = User {
+                id: state.next_id(),
+                username,
+                password_hash,
+                email,
+                totp_enabled: false,
+            };
+            state.users.push(user.clone());
+            user
+        })
+    }
+
+    fn find_by_username(&self, username: String) -> RepoFuture<Option<User>> {
+        self.with_state(move |state| {
+            state
+                .users
+                .iter()
+                .find(|user| user.username.eq_ignore_ascii_case(&username))
+                .cloned()
+        })
+    }
+}
+
+impl TaskRepository for MemoryRepository {
+    fn list(&self, user_id: i32) -> RepoFuture<Vec<Task>> {
+        self.with_state(move |state| state.tasks.iter().filter(|task| task.user_id == user_id).cloned().collect())
+    }
+
+    fn create(&self, user_id: i32, title: String, status: String, note: Option<String>) -> RepoFuture<Task> {
+        self.with_state(move |state| {
+            let task = Task {
+                id: state.next_id(),
+                title,
+                status,
+                note: Some(note.unwrap_or_default()),
+                user_id,
+            };
+            state.tasks.push(task.clone());
+            task
+        })
+    }
+
+    fn owner(&self, task_id: i32) -> RepoFuture<Option<i32>> {
+        self.with_state(move |state| state.tasks.iter().find(|task| task.id == task_id).map(|task| task.user_id))
+    }
+
+    fn update_status(&self, task_id: i32, status: String) -> RepoFuture<bool> {
+        self.with_state(move |state| match state.tasks.iter_mut().find(|task| task.id == task_id) {
+            Some(task) => {
+                task.status = status;
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn update_note(&self, task_id: i32, note: String) -> RepoFuture<bool> {
+        self.with_state(move |state| match state.tasks.iter_mut().find(|task| task.id == task_id) {
+            Some(task) => {
+                task.note = Some(note);
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn delete(&self, task_id: i32) -> RepoFuture<bool> {
+        self.with_state(move |state| remove_where(&mut state.tasks, |task| task.id == task_id))
+    }
+
+    fn count_for_user(&self, user_id: i32) -> RepoFuture<i64> {
+        self.with_state(move |state| state.tasks.iter().filter(|task| task.user_id == user_id).count() as i64)
+    }
+}
+
+impl SubjectRepository for MemoryRepository {
+    fn list(&self, user_id: i32, include_archived: bool) -> RepoFuture<Vec<Subject>> {
+        self.with_state(move |state| {
+            let mut subjects: Vec<Subject> = state
+                .subjects
+                .iter()
+                .filter(|subject| subject.user_id == user_id && (include_archived || !subject.archived))
+                .cloned()
+                .collect();
+            subjects.sort_by_key(|subject| (subject.display_order, subject.id));
+            subjects
+        })
+    }
+
+    fn create(&self, user_id: i32, name: String) -> RepoFuture<Subject> {
+        self.with_state(move |state| {
+            let subject = Subject::new(state.next_id(), name, user_id);
+            state.subjects.push(subject.clone());
+            subject
+        })
+    }
+
+    fn owner(&self, subject_id: i32) -> RepoFuture<Option<i32>> {
+        self.with_state(move |state| state.subject_owner(subject_id))
+    }
+
+    fn update(&self, subject_id: i32, changes: SubjectChanges) -> RepoFuture<Option<Subject>> {
+        self.with_state(move |state| {
+            let subject = state.subjects.iter_mut().find(|subject| subject.id == subject_id)?;
+            changes.apply(subject);
+            Some(subject.clone())
+        })
+    }
+
+    fn delete(&self, subject_id: i32) -> RepoFuture<bool> {
+        self.with_state(move |state| state.remove_subjects(|subject| subject.id == subject_id))
+    }
+
+    fn count_for_user(&self, user_id: i32) -> RepoFuture<i64> {
+        self.with_state(move |state| state.subjects.iter().filter(|subject| subject.user_id == user_id).count() as i64)
+    }
+}
+
+impl ExamDateRepository for MemoryRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<ExamDate>> {
+        self.with_state(move |state| {
+            let mut exam_dates: Vec<ExamDate> = state
+                .exam_dates
+                .iter()
+                .filter(|exam_date| exam_date.subject_id == subject_id)
+                .cloned()
+                .collect();
+            exam_dates.sort_by(|a, b| (&a.date, a.id).cmp(&(&b.date, b.id)));
+            exam_dates
+        })
+    }
+
+    fn create(&self, subject_id: i32, exam: NewExamDate) -> RepoFuture<ExamDate> {
+        self.with_state(move |state| {
+            let exam_date = ExamDate::new(state.next_id(), subject_id, exam);
+            state.exam_dates.push(exam_date.clone());
+            exam_date
+        })
+    }
+
+    fn owner(&self, exam_date_id: i32) -> RepoFuture<Option<i32>> {
+        self.with_state(move |state| {
+            let exam_date = state.exam_dates.iter().find(|exam_date| exam_date.id == exam_date_id)?;
+            state.subject_owner(exam_date.subject_id)
+        })
+    }
+
+    fn update(&self, exam_date_id: i32, changes: ExamDateChanges) -> RepoFuture<Option<ExamDate>> {
+        self.with_state(move |state| {
+            let exam_date = state.exam_dates.iter_mut().find(|exam_date| exam_date.id == exam_date_id)?;
+            changes.apply(exam_date);
+            Some(exam_date.clone())
+        })
+    }
+
+    fn delete(&self, exam_date_id: i32) -> RepoFuture<bool> {
+        self.with_state(move |state| remove_where(&mut state.exam_dates, |exam_date| exam_date.id == exam_date_id))
+    }
+}
+
+impl NoteRepository for MemoryRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<Note>> {
+        self.with_state(move |state| state.notes.iter().filter(|note| note.subject_id == subject_id).cloned().collect())
+    }
+
+    fn find(&self, note_id: i32) -> RepoFuture<Option<Note>> {
+        self.with_state(move |state| state.notes.iter().find(|note| note.id == note_id).cloned())
+    }
+
+    fn create(&self, subject_id: i32, title: Option<String>, content: String) -> RepoFuture<Note> {
+        self.with_state(move |state| {
+            let note = Note::new(state.next_id(), subject_id, title, content, now_secs());
+            state.notes.push(note.clone());
+            note
+        })
+    }
+
+    fn owner(&self, note_id: i32) -> RepoFuture<Option<i32>> {
+        self.with_state(move |state| {
+            let note = state.notes.iter().find(|note| note.id == note_id)?;
+            state.subject_owner(note.subject_id)
+        })
+    }
+
+    fn update(&self, note_id: i32, changes: NoteChanges) -> RepoFuture<Option<Note>> {
+        self.with_state(move |state| {
+            let note = state.notes.iter_mut().find(|note| note.id == note_id)?;
+            let previous = note.clone();
+            if !changes.apply(note, now_secs()) {
+                return Some(previous);
+            }
+            let updated = note.clone();
+            let revision = NoteRevision {
+                id: state.next_id(),
+                note_id,
+                title: previous.title,
+                content: previous.content,
+                created_at: previous.updated_at,
+            };
+            state.note_revisions.push(revision);
+            Some(updated)
+        })
+    }
+
+    fn revisions(&self, note_id: i32) -> RepoFuture<Vec<NoteRevision>> {
+        self.with_state(move |state| {
+            let mut revisions: Vec<NoteRevision> =
+                state.note_revisions.iter().filter(|revision| revision.note_id == note_id).cloned().collect();
+            revisions.sort_by_key(|revision| std::cmp::Reverse(revision.id));
+            revisions
+        })
+    }
+
+    fn find_revision(&self, note_id: i32, revision_id: i32) -> RepoFuture<Option<NoteRevision>> {
+        self.with_state(move |state| {
+            state
+                .note_revisions
+                .iter()
+                .find(|revision| revision.id == revision_id && revision.note_id == note_id)
+                .cloned()
+        })
+    }
+
+    fn delete(&self, note_id: i32) -> RepoFuture<bool> {
+        self.with_state(move |state| state.remove_notes(|note| note.id == note_id))
+    }
+}
+
+impl FileLinkRepository for MemoryRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<FileLink>> {
+        self.with_state(move |state| {
+            state
+                .file_links
+                .iter()
+                .filter(|file_link| file_link.subject_id == subject_id)
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn create(&self, subject_id: i32, link: NewFileLink) -> RepoFuture<FileLink> {
+        self.with_state(move |state| {
+            let file_link = FileLink::new(state.next_id(), subject_id, link, now_secs());
+            state.file_links.push(file_link.clone());
+            file_link
+        })
+    }
+
+    fn owner(&self, file_link_id: i32) -> RepoFuture<Option<i32>> {
+        self.with_state(move |state| {
+            let file_link = state.file_links.iter().find(|file_link| file_link.id == file_link_id)?;
+            state.subject_owner(file_link.subject_id)
+        })
+    }
+
+    fn update(&self, file_link_id: i32, changes: FileLinkChanges) -> RepoFuture<Option<FileLink>> {
+        self.with_state(move |state| {
+            let file_link = state.file_links.iter_mut().find(|file_link| file_link.id == file_link_id)?;
+            changes.apply(file_link);
+            Some(file_link.clone())
+        })
+    }
+
+    fn delete(&self, file_link_id: i32) -> RepoFuture<bool> {
+        self.with_state(move |state| remove_where(&mut state.file_links, |file_link| file_link.id == file_link_id))
+    }
+}
+
+impl StoredFileRepository for MemoryRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<StoredFile>> {
+        self.with_state(move |state| {
+            state.stored_files.iter().filter(|file| file.subject_id == subject_id).cloned().collect()
+        })
+    }
+
+    fn find(&self, file_id: i32) -> RepoFuture<Option<StoredFile>> {
+        self.with_state(move |state| state.stored_files.iter().find(|file| file.id == file_id).cloned())
+    }
+
+    fn create(&self, subject_id: i32, file: NewStoredFile) -> RepoFuture<StoredFile> {
+        self.with_state(move |state| {
+            let file = StoredFile::new(state.next_id(), subject_id, file, now_secs());
+            state.stored_files.push(file.clone());
+            file
+        })
+    }
+
+    fn owner(&self, file_id: i32) -> RepoFuture<Option<i32>> {
+        self.with_state(move |state| {
+            let file = state.stored_files.iter().find(|file| file.id == file_id)?;
+            state.subject_owner(file.subject_id)
+        })
+    }
+
+    fn delete(&self, file_id: i32) -> RepoFuture<bool> {
+        self.with_state(move |state| remove_where(&mut state.stored_files, |file| file.id == file_id))
+    }
+
+    fn hashes_for_user(&self, user_id: i32) -> RepoFuture<Vec<String>> {
+        self.with_state(move |state| {
+            let mut hashes: Vec<String> = state
+                .stored_files
+                .iter()
+                .filter(|file| state.subject_owner(file.subject_id) == Some(user_id))
+                .map(|file| file.sha256.clone())
+                .collect();
+            hashes.sort();
+            hashes.dedup();
+            hashes
+        })
+    }
+
+    fn is_referenced(&self, sha256: String) -> RepoFuture<bool> {
+        self.with_state(move |state| state.stored_files.iter().any(|file| file.sha256 == sha256))
+    }
+}
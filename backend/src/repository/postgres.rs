@@ -0,0 +1,1715 @@
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio_postgres::config::{Host, SslMode};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Config, Row, Transaction};
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use super::{
+    AccountRepository, AccountStatus, AdminRepository, ApiTokenRepository, ExamDate, ExamDateChanges,
+    ExamDateRepository, ExamKind, FileLink, FileLinkChanges, FileLinkRepository, LinkKind, LoginAttemptRepository,
+    NewExamDate, NewFileLink, NewStoredFile, Note, NoteChanges, NoteRepository, NoteRevision, PasswordResetRepository,
+    RepoError, RepoFuture, SessionRepository, StoredFile, StoredFileRepository, Subject, SubjectChanges,
+    SubjectRepository, Task, TaskRepository, TwoFactorRepository, User, UserRepository,
+};
+use crate::admin::{AuditEntry, UserSummary, AUDIT_LOG_PAGE_SIZE, ROLE_ADMIN};
+use crate::api_tokens::{new_api_token, Access, ApiToken, Area, TokenScope, LAST_USED_RESOLUTION_SECS};
+use crate::auth::now_secs;
+use crate::i18n::Locale;
+use crate::password_reset::RESET_TOKEN_TTL_SECS;
+use crate::rate_limit::{count_attempt, Policy};
+use crate::sessions::{generate_token, hash_token, Session, LAST_SEEN_RESOLUTION_SECS, REFRESH_TOKEN_TTL_SECS};
+use crate::two_factor::TotpState;
+
+pub type PgPool = Pool;
+
+const POOL_SIZE: usize = 16;
+
+const SUBJECT_COLUMNS: &str = "id, name, user_id, color, icon, display_order, archived, professor, course_code";
+const EXAM_DATE_COLUMNS: &str = "id, subject_id, date, duration_minutes, kind, location, notes, weight";
+const NOTE_COLUMNS: &str = "id, subject_id, title, content, created_at, updated_at";
+const NOTE_REVISION_COLUMNS: &str = "id, note_id, title, content, created_at";
+const FILE_LINK_COLUMNS: &str = "id, subject_id, url, label, kind, created_at";
+const STORED_FILE_COLUMNS: &str = "id, subject_id, filename, mime_type, size, sha256, created_at";
+
+// Same tables, columns and foreign keys as the SQLite schema, so deleting a user or a subject takes
+// everything hanging from it along through ON DELETE CASCADE here as well
+const MIGRATIONS: &[(i32, &str, &str)] = &[
+    (
+        1,
+        "baseline",
+        "CREATE TABLE tasks (
+             id SERIAL PRIMARY KEY,
+             title TEXT NOT NULL,
+             status TEXT NOT NULL,
+             note TEXT,
+             user_id INTEGER NOT NULL
+         );
+         CREATE TABLE subjects (
+             id SERIAL PRIMARY KEY,
+             name TEXT NOT NULL,
+             user_id INTEGER NOT NULL
+         );
+         CREATE TABLE exam_dates (
+             id SERIAL PRIMARY KEY,
+             subject_id INTEGER NOT NULL REFERENCES subjects(id) ON DELETE CASCADE,
+             date TEXT NOT NULL
+         );
+         CREATE TABLE notes (
+             id SERIAL PRIMARY KEY,
+             subject_id INTEGER NOT NULL REFERENCES subjects(id) ON DELETE CASCADE,
+             content TEXT NOT NULL
+         );
+         CREATE TABLE file_links (
+             id SERIAL PRIMARY KEY,
+             subject_id INTEGER NOT NULL REFERENCES subjects(id) ON DELETE CASCADE,
+             url TEXT NOT NULL
+         );
+         CREATE INDEX tasks_user_id ON tasks (user_id);
+         CREATE INDEX subjects_user_id ON subjects (user_id);
+         CREATE INDEX exam_dates_subject_id ON exam_dates (subject_id);
+         CREATE INDEX notes_subject_id ON notes (subject_id);
+         CREATE INDEX file_links_subject_id ON file_links (subject_id);",
+    ),
+    (
+        2,
+        "subject_details",
+        "ALTER TABLE subjects
+             ADD COLUMN color TEXT,
+             ADD COLUMN icon TEXT,
+             ADD COLUMN display_order INTEGER NOT NULL DEFAULT 0,
+             ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE,
+             ADD COLUMN professor TEXT,
+             ADD COLUMN course_code TEXT;",
+    ),
+    (
+        3,
+        "exam_details",
+        "ALTER TABLE exam_dates
+             ADD COLUMN duration_minutes INTEGER,
+             ADD COLUMN kind TEXT NOT NULL DEFAULT 'parcial',
+             ADD COLUMN location TEXT,
+             ADD COLUMN notes TEXT,
+             ADD COLUMN weight DOUBLE PRECISION;
+         UPDATE exam_dates SET date = date || 'T00:00:00' WHERE date ~ '^\\d{4}-\\d{2}-\\d{2}$';",
+    ),
+    (
+        4,
+        "note_history",
+        "ALTER TABLE notes
+             ADD COLUMN title TEXT,
+             ADD COLUMN created_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM now())::BIGINT,
+             ADD COLUMN updated_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM now())::BIGINT;
+         CREATE TABLE note_revisions (
+             id SERIAL PRIMARY KEY,
+             note_id INTEGER NOT NULL REFERENCES notes(id) ON DELETE CASCADE,
+             title TEXT,
+             content TEXT NOT NULL,
+             created_at BIGINT NOT NULL
+         );
+         CREATE INDEX note_revisions_note_id ON note_revisions (note_id);",
+    ),
+    (
+        5,
+        "file_link_details",
+        "ALTER TABLE file_links
+             ADD COLUMN label TEXT,
+             ADD COLUMN kind TEXT,
+             ADD COLUMN created_at BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM now())::BIGINT;",
+    ),
+    (
+        6,
+        "stored_files",
+        "CREATE TABLE stored_files (
+             id SERIAL PRIMARY KEY,
+             subject_id INTEGER NOT NULL REFERENCES subjects(id) ON DELETE CASCADE,
+             filename TEXT NOT NULL,
+             mime_type TEXT NOT NULL,
+             size BIGINT NOT NULL,
+             sha256 TEXT NOT NULL,
+             created_at BIGINT NOT NULL
+         );
+         CREATE INDEX stored_files_subject_id ON stored_files (subject_id);
+         CREATE INDEX stored_files_sha256 ON stored_files (sha256);",
+    ),
+    (
+        7,
+        "accounts",
+        "CREATE TABLE users (
+             id SERIAL PRIMARY KEY,
+             username TEXT NOT NULL UNIQUE,
+             password_hash TEXT NOT NULL,
+             email TEXT,
+             totp_secret TEXT,
+             totp_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+             totp_last_step BIGINT,
+             role TEXT NOT NULL DEFAULT 'user',
+             disabled BOOLEAN NOT NULL DEFAULT FALSE,
+             must_reset_password BOOLEAN NOT NULL DEFAULT FALSE,
+             language TEXT
+         );
+         CREATE UNIQUE INDEX users_username_nocase ON users (lower(username));
+         CREATE TABLE sessions (
+             id BIGSERIAL PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             refresh_token_hash TEXT NOT NULL UNIQUE,
+             device TEXT,
+             created_at BIGINT NOT NULL,
+             last_seen_at BIGINT NOT NULL,
+             expires_at BIGINT NOT NULL,
+             revoked_at BIGINT
+         );
+         CREATE TABLE login_attempts (
+             key TEXT PRIMARY KEY,
+             attempts BIGINT NOT NULL,
+             last_attempt_at BIGINT NOT NULL,
+             blocked_until BIGINT NOT NULL
+         );
+         CREATE TABLE recovery_codes (
+             id BIGSERIAL PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             code_hash TEXT NOT NULL,
+             used_at BIGINT
+         );
+         CREATE TABLE password_reset_tokens (
+             id BIGSERIAL PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             token_hash TEXT NOT NULL UNIQUE,
+             created_at BIGINT NOT NULL,
+             expires_at BIGINT NOT NULL,
+             used_at BIGINT
+         );
+         CREATE TABLE audit_log (
+             id BIGSERIAL PRIMARY KEY,
+             admin_id INTEGER NOT NULL,
+             action TEXT NOT NULL,
+             target_user_id INTEGER,
+             details TEXT,
+             created_at BIGINT NOT NULL
+         );
+         CREATE TABLE api_tokens (
+             id BIGSERIAL PRIMARY KEY,
+             user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+             name TEXT NOT NULL,
+             token_hash TEXT NOT NULL UNIQUE,
+             access TEXT NOT NULL,
+             area TEXT NOT NULL,
+             created_at BIGINT NOT NULL,
+             last_used_at BIGINT,
+             revoked_at BIGINT
+         );
+         CREATE INDEX sessions_user_id ON sessions (user_id);
+         CREATE INDEX recovery_codes_user_id ON recovery_codes (user_id);
+         CREATE INDEX password_reset_tokens_user_id ON password_reset_tokens (user_id);
+         CREATE INDEX api_tokens_user_id ON api_tokens (user_id);
+         ALTER TABLE tasks ADD FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;
+         ALTER TABLE subjects ADD FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE;",
+    ),
+];
+
+// Connect to DATABASE_URL and bring its schema up to date
+pub async fn open_pool(url: &str) -> Result<PgPool, RepoError> {
+    open_pool_with(url.parse()?).await
+}
+
+// Reaching a server on another machine without sslmode=require would send the password and every
+// row in the clear, so the configuration is rejected. The local socket and loopback are exempt.
+pub fn check_postgres_tls(config: &Config) -> Result<(), String> {
+    if config.get_ssl_mode() == SslMode::Require {
+        return Ok(());
+    }
+    let remote = config.get_hosts().iter().any(|host| match host {
+        Host::Tcp(name) => name != "localhost" && !name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback()),
+        #[cfg(unix)]
+        Host::Unix(_) => false,
+    });
+    if remote {
+        return Err("DATABASE_URL must set sslmode=require to reach a server on another machine".to_string());
+    }
+    Ok(())
+}
+
+// Used whenever the server offers TLS, and insisted on with sslmode=require; the server's
+// certificate has to chain to one of the Mozilla roots
+fn tls() -> MakeRustlsConnect {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("The ring provider supports the default TLS versions.")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    MakeRustlsConnect::new(config)
+}
+
+pub async fn open_pool_with(config: Config) -> Result<PgPool, RepoError> {
+    let manager = Manager::from_config(
+        config,
+        tls(),
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    // Building only fails for timeouts without a runtime, and none are set
+    let pool = Pool::builder(manager).max_size(POOL_SIZE).build().expect("Invalid postgres pool settings.");
+    migrate(&pool).await?;
+    Ok(pool)
+}
+
+async fn migrate(pool: &PgPool) -> Result<(), RepoError> {
+    let mut client = pool.get().await?;
+    let tx = client.transaction().await?;
+    // Several instances may start at once; only one of them applies the migrations
+    tx.execute("SELECT pg_advisory_xact_lock(7283540)", &[]).await?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+             version INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             applied_at BIGINT NOT NULL
+         )",
+    )
+    .await?;
+    let current: i32 = tx.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[]).await?.get(0);
+    for (version, name, sql) in MIGRATIONS.iter().filter(|(version, _, _)| *version > current) {
+        tx.batch_execute(sql).await?;
+        tx.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES ($1, $2, $3)",
+            &[version, name, &now_secs()],
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub struct PostgresRepository {
+    pool: PgPool,
+}
+
+impl PostgresRepository {
+    pub fn new(pool: PgPool) -> Self {
+        PostgresRepository { pool }
+    }
+
+    // Whether the statement touched any row
+    async fn execute(pool: PgPool, sql: &'static str, id: i32) -> Result<bool, RepoError> {
+        Ok(pool.get().await?.execute(sql, &[&id]).await? > 0)
+    }
+
+    async fn count(pool: PgPool, sql: &'static str, id: i32) -> Result<i64, RepoError> {
+        Ok(pool.get().await?.query_one(sql, &[&id]).await?.try_get(0)?)
+    }
+
+    // Id of the row an INSERT ... RETURNING id just created
+    async fn insert(pool: PgPool, sql: &'static str, params: &[&(dyn ToSql + Sync)]) -> Result<i32, RepoError> {
+        Ok(pool.get().await?.query_one(sql, params).await?.try_get(0)?)
+    }
+
+    // The owning user of a row, or None when it does not exist
+    async fn find_owner(pool: PgPool, sql: &'static str, id: i32) -> Result<Option<i32>, RepoError> {
+        let row = pool.get().await?.query_opt(sql, &[&id]).await?;
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+}
+
+impl UserRepository for PostgresRepository {
+    fn create(&self, username: String, password_hash: String, email: Option<String>) -> RepoFuture<User> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let id = Self::insert(
+                pool,
+                "INSERT INTO users (username, password_hash, email) VALUES ($1, $2, $3) RETURNING id",
+                &[&username, &password_hash, &email],
+            )
+            .await?;
+            Ok(User {
+                id,
+                username,
+                password_hash,
+                email,
+                totp_enabled: false,
+            })
+        })
+    }
+
+    fn find_by_username(&self, username: String) -> RepoFuture<Option<User>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let row = pool
+                .get()
+                .await?
+                .query_opt(
+                    "SELECT id, username, password_hash, email, totp_enabled FROM users
+                     WHERE lower(username) = lower($1)",
+                    &[&username],
+                )
+                .await?;
+            row.map(|row| {
+                Ok(User {
+                    id: row.try_get(0)?,
+                    username: row.try_get(1)?,
+                    password_hash: row.try_get(2)?,
+                    email: row.try_get(3)?,
+                    totp_enabled: row.try_get(4)?,
+                })
+            })
+            .transpose()
+        })
+    }
+}
+
+impl TaskRepository for PostgresRepository {
+    fn list(&self, user_id: i32) -> RepoFuture<Vec<Task>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let rows = pool
+                .get()
+                .await?
+                .query("SELECT id, title, status, note, user_id FROM tasks WHERE user_id = $1", &[&user_id])
+                .await?;
+            rows.iter()
+                .map(|row| {
+                    Ok(Task {
+                        id: row.try_get(0)?,
+                        title: row.try_get(1)?,
+                        status: row.try_get(2)?,
+                        note: row.try_get(3)?,
+                        user_id: row.try_get(4)?,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn create(&self, user_id: i32, title: String, status: String, note: Option<String>) -> RepoFuture<Task> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let note = note.unwrap_or_default();
+            let id = Self::insert(
+                pool,
+                "INSERT INTO tasks (title, status, note, user_id) VALUES ($1, $2, $3, $4) RETURNING id",
+                &[&title, &status, &note, &user_id],
+            )
+            .await?;
+            Ok(Task {
+                id,
+                title,
+                status,
+                note: Some(note),
+                user_id,
+            })
+        })
+    }
+
+    fn owner(&self, task_id: i32) -> RepoFuture<Option<i32>> {
+        Box::pin(Self::find_owner(self.pool.clone(), "SELECT user_id FROM tasks WHERE id = $1", task_id))
+    }
+
+    fn update_status(&self, task_id: i32, status: String) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let updated = pool
+                .get()
+                .await?
+                .execute("UPDATE tasks SET status = $1 WHERE id = $2", &[&status, &task_id])
+                .await?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn update_note(&self, task_id: i32, note: String) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let updated = pool
+                .get()
+                .await?
+                .execute("UPDATE tasks SET note = $1 WHERE id = $2", &[&note, &task_id])
+                .await?;
+            Ok(updated > 0)
+        })
+    }
+
+    fn delete(&self, task_id: i32) -> RepoFuture<bool> {
+        Box::pin(Self::execute(self.pool.clone(), "DELETE FROM tasks WHERE id = $1", task_id))
+    }
+
+    fn count_for_user(&self, user_id: i32) -> RepoFuture<i64> {
+        Box::pin(Self::count(self.pool.clone(), "SELECT COUNT(*) FROM tasks WHERE user_id = $1", user_id))
+    }
+}
+
+fn subject_from_row(row: &Row) -> Result<Subject, RepoError> {
+    Ok(Subject {
+        id: row.try_get(0)?,
+        name: row.try_get(1)?,
+        user_id: row.try_get(2)?,
+        color: row.try_get(3)?,
+        icon: row.try_get(4)?,
+        display_order: row.try_get(5)?,
+        archived: row.try_get(6)?,
+        professor: row.try_get(7)?,
+        course_code: row.try_get(8)?,
+    })
+}
+
+impl SubjectRepository for PostgresRepository {
+    fn list(&self, user_id: i32, include_archived: bool) -> RepoFuture<Vec<Subject>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!(
+                "SELECT {} FROM subjects WHERE user_id = $1 AND ($2 OR NOT archived) ORDER BY display_order, id",
+                SUBJECT_COLUMNS
+            );
+            let rows = pool.get().await?.query(&sql, &[&user_id, &include_archived]).await?;
+            rows.iter().map(subject_from_row).collect()
+        })
+    }
+
+    fn create(&self, user_id: i32, name: String) -> RepoFuture<Subject> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let id = Self::insert(
+                pool,
+                "INSERT INTO subjects (name, user_id) VALUES ($1, $2) RETURNING id",
+                &[&name, &user_id],
+            )
+            .await?;
+            Ok(Subject::new(id, name, user_id))
+        })
+    }
+
+    fn owner(&self, subject_id: i32) -> RepoFuture<Option<i32>> {
+        Box::pin(Self::find_owner(self.pool.clone(), "SELECT user_id FROM subjects WHERE id = $1", subject_id))
+    }
+
+    fn update(&self, subject_id: i32, changes: SubjectChanges) -> RepoFuture<Option<Subject>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            let sql = format!("SELECT {} FROM subjects WHERE id = $1 FOR UPDATE", SUBJECT_COLUMNS);
+            let mut subject = match tx.query_opt(&sql, &[&subject_id]).await? {
+                Some(row) => subject_from_row(&row)?,
+                None => return Ok(None),
+            };
+            changes.apply(&mut subject);
+            tx.execute(
+                "UPDATE subjects SET name = $1, color = $2, icon = $3, display_order = $4, archived = $5,
+                     professor = $6, course_code = $7
+                 WHERE id = $8",
+                &[
+                    &subject.name,
+                    &subject.color,
+                    &subject.icon,
+                    &subject.display_order,
+                    &subject.archived,
+                    &subject.professor,
+                    &subject.course_code,
+                    &subject_id,
+                ],
+            )
+            .await?;
+            tx.commit().await?;
+            Ok(Some(subject))
+        })
+    }
+
+    fn delete(&self, subject_id: i32) -> RepoFuture<bool> {
+        // Exam dates, notes and file links go with it through ON DELETE CASCADE
+        Box::pin(Self::execute(self.pool.clone(), "DELETE FROM subjects WHERE id = $1", subject_id))
+    }
+
+    fn count_for_user(&self, user_id: i32) -> RepoFuture<i64> {
+        Box::pin(Self::count(self.pool.clone(), "SELECT COUNT(*) FROM subjects WHERE user_id = $1", user_id))
+    }
+}
+
+fn exam_date_from_row(row: &Row) -> Result<ExamDate, RepoError> {
+    Ok(ExamDate {
+        id: row.try_get(0)?,
+        subject_id: row.try_get(1)?,
+        date: row.try_get(2)?,
+        duration_minutes: row.try_get(3)?,
+        // Every exam date was a midterm before there were kinds
+        kind: ExamKind::parse(row.try_get(4)?).unwrap_or(ExamKind::Parcial),
+        location: row.try_get(5)?,
+        notes: row.try_get(6)?,
+        weight: row.try_get(7)?,
+    })
+}
+
+impl ExamDateRepository for PostgresRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<ExamDate>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!(
+                "SELECT {} FROM exam_dates WHERE subject_id = $1 ORDER BY date, id",
+                EXAM_DATE_COLUMNS
+            );
+            let rows = pool.get().await?.query(&sql, &[&subject_id]).await?;
+            rows.iter().map(exam_date_from_row).collect()
+        })
+    }
+
+    fn create(&self, subject_id: i32, exam: NewExamDate) -> RepoFuture<ExamDate> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let id = Self::insert(
+                pool,
+                "INSERT INTO exam_dates (subject_id, date, duration_minutes, kind, location, notes, weight)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+                &[
+                    &subject_id,
+                    &exam.date,
+                    &exam.duration_minutes,
+                    &exam.kind.as_str(),
+                    &exam.location,
+                    &exam.notes,
+                    &exam.weight,
+                ],
+            )
+            .await?;
+            Ok(ExamDate::new(id, subject_id, exam))
+        })
+    }
+
+    fn owner(&self, exam_date_id: i32) -> RepoFuture<Option<i32>> {
+        Box::pin(Self::find_owner(
+            self.pool.clone(),
+            "SELECT subjects.user_id FROM exam_dates
+             JOIN subjects ON subjects.id = exam_dates.subject_id
+             WHERE exam_dates.id = $1",
+            exam_date_id,
+        ))
+    }
+
+    fn update(&self, exam_date_id: i32, changes: ExamDateChanges) -> RepoFuture<Option<ExamDate>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            let sql = format!("SELECT {} FROM exam_dates WHERE id = $1 FOR UPDATE", EXAM_DATE_COLUMNS);
+            let mut exam_date = match tx.query_opt(&sql, &[&exam_date_id]).await? {
+                Some(row) => exam_date_from_row(&row)?,
+                None => return Ok(None),
+            };
+            changes.apply(&mut exam_date);
+            tx.execute(
+                "UPDATE exam_dates SET date = $1, duration_minutes = $2, kind = $3, location = $4, notes = $5,
+                     weight = $6
+                 WHERE id = $7",
+                &[
+                    &exam_date.date,
+                    &exam_date.duration_minutes,
+                    &exam_date.kind.as_str(),
+                    &exam_date.location,
+                    &exam_date.notes,
+                    &exam_date.weight,
+                    &exam_date_id,
+                ],
+            )
+            .await?;
+            tx.commit().await?;
+            Ok(Some(exam_date))
+        })
+    }
+
+    fn delete(&self, exam_date_id: i32) -> RepoFuture<bool> {
+        Box::pin(Self::execute(self.pool.clone(), "DELETE FROM exam_dates WHERE id = $1", exam_date_id))
+    }
+}
+
+fn note_from_row(row: &Row) -> Result<Note, RepoError> {
+    Ok(Note {
+        id: row.try_get(0)?,
+        subject_id: row.try_get(1)?,
+        title: row.try_get(2)?,
+        content: row.try_get(3)?,
+        created_at: row.try_get(4)?,
+        updated_at: row.try_get(5)?,
+    })
+}
+
+fn note_revision_from_row(row: &Row) -> Result<NoteRevision, RepoError> {
+    Ok(NoteRevision {
+        id: row.try_get(0)?,
+        note_id: row.try_get(1)?,
+        title: row.try_get(2)?,
+        content: row.try_get(3)?,
+        created_at: row.try_get(4)?,
+    })
+}
+
+impl NoteRepository for PostgresRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<Note>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!("SELECT {} FROM notes WHERE subject_id = $1 ORDER BY id", NOTE_COLUMNS);
+            let rows = pool.get().await?.query(&sql, &[&subject_id]).await?;
+            rows.iter().map(note_from_row).collect()
+        })
+    }
+
+    fn find(&self, note_id: i32) -> RepoFuture<Option<Note>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!("SELECT {} FROM notes WHERE id = $1", NOTE_COLUMNS);
+            let row = pool.get().await?.query_opt(&sql, &[&note_id]).await?;
+            row.as_ref().map(note_from_row).transpose()
+        })
+    }
+
+    fn create(&self, subject_id: i32, title: Option<String>, content: String) -> RepoFuture<Note> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let now = now_secs();
+            let id = Self::insert(
+                pool,
+                "INSERT INTO notes (subject_id, title, content, created_at, updated_at)
+                 VALUES ($1, $2, $3, $4, $4) RETURNING id",
+                &[&subject_id, &title, &content, &now],
+            )
+            .await?;
+            Ok(Note::new(id, subject_id, title, content, now))
+        })
+    }
+
+    fn owner(&self, note_id: i32) -> RepoFuture<Option<i32>> {
+        Box::pin(Self::find_owner(
+            self.pool.clone(),
+            "SELECT subjects.user_id FROM notes
+             JOIN subjects ON subjects.id = notes.subject_id
+             WHERE notes.id = $1",
+            note_id,
+        ))
+    }
+
+    fn update(&self, note_id: i32, changes: NoteChanges) -> RepoFuture<Option<Note>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            let sql = format!("SELECT {} FROM notes WHERE id = $1 FOR UPDATE", NOTE_COLUMNS);
+            let mut note = match tx.query_opt(&sql, &[&note_id]).await? {
+                Some(row) => note_from_row(&row)?,
+                None => return Ok(None),
+            };
+            let previous = note.clone();
+            if changes.apply(&mut note, now_secs()) {
+                tx.execute(
+                    "INSERT INTO note_revisions (note_id, title, content, created_at) VALUES ($1, $2, $3, $4)",
+                    &[&note_id, &previous.title, &previous.content, &previous.updated_at],
+                )
+                .await?;
+                tx.execute(
+                    "UPDATE notes SET title = $1, content = $2, updated_at = $3 WHERE id = $4",
+                    &[&note.title, &note.content, &note.updated_at, &note_id],
+                )
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(Some(note))
+        })
+    }
+
+    fn revisions(&self, note_id: i32) -> RepoFuture<Vec<NoteRevision>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!(
+                "SELECT {} FROM note_revisions WHERE note_id = $1 ORDER BY id DESC",
+                NOTE_REVISION_COLUMNS
+            );
+            let rows = pool.get().await?.query(&sql, &[&note_id]).await?;
+            rows.iter().map(note_revision_from_row).collect()
+        })
+    }
+
+    fn find_revision(&self, note_id: i32, revision_id: i32) -> RepoFuture<Option<NoteRevision>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!("SELECT {} FROM note_revisions WHERE id = $1 AND note_id = $2", NOTE_REVISION_COLUMNS);
+            let row = pool.get().await?.query_opt(&sql, &[&revision_id, &note_id]).await?;
+            row.as_ref().map(note_revision_from_row).transpose()
+        })
+    }
+
+    fn delete(&self, note_id: i32) -> RepoFuture<bool> {
+        Box::pin(Self::execute(self.pool.clone(), "DELETE FROM notes WHERE id = $1", note_id))
+    }
+}
+
+fn file_link_from_row(row: &Row) -> Result<FileLink, RepoError> {
+    Ok(FileLink {
+        id: row.try_get(0)?,
+        subject_id: row.try_get(1)?,
+        url: row.try_get(2)?,
+        label: row.try_get(3)?,
+        kind: row.try_get::<_, Option<&str>>(4)?.and_then(LinkKind::parse),
+        created_at: row.try_get(5)?,
+    })
+}
+
+impl FileLinkRepository for PostgresRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<FileLink>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!("SELECT {} FROM file_links WHERE subject_id = $1 ORDER BY id", FILE_LINK_COLUMNS);
+            let rows = pool.get().await?.query(&sql, &[&subject_id]).await?;
+            rows.iter().map(file_link_from_row).collect()
+        })
+    }
+
+    fn create(&self, subject_id: i32, link: NewFileLink) -> RepoFuture<FileLink> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let now = now_secs();
+            let id = Self::insert(
+                pool,
+                "INSERT INTO file_links (subject_id, url, label, kind, created_at)
+                 VALUES ($1, $2, $3, $4, $5) RETURNING id",
+                &[&subject_id, &link.url, &link.label, &link.kind.map(LinkKind::as_str), &now],
+            )
+            .await?;
+            Ok(FileLink::new(id, subject_id, link, now))
+        })
+    }
+
+    fn owner(&self, file_link_id: i32) -> RepoFuture<Option<i32>> {
+        Box::pin(Self::find_owner(
+            self.pool.clone(),
+            "SELECT subjects.user_id FROM file_links
+             JOIN subjects ON subjects.id = file_links.subject_id
+             WHERE file_links.id = $1",
+            file_link_id,
+        ))
+    }
+
+    fn update(&self, file_link_id: i32, changes: FileLinkChanges) -> RepoFuture<Option<FileLink>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            let sql = format!("SELECT {} FROM file_links WHERE id = $1 FOR UPDATE", FILE_LINK_COLUMNS);
+            let mut file_link = match tx.query_opt(&sql, &[&file_link_id]).await? {
+                Some(row) => file_link_from_row(&row)?,
+                None => return Ok(None),
+            };
+            changes.apply(&mut file_link);
+            tx.execute(
+                "UPDATE file_links SET url = $1, label = $2, kind = $3 WHERE id = $4",
+                &[&file_link.url, &file_link.label, &file_link.kind.map(LinkKind::as_str), &file_link_id],
+            )
+            .await?;
+            tx.commit().await?;
+            Ok(Some(file_link))
+        })
+    }
+
+    fn delete(&self, file_link_id: i32) -> RepoFuture<bool> {
+        Box::pin(Self::execute(self.pool.clone(), "DELETE FROM file_links WHERE id = $1", file_link_id))
+    }
+}
+
+fn stored_file_from_row(row: &Row) -> Result<StoredFile, RepoError> {
+    Ok(StoredFile {
+        id: row.try_get(0)?,
+        subject_id: row.try_get(1)?,
+        filename: row.try_get(2)?,
+        mime_type: row.try_get(3)?,
+        size: row.try_get(4)?,
+        sha256: row.try_get(5)?,
+        created_at: row.try_get(6)?,
+    })
+}
+
+impl StoredFileRepository for PostgresRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<StoredFile>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!("SELECT {} FROM stored_files WHERE subject_id = $1 ORDER BY id", STORED_FILE_COLUMNS);
+            let rows = pool.get().await?.query(&sql, &[&subject_id]).await?;
+            rows.iter().map(stored_file_from_row).collect()
+        })
+    }
+
+    fn find(&self, file_id: i32) -> RepoFuture<Option<StoredFile>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let sql = format!("SELECT {} FROM stored_files WHERE id = $1", STORED_FILE_COLUMNS);
+            let row = pool.get().await?.query_opt(&sql, &[&file_id]).await?;
+            row.as_ref().map(stored_file_from_row).transpose()
+        })
+    }
+
+    fn create(&self, subject_id: i32, file: NewStoredFile) -> RepoFuture<StoredFile> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let now = now_secs();
+            let id = Self::insert(
+                pool,
+                "INSERT INTO stored_files (subject_id, filename, mime_type, size, sha256, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                &[&subject_id, &file.filename, &file.mime_type, &file.size, &file.sha256, &now],
+            )
+            .await?;
+            Ok(StoredFile::new(id, subject_id, file, now))
+        })
+    }
+
+    fn owner(&self, file_id: i32) -> RepoFuture<Option<i32>> {
+        Box::pin(Self::find_owner(
+            self.pool.clone(),
+            "SELECT subjects.user_id FROM stored_files
+             JOIN subjects ON subjects.id = stored_files.subject_id
+             WHERE stored_files.id = $1",
+            file_id,
+        ))
+    }
+
+    fn delete(&self, file_id: i32) -> RepoFuture<bool> {
+        Box::pin(Self::execute(self.pool.clone(), "DELETE FROM stored_files WHERE id = $1", file_id))
+    }
+
+    fn hashes_for_user(&self, user_id: i32) -> RepoFuture<Vec<String>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let rows = pool
+                .get()
+                .await?
+                .query(
+                    "SELECT DISTINCT stored_files.sha256 FROM stored_files
+                     JOIN subjects ON subjects.id = stored_files.subject_id
+                     WHERE subjects.user_id = $1",
+                    &[&user_id],
+                )
+                .await?;
+            rows.iter().map(|row| Ok(row.try_get(0)?)).collect()
+        })
+    }
+
+    fn is_referenced(&self, sha256: String) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let row = pool
+                .get()
+                .await?
+                .query_one("SELECT EXISTS (SELECT 1 FROM stored_files WHERE sha256 = $1)", &[&sha256])
+                .await?;
+            Ok(row.try_get(0)?)
+        })
+    }
+}
+
+// Ends every session but keep_session_id, if given, and every API token of the user
+async fn revoke_access(tx: &Transaction<'_>, user_id: i32, keep_session_id: Option<i64>) -> Result<(), RepoError> {
+    let now = now_secs();
+    tx.execute(
+        "UPDATE sessions SET revoked_at = $1
+         WHERE user_id = $2 AND id IS DISTINCT FROM $3 AND revoked_at IS NULL",
+        &[&now, &user_id, &keep_session_id],
+    )
+    .await?;
+    tx.execute(
+        "UPDATE api_tokens SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL",
+        &[&now, &user_id],
+    )
+    .await?;
+    Ok(())
+}
+
+impl AccountRepository for PostgresRepository {
+    fn status(&self, user_id: i32) -> RepoFuture<Option<AccountStatus>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let row = pool
+                .get()
+                .await?
+                .query_opt("SELECT role, disabled, must_reset_password FROM users WHERE id = $1", &[&user_id])
+                .await?;
+            row.map(|row| {
+                Ok(AccountStatus {
+                    role: row.try_get(0)?,
+                    disabled: row.try_get(1)?,
+                    must_reset_password: row.try_get(2)?,
+                })
+            })
+            .transpose()
+        })
+    }
+
+    fn username(&self, user_id: i32) -> RepoFuture<Option<String>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let row = pool.get().await?.query_opt("SELECT username FROM users WHERE id = $1", &[&user_id]).await?;
+            Ok(row.map(|row| row.try_get(0)).transpose()?)
+        })
+    }
+
+    fn password_hash(&self, user_id: i32) -> RepoFuture<Option<String>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let row = pool.get().await?.query_opt("SELECT password_hash FROM users WHERE id = $1", &[&user_id]).await?;
+            Ok(row.map(|row| row.try_get(0)).transpose()?)
+        })
+    }
+
+    fn locale(&self, user_id: i32) -> RepoFuture<Option<Locale>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let row = pool.get().await?.query_opt("SELECT language FROM users WHERE id = $1", &[&user_id]).await?;
+            let language: Option<String> = row.map(|row| row.try_get(0)).transpose()?.flatten();
+            Ok(language.as_deref().and_then(Locale::from_tag))
+        })
+    }
+
+    fn update_password(&self, user_id: i32, password_hash: String, keep_session_id: i64) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            tx.execute("UPDATE users SET password_hash = $1 WHERE id = $2", &[&password_hash, &user_id])
+                .await?;
+            revoke_access(&tx, user_id, Some(keep_session_id)).await?;
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn update_username(&self, user_id: i32, username: String) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute("UPDATE users SET username = $1 WHERE id = $2", &[&username, &user_id])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn update_email(&self, user_id: i32, email: Option<String>) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            pool.get().await?.execute("UPDATE users SET email = $1 WHERE id = $2", &[&email, &user_id]).await?;
+            Ok(())
+        })
+    }
+
+    fn update_locale(&self, user_id: i32, locale: Option<Locale>) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute("UPDATE users SET language = $1 WHERE id = $2", &[&locale.map(Locale::tag), &user_id])
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn delete(&self, user_id: i32) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            Self::execute(pool, "DELETE FROM users WHERE id = $1", user_id).await?;
+            Ok(())
+        })
+    }
+
+    fn promote(&self, username: String) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute("UPDATE users SET role = $1 WHERE lower(username) = lower($2)", &[&ROLE_ADMIN, &username])
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+impl SessionRepository for PostgresRepository {
+    fn create(&self, user_id: i32, device: Option<String>) -> RepoFuture<(i64, String)> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let refresh_token = generate_token();
+            let now = now_secs();
+            let row = pool
+                .get()
+                .await?
+                .query_one(
+                    "INSERT INTO sessions (user_id, refresh_token_hash, device, created_at, last_seen_at, expires_at)
+                     VALUES ($1, $2, $3, $4, $4, $5) RETURNING id",
+                    &[&user_id, &hash_token(&refresh_token), &device, &now, &(now + REFRESH_TOKEN_TTL_SECS)],
+                )
+                .await?;
+            Ok((row.try_get(0)?, refresh_token))
+        })
+    }
+
+    fn touch(&self, session_id: i64, user_id: i32) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let now = now_secs();
+            let client = pool.get().await?;
+            let active = client
+                .query_opt(
+                    "SELECT id FROM sessions
+                     WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL AND expires_at > $3",
+                    &[&session_id, &user_id, &now],
+                )
+                .await?
+                .is_some();
+            if active {
+                client
+                    .execute(
+                        "UPDATE sessions SET last_seen_at = $1 WHERE id = $2 AND last_seen_at < $3",
+                        &[&now, &session_id, &(now - LAST_SEEN_RESOLUTION_SECS)],
+                    )
+                    .await?;
+            }
+            Ok(active)
+        })
+    }
+
+    fn rotate(&self, refresh_token: String) -> RepoFuture<Option<(i64, i32, String)>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let now = now_secs();
+            let new_token = generate_token();
+            // A single statement, so two refreshes with the same token cannot both get a new one
+            let row = pool
+                .get()
+                .await?
+                .query_opt(
+                    "UPDATE sessions SET refresh_token_hash = $1, last_seen_at = $2, expires_at = $3
+                     WHERE refresh_token_hash = $4 AND revoked_at IS NULL AND expires_at > $2
+                     RETURNING id, user_id",
+                    &[&hash_token(&new_token), &now, &(now + REFRESH_TOKEN_TTL_SECS), &hash_token(&refresh_token)],
+                )
+                .await?;
+            row.map(|row| Ok((row.try_get(0)?, row.try_get(1)?, new_token))).transpose()
+        })
+    }
+
+    fn revoke(&self, user_id: i32, session_id: i64) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let revoked = pool
+                .get()
+                .await?
+                .execute(
+                    "UPDATE sessions SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL",
+                    &[&now_secs(), &session_id, &user_id],
+                )
+                .await?;
+            Ok(revoked > 0)
+        })
+    }
+
+    fn revoke_all(&self, user_id: i32) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute(
+                    "UPDATE sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL",
+                    &[&now_secs(), &user_id],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn list(&self, user_id: i32, current_session_id: i64) -> RepoFuture<Vec<Session>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let rows = pool
+                .get()
+                .await?
+                .query(
+                    "SELECT id, device, created_at, last_seen_at, expires_at FROM sessions
+                     WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > $2
+                     ORDER BY last_seen_at DESC",
+                    &[&user_id, &now_secs()],
+                )
+                .await?;
+            rows.iter()
+                .map(|row| {
+                    let id: i64 = row.try_get(0)?;
+                    Ok(Session {
+                        id,
+                        device: row.try_get(1)?,
+                        created_at: row.try_get(2)?,
+                        last_seen_at: row.try_get(3)?,
+                        expires_at: row.try_get(4)?,
+                        current: id == current_session_id,
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+impl ApiTokenRepository for PostgresRepository {
+    fn create(&self, user_id: i32, name: String, scope: TokenScope) -> RepoFuture<(i64, String)> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let token = new_api_token();
+            let row = pool
+                .get()
+                .await?
+                .query_one(
+                    "INSERT INTO api_tokens (user_id, name, token_hash, access, area, created_at)
+                     VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                    &[
+                        &user_id,
+                        &name,
+                        &hash_token(&token),
+                        &scope.access.as_str(),
+                        &scope.area.as_str(),
+                        &now_secs(),
+                    ],
+                )
+                .await?;
+            Ok((row.try_get(0)?, token))
+        })
+    }
+
+    fn find(&self, token: String) -> RepoFuture<Option<(i32, TokenScope)>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let client = pool.get().await?;
+            let row = match client
+                .query_opt(
+                    "SELECT id, user_id, access, area FROM api_tokens WHERE token_hash = $1 AND revoked_at IS NULL",
+                    &[&hash_token(&token)],
+                )
+                .await?
+            {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            let id: i64 = row.try_get(0)?;
+            let scope = match TokenScope::parse(row.try_get(2)?, row.try_get(3)?) {
+                Some(scope) => scope,
+                None => return Ok(None),
+            };
+
+            let now = now_secs();
+            client
+                .execute(
+                    "UPDATE api_tokens SET last_used_at = $1
+                     WHERE id = $2 AND (last_used_at IS NULL OR last_used_at < $3)",
+                    &[&now, &id, &(now - LAST_USED_RESOLUTION_SECS)],
+                )
+                .await?;
+            Ok(Some((row.try_get(1)?, scope)))
+        })
+    }
+
+    fn list(&self, user_id: i32) -> RepoFuture<Vec<ApiToken>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let rows = pool
+                .get()
+                .await?
+                .query(
+                    "SELECT id, name, access, area, created_at, last_used_at FROM api_tokens
+                     WHERE user_id = $1 AND revoked_at IS NULL
+                     ORDER BY created_at DESC",
+                    &[&user_id],
+                )
+                .await?;
+            rows.iter()
+                .map(|row| {
+                    Ok(ApiToken {
+                        id: row.try_get(0)?,
+                        name: row.try_get(1)?,
+                        access: Access::parse(row.try_get(2)?).unwrap_or(Access::Read),
+                        area: Area::parse(row.try_get(3)?).unwrap_or(Area::All),
+                        created_at: row.try_get(4)?,
+                        last_used_at: row.try_get(5)?,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn revoke(&self, user_id: i32, token_id: i64) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let revoked = pool
+                .get()
+                .await?
+                .execute(
+                    "UPDATE api_tokens SET revoked_at = $1 WHERE id = $2 AND user_id = $3 AND revoked_at IS NULL",
+                    &[&now_secs(), &token_id, &user_id],
+                )
+                .await?;
+            Ok(revoked > 0)
+        })
+    }
+}
+
+impl TwoFactorRepository for PostgresRepository {
+    fn state(&self, user_id: i32) -> RepoFuture<Option<TotpState>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let row = pool
+                .get()
+                .await?
+                .query_opt("SELECT username, totp_secret, totp_enabled FROM users WHERE id = $1", &[&user_id])
+                .await?;
+            row.map(|row| {
+                Ok(TotpState {
+                    username: row.try_get(0)?,
+                    secret: row.try_get(1)?,
+                    enabled: row.try_get(2)?,
+                })
+            })
+            .transpose()
+        })
+    }
+
+    fn set_pending_secret(&self, user_id: i32, secret: String) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute(
+                    "UPDATE users SET totp_secret = $1, totp_enabled = FALSE, totp_last_step = NULL WHERE id = $2",
+                    &[&secret, &user_id],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn enable(&self, user_id: i32, recovery_code_hashes: Vec<String>, step: i64) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            tx.execute(
+                "UPDATE users SET totp_enabled = TRUE, totp_last_step = $1 WHERE id = $2",
+                &[&step, &user_id],
+            )
+            .await?;
+            tx.execute("DELETE FROM recovery_codes WHERE user_id = $1", &[&user_id]).await?;
+            for code_hash in &recovery_code_hashes {
+                tx.execute(
+                    "INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+                    &[&user_id, code_hash],
+                )
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn disable(&self, user_id: i32) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            tx.execute(
+                "UPDATE users SET totp_secret = NULL, totp_enabled = FALSE, totp_last_step = NULL WHERE id = $1",
+                &[&user_id],
+            )
+            .await?;
+            tx.execute("DELETE FROM recovery_codes WHERE user_id = $1", &[&user_id]).await?;
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn use_step(&self, user_id: i32, step: i64) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let updated = pool
+                .get()
+                .await?
+                .execute(
+                    "UPDATE users SET totp_last_step = $1
+                     WHERE id = $2 AND (totp_last_step IS NULL OR totp_last_step < $1)",
+                    &[&step, &user_id],
+                )
+                .await?;
+            Ok(updated == 1)
+        })
+    }
+
+    fn unused_recovery_codes(&self, user_id: i32) -> RepoFuture<Vec<(i64, String)>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let rows = pool
+                .get()
+                .await?
+                .query(
+                    "SELECT id, code_hash FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL ORDER BY id",
+                    &[&user_id],
+                )
+                .await?;
+            rows.iter().map(|row| Ok((row.try_get(0)?, row.try_get(1)?))).collect()
+        })
+    }
+
+    fn use_recovery_code(&self, code_id: i64) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let updated = pool
+                .get()
+                .await?
+                .execute(
+                    "UPDATE recovery_codes SET used_at = $1 WHERE id = $2 AND used_at IS NULL",
+                    &[&now_secs(), &code_id],
+                )
+                .await?;
+            Ok(updated == 1)
+        })
+    }
+}
+
+impl PasswordResetRepository for PostgresRepository {
+    fn find_recipient(
+        &self,
+        username: Option<String>,
+        email: Option<String>,
+    ) -> RepoFuture<Option<(i32, String, String)>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let (sql, value) = match (username, email) {
+                (Some(username), _) => (
+                    "SELECT id, email, username FROM users WHERE lower(username) = lower($1) AND email IS NOT NULL",
+                    username,
+                ),
+                (None, Some(email)) => ("SELECT id, email, username FROM users WHERE lower(email) = lower($1)", email),
+                (None, None) => return Ok(None),
+            };
+            let row = pool.get().await?.query_opt(sql, &[&value]).await?;
+            row.map(|row| Ok((row.try_get(0)?, row.try_get(1)?, row.try_get(2)?))).transpose()
+        })
+    }
+
+    fn create(&self, user_id: i32) -> RepoFuture<String> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let token = generate_token();
+            let now = now_secs();
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            tx.execute(
+                "UPDATE password_reset_tokens SET used_at = $1 WHERE user_id = $2 AND used_at IS NULL",
+                &[&now, &user_id],
+            )
+            .await?;
+            tx.execute(
+                "INSERT INTO password_reset_tokens (user_id, token_hash, created_at, expires_at)
+                 VALUES ($1, $2, $3, $4)",
+                &[&user_id, &hash_token(&token), &now, &(now + RESET_TOKEN_TTL_SECS)],
+            )
+            .await?;
+            tx.commit().await?;
+            Ok(token)
+        })
+    }
+
+    fn find(&self, token: String) -> RepoFuture<Option<(i64, i32, String)>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let row = pool
+                .get()
+                .await?
+                .query_opt(
+                    "SELECT password_reset_tokens.id, users.id, users.username FROM password_reset_tokens
+                     JOIN users ON users.id = password_reset_tokens.user_id
+                     WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2",
+                    &[&hash_token(&token), &now_secs()],
+                )
+                .await?;
+            row.map(|row| Ok((row.try_get(0)?, row.try_get(1)?, row.try_get(2)?))).transpose()
+        })
+    }
+
+    fn reset_password(&self, token_id: i64, user_id: i32, password_hash: String) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            let burned = tx
+                .execute(
+                    "UPDATE password_reset_tokens SET used_at = $1 WHERE id = $2 AND used_at IS NULL",
+                    &[&now_secs(), &token_id],
+                )
+                .await?;
+            if burned == 0 {
+                return Ok(false);
+            }
+            tx.execute(
+                "UPDATE users SET password_hash = $1, must_reset_password = FALSE WHERE id = $2",
+                &[&password_hash, &user_id],
+            )
+            .await?;
+            revoke_access(&tx, user_id, None).await?;
+            tx.commit().await?;
+            Ok(true)
+        })
+    }
+}
+
+async fn record_audit(
+    tx: &Transaction<'_>,
+    admin_id: i32,
+    action: &str,
+    target_user_id: i32,
+    details: Option<&str>,
+) -> Result<(), RepoError> {
+    tx.execute(
+        "INSERT INTO audit_log (admin_id, action, target_user_id, details, created_at) VALUES ($1, $2, $3, $4, $5)",
+        &[&admin_id, &action, &target_user_id, &details, &now_secs()],
+    )
+    .await?;
+    Ok(())
+}
+
+impl AdminRepository for PostgresRepository {
+    fn user_summaries(&self) -> RepoFuture<Vec<UserSummary>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let rows = pool
+                .get()
+                .await?
+                .query(
+                    "SELECT users.id, users.username, users.email, users.role, users.disabled,
+                            users.must_reset_password,
+                            (SELECT COUNT(*) FROM sessions
+                             WHERE sessions.user_id = users.id AND revoked_at IS NULL AND expires_at > $1),
+                            (SELECT MAX(last_seen_at) FROM sessions WHERE sessions.user_id = users.id)
+                     FROM users
+                     ORDER BY users.id",
+                    &[&now_secs()],
+                )
+                .await?;
+            rows.iter()
+                .map(|row| {
+                    Ok(UserSummary {
+                        id: row.try_get(0)?,
+                        username: row.try_get(1)?,
+                        email: row.try_get(2)?,
+                        role: row.try_get(3)?,
+                        disabled: row.try_get(4)?,
+                        must_reset_password: row.try_get(5)?,
+                        task_count: 0,
+                        subject_count: 0,
+                        active_session_count: row.try_get(6)?,
+                        last_seen_at: row.try_get(7)?,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn disable(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            if tx.execute("UPDATE users SET disabled = TRUE WHERE id = $1", &[&user_id]).await? == 0 {
+                return Ok(false);
+            }
+            revoke_access(&tx, user_id, None).await?;
+            record_audit(&tx, admin_id, "disable_user", user_id, None).await?;
+            tx.commit().await?;
+            Ok(true)
+        })
+    }
+
+    fn enable(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            if tx.execute("UPDATE users SET disabled = FALSE WHERE id = $1", &[&user_id]).await? == 0 {
+                return Ok(false);
+            }
+            record_audit(&tx, admin_id, "enable_user", user_id, None).await?;
+            tx.commit().await?;
+            Ok(true)
+        })
+    }
+
+    fn require_password_reset(&self, admin_id: i32, user_id: i32) -> RepoFuture<Option<(String, Option<String>)>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            let row = match tx
+                .query_opt(
+                    "UPDATE users SET must_reset_password = TRUE WHERE id = $1 RETURNING username, email",
+                    &[&user_id],
+                )
+                .await?
+            {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            revoke_access(&tx, user_id, None).await?;
+            record_audit(&tx, admin_id, "force_password_reset", user_id, None).await?;
+            tx.commit().await?;
+            Ok(Some((row.try_get(0)?, row.try_get(1)?)))
+        })
+    }
+
+    fn delete_user(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            // Everything the user owns goes with it through ON DELETE CASCADE
+            let deleted = tx.query_opt("DELETE FROM users WHERE id = $1 RETURNING username", &[&user_id]).await?;
+            let username: String = match deleted {
+                Some(row) => row.try_get(0)?,
+                None => return Ok(false),
+            };
+            record_audit(&tx, admin_id, "delete_user", user_id, Some(&username)).await?;
+            tx.commit().await?;
+            Ok(true)
+        })
+    }
+
+    fn audit_log(&self, before_id: Option<i64>) -> RepoFuture<Vec<AuditEntry>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let rows = pool
+                .get()
+                .await?
+                .query(
+                    "SELECT id, admin_id, action, target_user_id, details, created_at FROM audit_log
+                     WHERE id < $1
+                     ORDER BY id DESC
+                     LIMIT $2",
+                    &[&before_id.unwrap_or(i64::MAX), &AUDIT_LOG_PAGE_SIZE],
+                )
+                .await?;
+            rows.iter()
+                .map(|row| {
+                    Ok(AuditEntry {
+                        id: row.try_get(0)?,
+                        admin_id: row.try_get(1)?,
+                        action: row.try_get(2)?,
+                        target_user_id: row.try_get(3)?,
+                        details: row.try_get(4)?,
+                        created_at: row.try_get(5)?,
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+// Serializes attempts on the same keys until the transaction ends. Locks are taken in a fixed
+// order, so two requests sharing keys cannot deadlock.
+async fn lock_attempt_keys(tx: &Transaction<'_>, mut keys: Vec<&str>) -> Result<(), RepoError> {
+    keys.sort_unstable();
+    keys.dedup();
+    for key in keys {
+        tx.execute("SELECT pg_advisory_xact_lock(7283541, hashtext($1))", &[&key]).await?;
+    }
+    Ok(())
+}
+
+async fn seconds_blocked_for(tx: &Transaction<'_>, key: &str) -> Result<i64, RepoError> {
+    let row = tx.query_opt("SELECT blocked_until FROM login_attempts WHERE key = $1", &[&key]).await?;
+    let blocked_until: Option<i64> = row.map(|row| row.try_get(0)).transpose()?;
+    Ok((blocked_until.unwrap_or(0) - now_secs()).max(0))
+}
+
+async fn record_attempt(tx: &Transaction<'_>, key: &str, policy: &Policy) -> Result<(), RepoError> {
+    let now = now_secs();
+    let row = tx.query_opt("SELECT attempts, last_attempt_at FROM login_attempts WHERE key = $1", &[&key]).await?;
+    let previous: Option<(i64, i64)> = match row {
+        Some(row) => Some((row.try_get(0)?, row.try_get(1)?)),
+        None => None,
+    };
+    let (attempts, blocked_until) = count_attempt(policy, previous, now);
+    tx.execute(
+        "INSERT INTO login_attempts (key, attempts, last_attempt_at, blocked_until)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (key) DO UPDATE SET
+             attempts = excluded.attempts,
+             last_attempt_at = excluded.last_attempt_at,
+             blocked_until = excluded.blocked_until",
+        &[&key, &attempts, &now, &blocked_until],
+    )
+    .await?;
+    Ok(())
+}
+
+impl LoginAttemptRepository for PostgresRepository {
+    fn reserve(&self, keys: Vec<(String, &'static Policy)>) -> RepoFuture<i64> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            lock_attempt_keys(&tx, keys.iter().map(|(key, _)| key.as_str()).collect()).await?;
+            let mut retry_after = 0;
+            for (key, _) in &keys {
+                retry_after = retry_after.max(seconds_blocked_for(&tx, key).await?);
+            }
+            if retry_after > 0 {
+                return Ok(retry_after);
+            }
+            for (key, policy) in &keys {
+                record_attempt(&tx, key, policy).await?;
+            }
+            tx.commit().await?;
+            Ok(0)
+        })
+    }
+
+    fn blocked_for(&self, keys: Vec<String>) -> RepoFuture<i64> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            let mut retry_after = 0;
+            for key in &keys {
+                retry_after = retry_after.max(seconds_blocked_for(&tx, key).await?);
+            }
+            Ok(retry_after)
+        })
+    }
+
+    fn record(&self, key: String, policy: &'static Policy) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let mut client = pool.get().await?;
+            let tx = client.transaction().await?;
+            lock_attempt_keys(&tx, vec![&key]).await?;
+            record_attempt(&tx, &key, policy).await?;
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn clear(&self, key: String) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            pool.get().await?.execute("DELETE FROM login_attempts WHERE key = $1", &[&key]).await?;
+            Ok(())
+        })
+    }
+
+    // Take back one reserved attempt that turned out not to be a failure, leaving any block in place
+    fn refund(&self, key: String) -> RepoFuture<()> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            pool.get()
+                .await?
+                .execute("UPDATE login_attempts SET attempts = GREATEST(attempts - 1, 0) WHERE key = $1", &[&key])
+                .await?;
+            Ok(())
+        })
+    }
+}
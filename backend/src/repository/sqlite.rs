@@ -0,0 +1,878 @@
+use rusqlite::{params, Connection, OptionalExtension, Result, Row};
+
+use super::{
+    AccountRepository, AccountStatus, AdminRepository, ApiTokenRepository, ExamDate, ExamDateChanges,
+    ExamDateRepository, ExamKind, FileLink, FileLinkChanges, FileLinkRepository, LinkKind, LoginAttemptRepository,
+    NewExamDate, NewFileLink, NewStoredFile, Note, NoteChanges, NoteRepository, NoteRevision, PasswordResetRepository,
+    RepoError, RepoFuture, SessionRepository, StoredFile, StoredFileRepository, Subject, SubjectChanges,
+    SubjectRepository, Task, TaskRepository, TwoFactorRepository, User, UserRepository,
+};
+use crate::admin::{AuditEntry, UserSummary};
+use crate::api_tokens::{ApiToken, TokenScope};
+use crate::auth::now_secs;
+use crate::db::{self, DbPool};
+use crate::i18n::Locale;
+use crate::rate_limit::Policy;
+use crate::sessions::Session;
+use crate::two_factor::TotpState;
+use crate::{account, admin, api_tokens, i18n, password_reset, rate_limit, sessions, two_factor};
+
+const SUBJECT_COLUMNS: &str = "id, name, user_id, color, icon, display_order, archived, professor, course_code";
+const EXAM_DATE_COLUMNS: &str = "id, subject_id, date, duration_minutes, kind, location, notes, weight";
+const NOTE_COLUMNS: &str = "id, subject_id, title, content, created_at, updated_at";
+const NOTE_REVISION_COLUMNS: &str = "id, note_id, title, content, created_at";
+const FILE_LINK_COLUMNS: &str = "id, subject_id, url, label, kind, created_at";
+const STORED_FILE_COLUMNS: &str = "id, subject_id, filename, mime_type, size, sha256, created_at";
+
+pub struct SqliteRepository {
+    pool: DbPool,
+}
+
+impl SqliteRepository {
+    pub fn new(pool: DbPool) -> Self {
+        SqliteRepository { pool }
+    }
+
+    // Run a database function on the blocking pool
+    fn run<T, F>(&self, f: F) -> RepoFuture<T>
+    where
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        Box::pin(async move { db::run(&pool, f).await.map_err(RepoError::from) })
+    }
+}
+
+impl UserRepository for SqliteRepository {
+    fn create(&self, username: String, password_hash: String, email: Option<String>) -> RepoFuture<User> {
+        self.run(move |conn| insert_user(conn, username, password_hash, email))
+    }
+
+    fn find_by_username(&self, username: String) -> RepoFuture<Option<User>> {
+        self.run(move |conn| find_user(conn, &username))
+    }
+}
+
+impl TaskRepository for SqliteRepository {
+    fn list(&self, user_id: i32) -> RepoFuture<Vec<Task>> {
+        self.run(move |conn| find_tasks(conn, user_id))
+    }
+
+    fn create(&self, user_id: i32, title: String, status: String, note: Option<String>) -> RepoFuture<Task> {
+        self.run(move |conn| insert_task(conn, title, status, note, user_id))
+    }
+
+    fn owner(&self, task_id: i32) -> RepoFuture<Option<i32>> {
+        self.run(move |conn| find_owner(conn, "SELECT user_id FROM tasks WHERE id = ?1", task_id))
+    }
+
+    fn update_status(&self, task_id: i32, status: String) -> RepoFuture<bool> {
+        self.run(move |conn| modify_task_status(conn, task_id, &status))
+    }
+
+    fn update_note(&self, task_id: i32, note: String) -> RepoFuture<bool> {
+        self.run(move |conn| modify_task_note(conn, task_id, &note))
+    }
+
+    fn delete(&self, task_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| remove_task(conn, task_id))
+    }
+
+    fn count_for_user(&self, user_id: i32) -> RepoFuture<i64> {
+        self.run(move |conn| count_owned(conn, "SELECT COUNT(*) FROM tasks WHERE user_id = ?1", user_id))
+    }
+}
+
+impl SubjectRepository for SqliteRepository {
+    fn list(&self, user_id: i32, include_archived: bool) -> RepoFuture<Vec<Subject>> {
+        self.run(move |conn| find_subjects(conn, user_id, include_archived))
+    }
+
+    fn create(&self, user_id: i32, name: String) -> RepoFuture<Subject> {
+        self.run(move |conn| insert_subject(conn, name, user_id))
+    }
+
+    fn owner(&self, subject_id: i32) -> RepoFuture<Option<i32>> {
+        self.run(move |conn| find_owner(conn, "SELECT user_id FROM subjects WHERE id = ?1", subject_id))
+    }
+
+    fn update(&self, subject_id: i32, changes: SubjectChanges) -> RepoFuture<Option<Subject>> {
+        self.run(move |conn| modify_subject(conn, subject_id, changes))
+    }
+
+    fn delete(&self, subject_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| remove_subject(conn, subject_id))
+    }
+
+    fn count_for_user(&self, user_id: i32) -> RepoFuture<i64> {
+        self.run(move |conn| count_owned(conn, "SELECT COUNT(*) FROM subjects WHERE user_id = ?1", user_id))
+    }
+}
+
+impl ExamDateRepository for SqliteRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<ExamDate>> {
+        self.run(move |conn| find_exam_dates(conn, subject_id))
+    }
+
+    fn create(&self, subject_id: i32, exam: NewExamDate) -> RepoFuture<ExamDate> {
+        self.run(move |conn| insert_exam_date(conn, subject_id, exam))
+    }
+
+    fn owner(&self, exam_date_id: i32) -> RepoFuture<Option<i32>> {
+        self.run(move |conn| {
+            find_owner(
+                conn,
+                "SELECT subjects.user_id FROM exam_dates
+                 JOIN subjects ON subjects.id = exam_dates.subject_id
+                 WHERE exam_dates.id = ?1",
+                exam_date_id,
+            )
+        })
+    }
+
+    fn update(&self, exam_date_id: i32, changes: ExamDateChanges) -> RepoFuture<Option<ExamDate>> {
+        self.run(move |conn| modify_exam_date(conn, exam_date_id, changes))
+    }
+
+    fn delete(&self, exam_date_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| remove_exam_date(conn, exam_date_id))
+    }
+}
+
+impl NoteRepository for SqliteRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<Note>> {
+        self.run(move |conn| find_notes(conn, subject_id))
+    }
+
+    fn find(&self, note_id: i32) -> RepoFuture<Option<Note>> {
+        self.run(move |conn| find_note(conn, note_id))
+    }
+
+    fn create(&self, subject_id: i32, title: Option<String>, content: String) -> RepoFuture<Note> {
+        self.run(move |conn| insert_note(conn, subject_id, title, content))
+    }
+
+    fn owner(&self, note_id: i32) -> RepoFuture<Option<i32>> {
+        self.run(move |conn| {
+            find_owner(
+                conn,
+                "SELECT subjects.user_id FROM notes
+                 JOIN subjects ON subjects.id = notes.subject_id
+                 WHERE notes.id = ?1",
+                note_id,
+            )
+        })
+    }
+
+    fn update(&self, note_id: i32, changes: NoteChanges) -> RepoFuture<Option<Note>> {
+        self.run(move |conn| modify_note(conn, note_id, changes))
+    }
+
+    fn revisions(&self, note_id: i32) -> RepoFuture<Vec<NoteRevision>> {
+        self.run(move |conn| find_note_revisions(conn, note_id))
+    }
+
+    fn find_revision(&self, note_id: i32, revision_id: i32) -> RepoFuture<Option<NoteRevision>> {
+        self.run(move |conn| find_note_revision(conn, note_id, revision_id))
+    }
+
+    fn delete(&self, note_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| remove_note(conn, note_id))
+    }
+}
+
+impl FileLinkRepository for SqliteRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<FileLink>> {
+        self.run(move |conn| find_file_links(conn, subject_id))
+    }
+
+    fn create(&self, subject_id: i32, link: NewFileLink) -> RepoFuture<FileLink> {
+        self.run(move |conn| insert_file_link(conn, subject_id, link))
+    }
+
+    fn owner(&self, file_link_id: i32) -> RepoFuture<Option<i32>> {
+        self.run(move |conn| {
+            find_owner(
+                conn,
+                "SELECT subjects.user_id FROM file_links
+                 JOIN subjects ON subjects.id = file_links.subject_id
+                 WHERE file_links.id = ?1",
+                file_link_id,
+            )
+        })
+    }
+
+    fn update(&self, file_link_id: i32, changes: FileLinkChanges) -> RepoFuture<Option<FileLink>> {
+        self.run(move |conn| modify_file_link(conn, file_link_id, changes))
+    }
+
+    fn delete(&self, file_link_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| remove_file_link(conn, file_link_id))
+    }
+}
+
+impl StoredFileRepository for SqliteRepository {
+    fn list(&self, subject_id: i32) -> RepoFuture<Vec<StoredFile>> {
+        self.run(move |conn| find_stored_files(conn, subject_id))
+    }
+
+    fn find(&self, file_id: i32) -> RepoFuture<Option<StoredFile>> {
+        self.run(move |conn| find_stored_file(conn, file_id))
+    }
+
+    fn create(&self, subject_id: i32, file: NewStoredFile) -> RepoFuture<StoredFile> {
+        self.run(move |conn| insert_stored_file(conn, subject_id, file))
+    }
+
+    fn owner(&self, file_id: i32) -> RepoFuture<Option<i32>> {
+        self.run(move |conn| {
+            find_owner(
+                conn,
+                "SELECT subjects.user_id FROM stored_files
+                 JOIN subjects ON subjects.id = stored_files.subject_id
+                 WHERE stored_files.id = ?1",
+                file_id,
+            )
+        })
+    }
+
+    fn delete(&self, file_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| remove_stored_file(conn, file_id))
+    }
+
+    fn hashes_for_user(&self, user_id: i32) -> RepoFuture<Vec<String>> {
+        self.run(move |conn| find_user_file_hashes(conn, user_id))
+    }
+
+    fn is_referenced(&self, sha256: String) -> RepoFuture<bool> {
+        self.run(move |conn| {
+            conn.query_row("SELECT EXISTS (SELECT 1 FROM stored_files WHERE sha256 = ?1)", [sha256], |row| row.get(0))
+        })
+    }
+}
+
+// Accounts keep their SQL next to their handlers; these only put it behind the repository traits
+impl AccountRepository for SqliteRepository {
+    fn status(&self, user_id: i32) -> RepoFuture<Option<AccountStatus>> {
+        self.run(move |conn| admin::find_status(conn, user_id))
+    }
+
+    fn username(&self, user_id: i32) -> RepoFuture<Option<String>> {
+        self.run(move |conn| account::find_username(conn, user_id))
+    }
+
+    fn password_hash(&self, user_id: i32) -> RepoFuture<Option<String>> {
+        self.run(move |conn| account::find_password_hash(conn, user_id))
+    }
+
+    fn locale(&self, user_id: i32) -> RepoFuture<Option<Locale>> {
+        self.run(move |conn| i18n::find_user_locale(conn, user_id))
+    }
+
+    fn update_password(&self, user_id: i32, password_hash: String, keep_session_id: i64) -> RepoFuture<()> {
+        self.run(move |conn| account::modify_password(conn, user_id, keep_session_id, &password_hash))
+    }
+
+    fn update_username(&self, user_id: i32, username: String) -> RepoFuture<()> {
+        self.run(move |conn| account::modify_username(conn, user_id, &username))
+    }
+
+    fn update_email(&self, user_id: i32, email: Option<String>) -> RepoFuture<()> {
+        self.run(move |conn| account::modify_email(conn, user_id, email.as_deref()))
+    }
+
+    fn update_locale(&self, user_id: i32, locale: Option<Locale>) -> RepoFuture<()> {
+        self.run(move |conn| i18n::set_user_locale(conn, user_id, locale))
+    }
+
+    fn delete(&self, user_id: i32) -> RepoFuture<()> {
+        self.run(move |conn| account::remove_user(conn, user_id))
+    }
+
+    fn promote(&self, username: String) -> RepoFuture<()> {
+        self.run(move |conn| admin::promote(conn, &username))
+    }
+}
+
+impl SessionRepository for SqliteRepository {
+    fn create(&self, user_id: i32, device: Option<String>) -> RepoFuture<(i64, String)> {
+        self.run(move |conn| sessions::create_session(conn, user_id, device.as_deref()))
+    }
+
+    fn touch(&self, session_id: i64, user_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| sessions::touch_session(conn, session_id, user_id))
+    }
+
+    fn rotate(&self, refresh_token: String) -> RepoFuture<Option<(i64, i32, String)>> {
+        self.run(move |conn| sessions::rotate_refresh_token(conn, &refresh_token))
+    }
+
+    fn revoke(&self, user_id: i32, session_id: i64) -> RepoFuture<bool> {
+        self.run(move |conn| sessions::revoke_session(conn, user_id, session_id))
+    }
+
+    fn revoke_all(&self, user_id: i32) -> RepoFuture<()> {
+        self.run(move |conn| sessions::revoke_all_sessions(conn, user_id).map(|_| ()))
+    }
+
+    fn list(&self, user_id: i32, current_session_id: i64) -> RepoFuture<Vec<Session>> {
+        self.run(move |conn| sessions::list_sessions(conn, user_id, current_session_id))
+    }
+}
+
+impl ApiTokenRepository for SqliteRepository {
+    fn create(&self, user_id: i32, name: String, scope: TokenScope) -> RepoFuture<(i64, String)> {
+        self.run(move |conn| api_tokens::insert_api_token(conn, user_id, &name, scope))
+    }
+
+    fn find(&self, token: String) -> RepoFuture<Option<(i32, TokenScope)>> {
+        self.run(move |conn| api_tokens::find_api_token(conn, &token))
+    }
+
+    fn list(&self, user_id: i32) -> RepoFuture<Vec<ApiToken>> {
+        self.run(move |conn| api_tokens::list_api_tokens(conn, user_id))
+    }
+
+    fn revoke(&self, user_id: i32, token_id: i64) -> RepoFuture<bool> {
+        self.run(move |conn| api_tokens::revoke_api_token(conn, user_id, token_id))
+    }
+}
+
+impl TwoFactorRepository for SqliteRepository {
+    fn state(&self, user_id: i32) -> RepoFuture<Option<TotpState>> {
+        self.run(move |conn| two_factor::find_totp_state(conn, user_id))
+    }
+
+    fn set_pending_secret(&self, user_id: i32, secret: String) -> RepoFuture<()> {
+        self.run(move |conn| two_factor::set_pending_secret(conn, user_id, &secret))
+    }
+
+    fn enable(&self, user_id: i32, recovery_code_hashes: Vec<String>, step: i64) -> RepoFuture<()> {
+        self.run(move |conn| two_factor::enable_totp(conn, user_id, &recovery_code_hashes, step))
+    }
+
+    fn disable(&self, user_id: i32) -> RepoFuture<()> {
+        self.run(move |conn| two_factor::disable_totp(conn, user_id))
+    }
+
+    fn use_step(&self, user_id: i32, step: i64) -> RepoFuture<bool> {
+        self.run(move |conn| two_factor::use_totp_step(conn, user_id, step))
+    }
+
+    fn unused_recovery_codes(&self, user_id: i32) -> RepoFuture<Vec<(i64, String)>> {
+        self.run(move |conn| two_factor::find_unused_recovery_codes(conn, user_id))
+    }
+
+    fn use_recovery_code(&self, code_id: i64) -> RepoFuture<bool> {
+        self.run(move |conn| two_factor::use_recovery_code(conn, code_id))
+    }
+}
+
+impl PasswordResetRepository for SqliteRepository {
+    fn find_recipient(
+        &self,
+        username: Option<String>,
+        email: Option<String>,
+    ) -> RepoFuture<Option<(i32, String, String)>> {
+        self.run(move |conn| password_reset::find_recipient(conn, username, email))
+    }
+
+    fn create(&self, user_id: i32) -> RepoFuture<String> {
+        self.run(move |conn| password_reset::create_reset_token(conn, user_id))
+    }
+
+    fn find(&self, token: String) -> RepoFuture<Option<(i64, i32, String)>> {
+        self.run(move |conn| password_reset::find_reset_token(conn, &token))
+    }
+
+    fn reset_password(&self, token_id: i64, user_id: i32, password_hash: String) -> RepoFuture<bool> {
+        self.run(move |conn| password_reset::reset_password(conn, token_id, user_id, &password_hash))
+    }
+}
+
+impl AdminRepository for SqliteRepository {
+    fn user_summaries(&self) -> RepoFuture<Vec<UserSummary>> {
+        self.run(admin::find_user_summaries)
+    }
+
+    fn disable(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| admin::disable_account(conn, admin_id, user_id))
+    }
+
+    fn enable(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| admin::enable_account(conn, admin_id, user_id))
+    }
+
+    fn require_password_reset(&self, admin_id: i32, user_id: i32) -> RepoFuture<Option<(String, Option<String>)>> {
+        self.run(move |conn| admin::require_password_reset(conn, admin_id, user_id))
+    }
+
+    fn delete_user(&self, admin_id: i32, user_id: i32) -> RepoFuture<bool> {
+        self.run(move |conn| admin::delete_account(conn, admin_id, user_id))
+    }
+
+    fn audit_log(&self, before_id: Option<i64>) -> RepoFuture<Vec<AuditEntry>> {
+        self.run(move |conn| admin::find_audit_entries(conn, before_id))
+    }
+}
+
+impl LoginAttemptRepository for SqliteRepository {
+    fn reserve(&self, keys: Vec<(String, &'static Policy)>) -> RepoFuture<i64> {
+        self.run(move |conn| rate_limit::reserve_attempts(conn, &keys))
+    }
+
+    fn blocked_for(&self, keys: Vec<String>) -> RepoFuture<i64> {
+        self.run(move |conn| rate_limit::seconds_blocked(conn, &keys))
+    }
+
+    fn record(&self, key: String, policy: &'static Policy) -> RepoFuture<()> {
+        self.run(move |conn| rate_limit::record_attempt(conn, &key, policy))
+    }
+
+    fn clear(&self, key: String) -> RepoFuture<()> {
+        self.run(move |conn| rate_limit::clear(conn, &key))
+    }
+
+    fn refund(&self, key: String) -> RepoFuture<()> {
+        self.run(move |conn| rate_limit::refund(conn, &key))
+    }
+}
+
+// Database functions
+fn find_owner(conn: &Connection, sql: &str, id: i32) -> Result<Option<i32>> {
+    conn.query_row(sql, [id], |row| row.get(0)).optional()
+}
+
+fn find_user(conn: &Connection, username: &str) -> Result<Option<User>> {
+    conn.query_row(
+        "SELECT id, username, password_hash, email, totp_enabled FROM users WHERE username = ?1 COLLATE NOCASE",
+        [username],
+        |row| {
+            Ok(User {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                password_hash: row.get(2)?,
+                email: row.get(3)?,
+                totp_enabled: row.get(4)?,
+            })
+        },
+    )
+    .optional()
+}
+
+fn find_tasks(conn: &Connection, user_id: i32) -> Result<Vec<Task>> {
+    let mut stmt = conn.prepare("SELECT id, title, status, note, user_id FROM tasks WHERE user_id = ?1")?;
+    let task_iter = stmt.query_map([user_id], |row| {
+        Ok(Task {
+            id: row.get(0)?,
+            title: row.get(1)?,
+            status: row.get(2)?,
+            note: row.get(3)?,
+            user_id: row.get(4)?,
+        })
+    })?;
+    task_iter.collect()
+}
+
+fn subject_from_row(row: &Row) -> Result<Subject> {
+    Ok(Subject {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        user_id: row.get(2)?,
+        color: row.get(3)?,
+        icon: row.get(4)?,
+        display_order: row.get(5)?,
+        archived: row.get(6)?,
+        professor: row.get(7)?,
+        course_code: row.get(8)?,
+    })
+}
+
+fn find_subjects(conn: &Connection, user_id: i32, include_archived: bool) -> Result<Vec<Subject>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM subjects WHERE user_id = ?1 AND (?2 OR NOT archived) ORDER BY display_order, id",
+        SUBJECT_COLUMNS
+    ))?;
+    let subject_iter = stmt.query_map(params![user_id, include_archived], subject_from_row)?;
+    subject_iter.collect()
+}
+
+fn find_subject(conn: &Connection, subject_id: i32) -> Result<Option<Subject>> {
+    conn.query_row(
+        &format!("SELECT {} FROM subjects WHERE id = ?1", SUBJECT_COLUMNS),
+        [subject_id],
+        subject_from_row,
+    )
+    .optional()
+}
+
+fn exam_date_from_row(row: &Row) -> Result<ExamDate> {
+    Ok(ExamDate {
+        id: row.get(0)?,
+        subject_id: row.get(1)?,
+        date: row.get(2)?,
+        duration_minutes: row.get(3)?,
+        // Every exam date was a midterm before there were kinds
+        kind: ExamKind::parse(&row.get::<_, String>(4)?).unwrap_or(ExamKind::Parcial),
+        location: row.get(5)?,
+        notes: row.get(6)?,
+        weight: row.get(7)?,
+    })
+}
+
+fn find_exam_dates(conn: &Connection, subject_id: i32) -> Result<Vec<ExamDate>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM exam_dates WHERE subject_id = ?1 ORDER BY date, id",
+        EXAM_DATE_COLUMNS
+    ))?;
+    let exam_date_iter = stmt.query_map([subject_id], exam_date_from_row)?;
+    exam_date_iter.collect()
+}
+
+fn find_exam_date(conn: &Connection, exam_date_id: i32) -> Result<Option<ExamDate>> {
+    conn.query_row(
+        &format!("SELECT {} FROM exam_dates WHERE id = ?1", EXAM_DATE_COLUMNS),
+        [exam_date_id],
+        exam_date_from_row,
+    )
+    .optional()
+}
+
+fn note_from_row(row: &Row) -> Result<Note> {
+    Ok(Note {
+        id: row.get(0)?,
+        subject_id: row.get(1)?,
+        title: row.get(2)?,
+        content: row.get(3)?,
+        created_at: row.get(4)?,
+        updated_at: row.get(5)?,
+    })
+}
+
+fn note_revision_from_row(row: &Row) -> Result<NoteRevision> {
+    Ok(NoteRevision {
+        id: row.get(0)?,
+        note_id: row.get(1)?,
+        title: row.get(2)?,
+        content: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+fn find_notes(conn: &Connection, subject_id: i32) -> Result<Vec<Note>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM notes WHERE subject_id = ?1 ORDER BY id", NOTE_COLUMNS))?;
+    let note_iter = stmt.query_map([subject_id], note_from_row)?;
+    note_iter.collect()
+}
+
+fn find_note(conn: &Connection, note_id: i32) -> Result<Option<Note>> {
+    conn.query_row(&format!("SELECT {} FROM notes WHERE id = ?1", NOTE_COLUMNS), [note_id], note_from_row)
+        .optional()
+}
+
+fn find_note_revisions(conn: &Connection, note_id: i32) -> Result<Vec<NoteRevision>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM note_revisions WHERE note_id = ?1 ORDER BY id DESC",
+        NOTE_REVISION_COLUMNS
+    ))?;
+    let revision_iter = stmt.query_map([note_id], note_revision_from_row)?;
+    revision_iter.collect()
+}
+
+fn find_note_revision(conn: &Connection, note_id: i32, revision_id: i32) -> Result<Option<NoteRevision>> {
+    conn.query_row(
+        &format!("SELECT {} FROM note_revisions WHERE id = ?1 AND note_id = ?2", NOTE_REVISION_COLUMNS),
+        [revision_id, note_id],
+        note_revision_from_row,
+    )
+    .optional()
+}
+
+fn file_link_from_row(row: &Row) -> Result<FileLink> {
+    Ok(FileLink {
+        id: row.get(0)?,
+        subject_id: row.get(1)?,
+        url: row.get(2)?,
+        label: row.get(3)?,
+        kind: row.get::<_, Option<String>>(4)?.as_deref().and_then(LinkKind::parse),
+        created_at: row.get(5)?,
+    })
+}
+
+fn find_file_links(conn: &Connection, subject_id: i32) -> Result<Vec<FileLink>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM file_links WHERE subject_id = ?1 ORDER BY id",
+        FILE_LINK_COLUMNS
+    ))?;
+    let file_link_iter = stmt.query_map([subject_id], file_link_from_row)?;
+    file_link_iter.collect()
+}
+
+fn find_file_link(conn: &Connection, file_link_id: i32) -> Result<Option<FileLink>> {
+    conn.query_row(
+        &format!("SELECT {} FROM file_links WHERE id = ?1", FILE_LINK_COLUMNS),
+        [file_link_id],
+        file_link_from_row,
+    )
+    .optional()
+}
+
+fn stored_file_from_row(row: &Row) -> Result<StoredFile> {
+    Ok(StoredFile {
+        id: row.get(0)?,
+        subject_id: row.get(1)?,
+        filename: row.get(2)?,
+        mime_type: row.get(3)?,
+        size: row.get(4)?,
+        sha256: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+fn find_stored_files(conn: &Connection, subject_id: i32) -> Result<Vec<StoredFile>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM stored_files WHERE subject_id = ?1 ORDER BY id",
+        STORED_FILE_COLUMNS
+    ))?;
+    let stored_file_iter = stmt.query_map([subject_id], stored_file_from_row)?;
+    stored_file_iter.collect()
+}
+
+fn find_stored_file(conn: &Connection, file_id: i32) -> Result<Option<StoredFile>> {
+    conn.query_row(
+        &format!("SELECT {} FROM stored_files WHERE id = ?1", STORED_FILE_COLUMNS),
+        [file_id],
+        stored_file_from_row,
+    )
+    .optional()
+}
+
+fn find_user_file_hashes(conn: &Connection, user_id: i32) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT stored_files.sha256 FROM stored_files
+         JOIN subjects ON subjects.id = stored_files.subject_id
+         WHERE subjects.user_id = ?1",
+    )?;
+    let hash_iter = stmt.query_map([user_id], |row| row.get(0))?;
+    hash_iter.collect()
+}
+
+// Database modification functions
+// Row ids are INTEGER PRIMARY KEY, so they are always small enough for the models' i32
+fn last_id(conn: &Connection) -> i32 {
+    conn.last_insert_rowid() as i32
+}
+
+fn insert_user(conn: &Connection, username: String, password_hash: String, email: Option<String>) -> Result<User> {
+    conn.execute(
+        "INSERT INTO users (username, password_hash, email) VALUES (?1, ?2, ?3)",
+        params![username, password_hash, email],
+    )?;
+    Ok(User {
+        id: last_id(conn),
+        username,
+        password_hash,
+        email,
+        totp_enabled: false,
+    })
+}
+
+fn insert_task(conn: &Connection, title: String, status: String, note: Option<String>, user_id: i32) -> Result<Task> {
+    let note = note.unwrap_or_default();
+    conn.execute(
+        "INSERT INTO tasks (title, status, note, user_id) VALUES (?1, ?2, ?3, ?4)",
+        params![title, status, note, user_id],
+    )?;
+    Ok(Task {
+        id: last_id(conn),
+        title,
+        status,
+        note: Some(note),
+        user_id,
+    })
+}
+
+fn count_owned(conn: &Connection, sql: &str, user_id: i32) -> Result<i64> {
+    conn.query_row(sql, [user_id], |row| row.get(0))
+}
+
+fn remove_task(conn: &Connection, task_id: i32) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM tasks WHERE id = ?1", [task_id])? > 0)
+}
+
+fn modify_task_status(conn: &Connection, task_id: i32, new_status: &str) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE tasks SET status = ?1 WHERE id = ?2",
+        params![new_status, task_id],
+    )?;
+    Ok(updated > 0)
+}
+
+fn modify_task_note(conn: &Connection, task_id: i32, new_note: &str) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE tasks SET note = ?1 WHERE id = ?2",
+        params![new_note, task_id],
+    )?;
+    Ok(updated > 0)
+}
+
+fn insert_subject(conn: &Connection, name: String, user_id: i32) -> Result<Subject> {
+    conn.execute(
+        "INSERT INTO subjects (name, user_id) VALUES (?1, ?2)",
+        params![name, user_id],
+    )?;
+    Ok(Subject::new(last_id(conn), name, user_id))
+}
+
+fn modify_subject(conn: &Connection, subject_id: i32, changes: SubjectChanges) -> Result<Option<Subject>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut subject = match find_subject(&tx, subject_id)? {
+        Some(subject) => subject,
+        None => return Ok(None),
+    };
+    changes.apply(&mut subject);
+    tx.execute(
+        "UPDATE subjects SET name = ?1, color = ?2, icon = ?3, display_order = ?4, archived = ?5,
+             professor = ?6, course_code = ?7
+         WHERE id = ?8",
+        params![
+            subject.name,
+            subject.color,
+            subject.icon,
+            subject.display_order,
+            subject.archived,
+            subject.professor,
+            subject.course_code,
+            subject_id
+        ],
+    )?;
+    tx.commit()?;
+    Ok(Some(subject))
+}
+
+fn remove_subject(conn: &Connection, subject_id: i32) -> Result<bool> {
+    // Exam dates, notes, file links and uploaded files go with it through ON DELETE CASCADE
+    Ok(conn.execute("DELETE FROM subjects WHERE id = ?1", [subject_id])? > 0)
+}
+
+fn insert_exam_date(conn: &Connection, subject_id: i32, exam: NewExamDate) -> Result<ExamDate> {
+    conn.execute(
+        "INSERT INTO exam_dates (subject_id, date, duration_minutes, kind, location, notes, weight)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            subject_id,
+            exam.date,
+            exam.duration_minutes,
+            exam.kind.as_str(),
+            exam.location,
+            exam.notes,
+            exam.weight
+        ],
+    )?;
+    Ok(ExamDate::new(last_id(conn), subject_id, exam))
+}
+
+fn modify_exam_date(conn: &Connection, exam_date_id: i32, changes: ExamDateChanges) -> Result<Option<ExamDate>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut exam_date = match find_exam_date(&tx, exam_date_id)? {
+        Some(exam_date) => exam_date,
+        None => return Ok(None),
+    };
+    changes.apply(&mut exam_date);
+    tx.execute(
+        "UPDATE exam_dates SET date = ?1, duration_minutes = ?2, kind = ?3, location = ?4, notes = ?5, weight = ?6
+         WHERE id = ?7",
+        params![
+            exam_date.date,
+            exam_date.duration_minutes,
+            exam_date.kind.as_str(),
+            exam_date.location,
+            exam_date.notes,
+            exam_date.weight,
+            exam_date_id
+        ],
+    )?;
+    tx.commit()?;
+    Ok(Some(exam_date))
+}
+
+fn remove_exam_date(conn: &Connection, exam_date_id: i32) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM exam_dates WHERE id = ?1", [exam_date_id])? > 0)
+}
+
+fn insert_note(conn: &Connection, subject_id: i32, title: Option<String>, content: String) -> Result<Note> {
+    let now = now_secs();
+    conn.execute(
+        "INSERT INTO notes (subject_id, title, content, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+        params![subject_id, title, content, now],
+    )?;
+    Ok(Note::new(last_id(conn), subject_id, title, content, now))
+}
+
+fn modify_note(conn: &Connection, note_id: i32, changes: NoteChanges) -> Result<Option<Note>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut note = match find_note(&tx, note_id)? {
+        Some(note) => note,
+        None => return Ok(None),
+    };
+    let previous = note.clone();
+    if changes.apply(&mut note, now_secs()) {
+        tx.execute(
+            "INSERT INTO note_revisions (note_id, title, content, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![note_id, previous.title, previous.content, previous.updated_at],
+        )?;
+        tx.execute(
+            "UPDATE notes SET title = ?1, content = ?2, updated_at = ?3 WHERE id = ?4",
+            params![note.title, note.content, note.updated_at, note_id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(Some(note))
+}
+
+fn remove_note(conn: &Connection, note_id: i32) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM notes WHERE id = ?1", [note_id])? > 0)
+}
+
+fn insert_file_link(conn: &Connection, subject_id: i32, link: NewFileLink) -> Result<FileLink> {
+    let now = now_secs();
+    conn.execute(
+        "INSERT INTO file_links (subject_id, url, label, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![subject_id, link.url, link.label, link.kind.map(LinkKind::as_str), now],
+    )?;
+    Ok(FileLink::new(last_id(conn), subject_id, link, now))
+}
+
+fn modify_file_link(conn: &Connection, file_link_id: i32, changes: FileLinkChanges) -> Result<Option<FileLink>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut file_link = match find_file_link(&tx, file_link_id)? {
+        Some(file_link) => file_link,
+        None => return Ok(None),
+    };
+    changes.apply(&mut file_link);
+    tx.execute(
+        "UPDATE file_links SET url = ?1, label = ?2, kind = ?3 WHERE id = ?4",
+        params![file_link.url, file_link.label, file_link.kind.map(LinkKind::as_str), file_link_id],
+    )?;
+    tx.commit()?;
+    Ok(Some(file_link))
+}
+
+fn remove_file_link(conn: &Connection, file_link_id: i32) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM file_links WHERE id = ?1", [file_link_id])? > 0)
+}
+
+fn insert_stored_file(conn: &Connection, subject_id: i32, file: NewStoredFile) -> Result<StoredFile> {
+    let now = now_secs();
+    conn.execute(
+        "INSERT INTO stored_files (subject_id, filename, mime_type, size, sha256, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![subject_id, file.filename, file.mime_type, file.size, file.sha256, now],
+    )?;
+    Ok(StoredFile::new(last_id(conn), subject_id, file, now))
+}
+
+fn remove_stored_file(conn: &Connection, file_id: i32) -> Result<bool> {
+    Ok(conn.execute("DELETE FROM stored_files WHERE id = ?1", [file_id])? > 0)
+}
@@ -0,0 +1,280 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::admin::login_blocked;
+use crate::auth::{create_token, now_secs, JwtKeys, SessionUser, ACCESS_TOKEN_TTL_SECS};
+use crate::error::{AppError, AppResult};
+use crate::i18n::{Locale, Message};
+use crate::repository::Accounts;
+
+// How long a refresh token can go unused before the session expires (30 days)
+pub const REFRESH_TOKEN_TTL_SECS: i64 = 30 * 24 * 60 * 60;
+
+// last_seen_at is only rewritten when it is older than this, to avoid a write per request
+pub const LAST_SEEN_RESOLUTION_SECS: i64 = 60;
+
+// Longest User-Agent we keep as the device name
+const MAX_DEVICE_LEN: usize = 200;
+
+// Session data structure, as listed to its owner
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub id: i64,
+    pub device: Option<String>,
+    pub created_at: i64,
+    pub last_seen_at: i64,
+    pub expires_at: i64,
+    pub current: bool,
+}
+
+// Credentials handed to the client when a session starts or is refreshed
+#[derive(Debug, Serialize)]
+pub struct SessionTokens {
+    pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+// Random opaque token, hex encoded
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// Only the SHA-256 of a token is stored, so a leaked database cannot be replayed
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Name of the device a request comes from, taken from its User-Agent
+pub fn device_name(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get("User-Agent")
+        .and_then(|value| value.to_str().ok())
+        .map(|agent| agent.chars().take(MAX_DEVICE_LEN).collect())
+}
+
+// Open a new session for the user and sign its first access token.
+// Disabled accounts and accounts waiting for a forced password reset are turned away here.
+pub async fn start_session(
+    accounts: &Accounts,
+    keys: &JwtKeys,
+    user_id: i32,
+    device: Option<String>,
+) -> AppResult<SessionTokens> {
+    let status = accounts.users.status(user_id).await?.ok_or(AppError::NotFound("user_not_found"))?;
+    if let Some(reason) = login_blocked(&status) {
+        return Err(reason);
+    }
+    let (session_id, refresh_token) = accounts.sessions.create(user_id, device).await?;
+    session_tokens(keys, user_id, session_id, refresh_token)
+}
+
+// Body returned once a login (with or without 2FA) succeeds
+pub fn login_response(locale: Locale, user_id: i32, tokens: SessionTokens) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "message": locale.text("login_succeeded"),
+        "user_id": user_id,
+        "token": tokens.token,
+        "refresh_token": tokens.refresh_token,
+        "expires_in": tokens.expires_in,
+    }))
+}
+
+fn session_tokens(
+    keys: &JwtKeys,
+    user_id: i32,
+    session_id: i64,
+    refresh_token: String,
+) -> AppResult<SessionTokens> {
+    let token = create_token(keys, user_id, session_id).map_err(AppError::internal)?;
+    Ok(SessionTokens {
+        token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_TTL_SECS,
+    })
+}
+
+// Handler functions
+pub async fn refresh(
+    refresh_info: web::Json<RefreshRequest>,
+    accounts: web::Data<Accounts>,
+    jwt_keys: web::Data<JwtKeys>,
+) -> AppResult<HttpResponse> {
+    let refresh_token = refresh_info.into_inner().refresh_token;
+    let rotated = accounts.sessions.rotate(refresh_token).await?;
+
+    match rotated {
+        Some((session_id, user_id, refresh_token)) => {
+            let tokens = session_tokens(&jwt_keys, user_id, session_id, refresh_token)?;
+            Ok(HttpResponse::Ok().json(tokens))
+        }
+        None => Err(AppError::Unauthorized("session_expired")),
+    }
+}
+
+pub async fn logout(user: SessionUser, accounts: web::Data<Accounts>) -> AppResult<Message> {
+    accounts.sessions.revoke(user.user_id, user.session_id).await?;
+    Ok(Message("session_closed"))
+}
+
+pub async fn logout_all(user: SessionUser, accounts: web::Data<Accounts>) -> AppResult<Message> {
+    accounts.sessions.revoke_all(user.user_id).await?;
+    Ok(Message("all_sessions_closed"))
+}
+
+pub async fn get_sessions(user: SessionUser, accounts: web::Data<Accounts>) -> AppResult<HttpResponse> {
+    let sessions = accounts.sessions.list(user.user_id, user.session_id).await?;
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+pub async fn delete_session(
+    user: SessionUser,
+    session_id: web::Path<i64>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<Message> {
+    if !accounts.sessions.revoke(user.user_id, session_id.into_inner()).await? {
+        return Err(AppError::NotFound("session_not_found"));
+    }
+    Ok(Message("session_closed"))
+}
+
+// Database functions
+pub fn create_session(conn: &Connection, user_id: i32, device: Option<&str>) -> Result<(i64, String)> {
+    let refresh_token = generate_token();
+    let now = now_secs();
+    conn.execute(
+        "INSERT INTO sessions (user_id, refresh_token_hash, device, created_at, last_seen_at, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?4, ?5)",
+        params![user_id, hash_token(&refresh_token), device, now, now + REFRESH_TOKEN_TTL_SECS],
+    )?;
+    Ok((conn.last_insert_rowid(), refresh_token))
+}
+
+// Check that a session is still active and record that it was just used
+pub fn touch_session(conn: &Connection, session_id: i64, user_id: i32) -> Result<bool> {
+    let now = now_secs();
+    let active = conn
+        .query_row(
+            "SELECT id FROM sessions
+             WHERE id = ?1 AND user_id = ?2 AND revoked_at IS NULL AND expires_at > ?3",
+            params![session_id, user_id, now],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?
+        .is_some();
+    if active {
+        conn.execute(
+            "UPDATE sessions SET last_seen_at = ?1 WHERE id = ?2 AND last_seen_at < ?1 - ?3",
+            params![now, session_id, LAST_SEEN_RESOLUTION_SECS],
+        )?;
+    }
+    Ok(active)
+}
+
+// Swap a valid refresh token for a new one, so every refresh token works only once
+pub fn rotate_refresh_token(conn: &Connection, refresh_token: &str) -> Result<Option<(i64, i32, String)>> {
+    let now = now_secs();
+    let tx = conn.unchecked_transaction()?;
+    let session = tx
+        .query_row(
+            "SELECT id, user_id FROM sessions
+             WHERE refresh_token_hash = ?1 AND revoked_at IS NULL AND expires_at > ?2",
+            params![hash_token(refresh_token), now],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i32>(1)?)),
+        )
+        .optional()?;
+
+    match session {
+        Some((session_id, user_id)) => {
+            let new_token = generate_token();
+            tx.execute(
+                "UPDATE sessions SET refresh_token_hash = ?1, last_seen_at = ?2, expires_at = ?3 WHERE id = ?4",
+                params![hash_token(&new_token), now, now + REFRESH_TOKEN_TTL_SECS, session_id],
+            )?;
+            tx.commit()?;
+            Ok(Some((session_id, user_id, new_token)))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn revoke_session(conn: &Connection, user_id: i32, session_id: i64) -> Result<bool> {
+    let revoked = conn.execute(
+        "UPDATE sessions SET revoked_at = ?1 WHERE id = ?2 AND user_id = ?3 AND revoked_at IS NULL",
+        params![now_secs(), session_id, user_id],
+    )?;
+    Ok(revoked > 0)
+}
+
+pub fn revoke_all_sessions(conn: &Connection, user_id: i32) -> Result<usize> {
+    conn.execute(
+        "UPDATE sessions SET revoked_at = ?1 WHERE user_id = ?2 AND revoked_at IS NULL",
+        params![now_secs(), user_id],
+    )
+}
+
+// Used after a password change, so only the device that made the change stays logged in
+pub fn revoke_other_sessions(conn: &Connection, user_id: i32, keep_session_id: i64) -> Result<usize> {
+    conn.execute(
+        "UPDATE sessions SET revoked_at = ?1 WHERE user_id = ?2 AND id != ?3 AND revoked_at IS NULL",
+        params![now_secs(), user_id, keep_session_id],
+    )
+}
+
+pub fn list_sessions(conn: &Connection, user_id: i32, current_session_id: i64) -> Result<Vec<Session>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, device, created_at, last_seen_at, expires_at FROM sessions
+         WHERE user_id = ?1 AND revoked_at IS NULL AND expires_at > ?2
+         ORDER BY last_seen_at DESC",
+    )?;
+    let session_iter = stmt.query_map(params![user_id, now_secs()], |row| {
+        let id: i64 = row.get(0)?;
+        Ok(Session {
+            id,
+            device: row.get(1)?,
+            created_at: row.get(2)?,
+            last_seen_at: row.get(3)?,
+            expires_at: row.get(4)?,
+            current: id == current_session_id,
+        })
+    })?;
+    session_iter.collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Open a session directly in the database and return an access token for it
+    pub fn login_token(conn: &Connection, keys: &JwtKeys, user_id: i32) -> String {
+        let (session_id, _) = create_session(conn, user_id, Some("test")).unwrap();
+        create_token(keys, user_id, session_id).unwrap()
+    }
+
+    #[test]
+    fn refresh_token_works_only_once() {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::migrations::run(&conn).unwrap();
+        conn.execute("INSERT INTO users (id, username, password_hash) VALUES (1, 'alice', 'x')", [])
+            .unwrap();
+        let (session_id, refresh_token) = create_session(&conn, 1, None).unwrap();
+
+        let (rotated_id, user_id, new_token) = rotate_refresh_token(&conn, &refresh_token).unwrap().unwrap();
+        assert_eq!((rotated_id, user_id), (session_id, 1));
+        assert!(rotate_refresh_token(&conn, &refresh_token).unwrap().is_none());
+
+        revoke_session(&conn, 1, session_id).unwrap();
+        assert!(rotate_refresh_token(&conn, &new_token).unwrap().is_none());
+        assert!(!touch_session(&conn, session_id, 1).unwrap());
+    }
+}
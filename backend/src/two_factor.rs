@@ -0,0 +1,352 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use bcrypt::{hash, verify};
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::Deserialize;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::auth::{now_secs, validate_challenge_token, JwtKeys, SessionUser};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::i18n::{Locale, Message};
+use crate::rate_limit;
+use crate::repository::Accounts;
+use crate::sessions::{device_name, login_response, start_session};
+
+// Name shown next to the account in authenticator apps
+const ISSUER: &str = "ClassMate";
+
+// Recovery codes handed out when 2FA is turned on
+const RECOVERY_CODE_COUNT: usize = 10;
+
+const TOTP_STEP_SECS: u64 = 30;
+
+const INVALID_CODE: AppError = AppError::Unauthorized("invalid_code");
+const ALREADY_ENABLED: AppError = AppError::Conflict("two_factor_already_enabled");
+
+// Request structures
+#[derive(Debug, Deserialize)]
+pub struct CodeRequest {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginSecondStepRequest {
+    challenge_token: String,
+    code: String,
+}
+
+// 2FA state of an account
+#[derive(Debug, Clone)]
+pub struct TotpState {
+    pub username: String,
+    pub secret: Option<String>,
+    pub enabled: bool,
+}
+
+// Without skew, so each check below answers for exactly one time step
+fn build_totp(secret: &str, username: &str) -> Option<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    TOTP::new(Algorithm::SHA1, 6, 0, TOTP_STEP_SECS, bytes, Some(ISSUER.to_string()), username.to_string()).ok()
+}
+
+// The time step the code belongs to, allowing one step of clock drift either way
+fn totp_step(state: &TotpState, code: &str) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let totp = build_totp(state.secret.as_deref()?, &state.username)?;
+    let current = now_secs() as u64 / TOTP_STEP_SECS;
+    (current - 1..=current + 1)
+        .find(|step| totp.check(&code, step * TOTP_STEP_SECS))
+        .map(|step| step as i64)
+}
+
+// Recovery codes are compared case-insensitively and without separators
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn generate_recovery_code() -> String {
+    let mut bytes = [0u8; 5];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let code = hex::encode(bytes);
+    format!("{}-{}", &code[..5], &code[5..])
+}
+
+async fn totp_state(accounts: &Accounts, user_id: i32) -> AppResult<TotpState> {
+    accounts.two_factor.state(user_id).await?.ok_or(AppError::NotFound("user_not_found"))
+}
+
+// Accept either a current TOTP code newer than the last one used, or an unused recovery code
+async fn second_factor_matches(accounts: &Accounts, user_id: i32, code: String) -> AppResult<bool> {
+    let state = totp_state(accounts, user_id).await?;
+    if !state.enabled {
+        return Ok(false);
+    }
+    if let Some(step) = totp_step(&state, &code) {
+        return Ok(accounts.two_factor.use_step(user_id, step).await?);
+    }
+
+    let code = normalize_recovery_code(&code);
+    if code.is_empty() {
+        return Ok(false);
+    }
+    let candidates = accounts.two_factor.unused_recovery_codes(user_id).await?;
+    // bcrypt again, so the comparisons run on the blocking thread pool
+    let matched = web::block(move || {
+        candidates
+            .into_iter()
+            .find(|(_, code_hash)| verify(&code, code_hash).unwrap_or(false))
+            .map(|(id, _)| id)
+    })
+    .await
+    .map_err(AppError::internal)?;
+    match matched {
+        Some(code_id) => Ok(accounts.two_factor.use_recovery_code(code_id).await?),
+        None => Ok(false),
+    }
+}
+
+// Handler functions
+pub async fn enroll(user: SessionUser, accounts: web::Data<Accounts>) -> AppResult<HttpResponse> {
+    let state = totp_state(&accounts, user.user_id).await?;
+    if state.enabled {
+        return Err(ALREADY_ENABLED);
+    }
+
+    let mut bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = Secret::Raw(bytes.to_vec()).to_encoded().to_string();
+    let totp = build_totp(&secret, &state.username)
+        .ok_or_else(|| AppError::internal("generated TOTP secret was rejected"))?;
+
+    // The secret stays pending until a code generated from it is verified
+    accounts.two_factor.set_pending_secret(user.user_id, secret.clone()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "secret": secret,
+        "otpauth_uri": totp.get_url(),
+    })))
+}
+
+pub async fn verify_enrollment(
+    req: HttpRequest,
+    user: SessionUser,
+    code_info: web::Json<CodeRequest>,
+    accounts: web::Data<Accounts>,
+    config: web::Data<Config>,
+) -> AppResult<HttpResponse> {
+    let state = totp_state(&accounts, user.user_id).await?;
+    if state.enabled {
+        return Err(ALREADY_ENABLED);
+    }
+    if state.secret.is_none() {
+        return Err(AppError::BadRequest("two_factor_not_started"));
+    }
+    let step = totp_step(&state, &code_info.code).ok_or(INVALID_CODE)?;
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect();
+    let codes = recovery_codes.clone();
+    let cost = config.bcrypt_cost;
+    let code_hashes = web::block(move || {
+        codes
+            .iter()
+            .map(|code| hash(normalize_recovery_code(code), cost))
+            .collect::<std::result::Result<Vec<_>, _>>()
+    })
+    .await
+    .map_err(AppError::internal)?
+    .map_err(AppError::internal)?;
+
+    accounts.two_factor.enable(user.user_id, code_hashes, step).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": Locale::of(&req).text("two_factor_enabled"),
+        "recovery_codes": recovery_codes,
+    })))
+}
+
+pub async fn disable(
+    user: SessionUser,
+    code_info: web::Json<CodeRequest>,
+    accounts: web::Data<Accounts>,
+) -> AppResult<Message> {
+    // Guesses here count against the same budget as the second login step
+    let key = rate_limit::two_factor_key(user.user_id);
+    rate_limit::check(&accounts, &[&key]).await?;
+
+    if !second_factor_matches(&accounts, user.user_id, code_info.into_inner().code).await? {
+        accounts.login_attempts.record(key, &rate_limit::LOGIN_PER_USERNAME).await?;
+        return Err(INVALID_CODE);
+    }
+    accounts.login_attempts.clear(key).await?;
+    accounts.two_factor.disable(user.user_id).await?;
+    Ok(Message("two_factor_disabled"))
+}
+
+// Second login step: trade the challenge token from /login and a code for a session
+pub async fn login_second_step(
+    req: HttpRequest,
+    login_info: web::Json<LoginSecondStepRequest>,
+    accounts: web::Data<Accounts>,
+    jwt_keys: web::Data<JwtKeys>,
+) -> AppResult<HttpResponse> {
+    let user_id = validate_challenge_token(&jwt_keys, &login_info.challenge_token)
+        .ok_or(AppError::Unauthorized("challenge_expired"))?;
+
+    let key = rate_limit::two_factor_key(user_id);
+    rate_limit::check(&accounts, &[&key]).await?;
+
+    if !second_factor_matches(&accounts, user_id, login_info.into_inner().code).await? {
+        accounts.login_attempts.record(key, &rate_limit::LOGIN_PER_USERNAME).await?;
+        return Err(INVALID_CODE);
+    }
+    accounts.login_attempts.clear(key).await?;
+    let tokens = start_session(&accounts, &jwt_keys, user_id, device_name(&req)).await?;
+    Ok(login_response(Locale::of(&req), user_id, tokens))
+}
+
+// Database functions
+pub fn find_totp_state(conn: &Connection, user_id: i32) -> Result<Option<TotpState>> {
+    conn.query_row(
+        "SELECT username, totp_secret, totp_enabled FROM users WHERE id = ?1",
+        [user_id],
+        |row| {
+            Ok(TotpState {
+                username: row.get(0)?,
+                secret: row.get(1)?,
+                enabled: row.get(2)?,
+            })
+        },
+    )
+    .optional()
+}
+
+pub fn set_pending_secret(conn: &Connection, user_id: i32, secret: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET totp_secret = ?1, totp_enabled = 0, totp_last_step = NULL WHERE id = ?2",
+        params![secret, user_id],
+    )?;
+    Ok(())
+}
+
+// The code that confirmed enrollment counts as used
+pub fn enable_totp(conn: &Connection, user_id: i32, recovery_code_hashes: &[String], step: i64) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE users SET totp_enabled = 1, totp_last_step = ?1 WHERE id = ?2",
+        params![step, user_id],
+    )?;
+    tx.execute("DELETE FROM recovery_codes WHERE user_id = ?1", [user_id])?;
+    for code_hash in recovery_code_hashes {
+        tx.execute(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?1, ?2)",
+            params![user_id, code_hash],
+        )?;
+    }
+    tx.commit()
+}
+
+pub fn disable_totp(conn: &Connection, user_id: i32) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL WHERE id = ?1",
+        [user_id],
+    )?;
+    tx.execute("DELETE FROM recovery_codes WHERE user_id = ?1", [user_id])?;
+    tx.commit()
+}
+
+// Record the step as used, unless it is not newer than the last one, which makes it a replay.
+// A single statement, so two requests with the same code cannot both get through.
+pub fn use_totp_step(conn: &Connection, user_id: i32, step: i64) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE users SET totp_last_step = ?1 WHERE id = ?2 AND (totp_last_step IS NULL OR totp_last_step < ?1)",
+        params![step, user_id],
+    )?;
+    Ok(updated == 1)
+}
+
+pub fn find_unused_recovery_codes(conn: &Connection, user_id: i32) -> Result<Vec<(i64, String)>> {
+    let mut stmt =
+        conn.prepare("SELECT id, code_hash FROM recovery_codes WHERE user_id = ?1 AND used_at IS NULL ORDER BY id")?;
+    let code_iter = stmt.query_map([user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    code_iter.collect()
+}
+
+// A single statement, so two requests with the same code cannot both burn it
+pub fn use_recovery_code(conn: &Connection, code_id: i64) -> Result<bool> {
+    let updated = conn.execute(
+        "UPDATE recovery_codes SET used_at = ?1 WHERE id = ?2 AND used_at IS NULL",
+        params![now_secs(), code_id],
+    )?;
+    Ok(updated == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{app, bearer, fixture};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_service, TestRequest};
+
+    #[actix_web::test]
+    async fn accepts_current_code_and_each_recovery_code_once() {
+        let f = fixture();
+        let accounts = Accounts::sqlite(f.pool.clone());
+        let conn = f.pool.get().unwrap();
+
+        let secret = Secret::Raw(vec![7u8; 20]).to_encoded().to_string();
+        set_pending_secret(&conn, 1, &secret).unwrap();
+        let recovery_code = generate_recovery_code();
+        let code_hash = hash(normalize_recovery_code(&recovery_code), 4).unwrap();
+        let totp = build_totp(&secret, "alice").unwrap();
+        let step = now_secs() / TOTP_STEP_SECS as i64;
+        enable_totp(&conn, 1, &[code_hash], step - 1).unwrap();
+        let matches = |code: &str| second_factor_matches(&accounts, 1, code.to_string());
+
+        // The code that confirmed enrollment and anything older are spent; each newer one works once
+        let previous = totp.generate((step - 1) as u64 * TOTP_STEP_SECS);
+        assert!(!matches(&previous).await.unwrap());
+        let current = totp.generate_current().unwrap();
+        assert!(matches(&current).await.unwrap());
+        assert!(!matches(&current).await.unwrap());
+        assert!(!matches("not-a-code").await.unwrap());
+
+        let typed = recovery_code.to_uppercase();
+        assert!(matches(&typed).await.unwrap());
+        assert!(!matches(&typed).await.unwrap());
+
+        disable_totp(&conn, 1).unwrap();
+        assert!(!matches(&current).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn disabling_shares_the_login_rate_limit() {
+        let f = fixture();
+        let conn = f.pool.get().unwrap();
+        let secret = Secret::Raw(vec![7u8; 20]).to_encoded().to_string();
+        set_pending_secret(&conn, 1, &secret).unwrap();
+        enable_totp(&conn, 1, &[], 0).unwrap();
+
+        let app = app!(f);
+        let disable = |code: &str| {
+            TestRequest::post()
+                .uri("/2fa/disable")
+                .insert_header(bearer(&f.alice_token))
+                .set_json(serde_json::json!({ "code": code }))
+                .to_request()
+        };
+
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            statuses.push(call_service(&app, disable("000000")).await.status());
+        }
+        assert_eq!(statuses[..4], [StatusCode::UNAUTHORIZED; 4]);
+        assert_eq!(statuses[4], StatusCode::TOO_MANY_REQUESTS);
+        // Even the right code waits until the block is over
+        let current = build_totp(&secret, "alice").unwrap().generate_current().unwrap();
+        assert_eq!(call_service(&app, disable(&current)).await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(find_totp_state(&conn, 1).unwrap().unwrap().enabled);
+    }
+}
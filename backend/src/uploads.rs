@@ -0,0 +1,514 @@
+use actix_files::NamedFile;
+use actix_multipart::{Field, Multipart};
+use actix_web::http::header::{self, DispositionType, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::lock::Mutex as AsyncMutex;
+use futures_util::TryStreamExt;
+use std::io;
+use std::sync::OnceLock;
+
+use crate::api_tokens::Permission;
+use crate::auth::AuthenticatedUser;
+use crate::authz::{authorize, Resource};
+use crate::blobs::{BlobStore, StagedBlob};
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::i18n::Message;
+use crate::repository::{NewStoredFile, Repositories, StoredFile};
+
+const STORED_FILE_NOT_FOUND: AppError = AppError::NotFound("stored_file_not_found");
+const INVALID_UPLOAD: AppError = AppError::BadRequest("invalid_upload");
+
+// Longest file name kept for an upload, in characters
+const FILENAME_MAX_LEN: usize = 255;
+
+// Run blob store work on the blocking pool, like db::run does for queries
+async fn blocking<T, F>(f: F) -> AppResult<T>
+where
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(AppError::internal)?.map_err(AppError::internal)
+}
+
+// Putting a blob in place together with the row that points at it, and checking that no row points at
+// a blob before deleting it, each happen under the lock of the blob's hash. Otherwise a release could
+// delete a blob that an upload of the same content has just found already stored. Striped by the
+// first byte of the hash; the upload directory belongs to this one server process.
+fn blob_lock(sha256: &str) -> &'static AsyncMutex<()> {
+    static LOCKS: OnceLock<Vec<AsyncMutex<()>>> = OnceLock::new();
+    let locks = LOCKS.get_or_init(|| (0..256).map(|_| AsyncMutex::new(())).collect());
+    let stripe = sha256.get(..2).and_then(|byte| usize::from_str_radix(byte, 16).ok()).unwrap_or(0);
+    &locks[stripe]
+}
+
+// Only call with the hash's lock held
+async fn delete_if_unreferenced(repos: &Repositories, blobs: &web::Data<dyn BlobStore>, sha256: String) {
+    match repos.files.is_referenced(sha256.clone()).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let store = blobs.clone().into_inner();
+            let hash = sha256.clone();
+            if let Err(error) = blocking(move || store.delete(&hash)).await {
+                log::warn!("Could not delete blob {}: {}", sha256, error);
+            }
+        }
+        Err(error) => log::warn!("Could not check whether blob {} is still used: {}", sha256, error),
+    }
+}
+
+// Delete the blobs that no file points at anymore. The rows are already gone by now,
+// so a blob that cannot be removed is only logged.
+pub async fn release_blobs(repos: &Repositories, blobs: &web::Data<dyn BlobStore>, hashes: Vec<String>) {
+    for sha256 in hashes {
+        let _guard = blob_lock(&sha256).lock().await;
+        delete_if_unreferenced(repos, blobs, sha256).await;
+    }
+}
+
+// Only the last component of the name the client sent, without control characters
+fn clean_filename(raw: &str) -> Option<String> {
+    let name = raw.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." || name.chars().count() > FILENAME_MAX_LEN {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+// Stream one part to the blob store, giving up with `too_large` as soon as it grows past the limit
+async fn stage_field(
+    field: &mut Field,
+    blobs: &web::Data<dyn BlobStore>,
+    max_bytes: u64,
+    too_large: AppError,
+) -> AppResult<StagedBlob> {
+    let store = blobs.clone().into_inner();
+    let mut staged: StagedBlob = blocking(move || store.stage()).await?;
+    while let Some(chunk) = field.try_next().await.map_err(|_| INVALID_UPLOAD)? {
+        if staged.size() + chunk.len() as u64 > max_bytes {
+            return Err(too_large);
+        }
+        staged = blocking(move || staged.write(&chunk).map(|_| staged)).await?;
+    }
+    Ok(staged)
+}
+
+// Every part of the form that carries a file, still staged; other fields are skipped. Besides the
+// size of each file, the number of files and their total size are capped for the whole request.
+async fn read_files(
+    payload: &mut Multipart,
+    blobs: &web::Data<dyn BlobStore>,
+    config: &Config,
+) -> AppResult<Vec<(NewStoredFile, StagedBlob)>> {
+    let mut files = Vec::new();
+    let mut total_bytes = 0;
+    while let Some(mut field) = payload.try_next().await.map_err(|_| INVALID_UPLOAD)? {
+        let raw_name = match field.content_disposition().and_then(|disposition| disposition.get_filename()) {
+            Some(raw_name) => raw_name.to_string(),
+            None => continue,
+        };
+        if files.len() as u64 >= config.max_upload_files {
+            return Err(AppError::PayloadTooLarge("upload_too_many_files"));
+        }
+        let filename = clean_filename(&raw_name).ok_or(AppError::BadRequest("upload_filename_invalid"))?;
+        let mime_type = field
+            .content_type()
+            .map(|mime| mime.essence_str().to_string())
+            .unwrap_or_else(|| mime::APPLICATION_OCTET_STREAM.to_string());
+        let left = config.max_upload_request_bytes.saturating_sub(total_bytes);
+        let staged = if config.max_upload_bytes <= left {
+            stage_field(&mut field, blobs, config.max_upload_bytes, AppError::PayloadTooLarge("upload_too_large")).await?
+        } else {
+            stage_field(&mut field, blobs, left, AppError::PayloadTooLarge("upload_request_too_large")).await?
+        };
+        total_bytes += staged.size();
+        let file = NewStoredFile {
+            filename,
+            mime_type,
+            size: i64::try_from(staged.size()).unwrap_or(i64::MAX),
+            sha256: staged.sha256(),
+        };
+        files.push((file, staged));
+    }
+    Ok(files)
+}
+
+// Commits the blob and writes its row under the hash's lock, taking the blob back out if the row fails
+async fn store_file(
+    repos: &Repositories,
+    blobs: &web::Data<dyn BlobStore>,
+    subject_id: i32,
+    file: NewStoredFile,
+    staged: StagedBlob,
+) -> AppResult<StoredFile> {
+    let _guard = blob_lock(&file.sha256).lock().await;
+    let store = blobs.clone().into_inner();
+    blocking(move || store.commit(staged)).await?;
+    let sha256 = file.sha256.clone();
+    match repos.files.create(subject_id, file).await {
+        Ok(stored) => Ok(stored),
+        Err(error) => {
+            delete_if_unreferenced(repos, blobs, sha256).await;
+            Err(error.into())
+        }
+    }
+}
+
+// Undo the files of a failed upload. Like release_blobs, whatever cannot be removed is only logged.
+async fn discard_files(repos: &Repositories, blobs: &web::Data<dyn BlobStore>, files: Vec<StoredFile>) {
+    let mut hashes = Vec::with_capacity(files.len());
+    for file in files {
+        if let Err(error) = repos.files.delete(file.id).await {
+            log::warn!("Could not delete stored file {}: {}", file.id, error);
+        }
+        hashes.push(file.sha256);
+    }
+    release_blobs(repos, blobs, hashes).await;
+}
+
+pub async fn upload_files(
+    user: AuthenticatedUser,
+    subject_id: web::Path<i32>,
+    mut payload: Multipart,
+    repos: web::Data<Repositories>,
+    blobs: web::Data<dyn BlobStore>,
+    config: web::Data<Config>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::WriteSubjects)?;
+
+    let subject_id = subject_id.into_inner();
+    authorize(&repos, &user, Resource::Subject(subject_id)).await?;
+
+    // A form rejected while it is read only leaves staged files behind, which drop
+    let files = read_files(&mut payload, &blobs, &config).await?;
+    if files.is_empty() {
+        return Err(AppError::BadRequest("upload_missing_file"));
+    }
+
+    // Files are stored one at a time; when one fails, those stored before it are taken back out
+    let mut stored = Vec::with_capacity(files.len());
+    for (file, staged) in files {
+        match store_file(&repos, &blobs, subject_id, file, staged).await {
+            Ok(file) => stored.push(file),
+            Err(error) => {
+                discard_files(&repos, &blobs, stored).await;
+                return Err(error);
+            }
+        }
+    }
+    Ok(HttpResponse::Created().json(stored))
+}
+
+pub async fn get_files(
+    user: AuthenticatedUser,
+    subject_id: web::Path<i32>,
+    repos: web::Data<Repositories>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadSubjects)?;
+
+    let subject_id = subject_id.into_inner();
+    authorize(&repos, &user, Resource::Subject(subject_id)).await?;
+
+    let files = repos.files.list(subject_id).await?;
+    Ok(HttpResponse::Ok().json(files))
+}
+
+// Streams the file from the blob store; Range and conditional requests are handled by NamedFile
+pub async fn download_file(
+    req: HttpRequest,
+    user: AuthenticatedUser,
+    file_id: web::Path<i32>,
+    repos: web::Data<Repositories>,
+    blobs: web::Data<dyn BlobStore>,
+) -> AppResult<HttpResponse> {
+    user.require(Permission::ReadSubjects)?;
+
+    let id = file_id.into_inner();
+    authorize(&repos, &user, Resource::StoredFile(id)).await?;
+
+    let stored = repos.files.find(id).await?.ok_or(STORED_FILE_NOT_FOUND)?;
+    let store = blobs.into_inner();
+    let sha256 = stored.sha256.clone();
+    let blob = blocking(move || store.open(&sha256)).await?;
+
+    let named = NamedFile::from_file(blob, &stored.filename).map_err(AppError::internal)?;
+    // Always a download, so an uploaded page or script never runs as this site
+    let mut disposition = named.content_disposition().clone();
+    disposition.disposition = DispositionType::Attachment;
+    let content_type = stored.mime_type.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let mut response = named.set_content_type(content_type).set_content_disposition(disposition).into_response(&req);
+    response
+        .headers_mut()
+        .insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    Ok(response)
+}
+
+pub async fn delete_file(
+    user: AuthenticatedUser,
+    file_id: web::Path<i32>,
+    repos: web::Data<Repositories>,
+    blobs: web::Data<dyn BlobStore>,
+) -> AppResult<Message> {
+    user.require(Permission::WriteSubjects)?;
+
+    let id = file_id.into_inner();
+    authorize(&repos, &user, Resource::StoredFile(id)).await?;
+
+    let stored = repos.files.find(id).await?.ok_or(STORED_FILE_NOT_FOUND)?;
+    if !repos.files.delete(id).await? {
+        return Err(STORED_FILE_NOT_FOUND);
+    }
+    release_blobs(&repos, &blobs, vec![stored.sha256]).await;
+    Ok(Message("stored_file_deleted"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{app, bearer, fixture, PASSWORD};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{call_and_read_body_json, call_service, read_body, read_body_json, TestRequest};
+    use futures_util::future::{select, Either};
+    use std::path::Path;
+    use std::time::Duration;
+
+    const BOUNDARY: &str = "classmate-boundary";
+
+    // A multipart form with one part per (filename, content type, content); no filename makes a plain field
+    fn form(parts: &[(Option<&str>, &str, &str)]) -> Vec<u8> {
+        let mut body = String::new();
+        for (filename, content_type, content) in parts {
+            body.push_str(&format!("--{}\r\nContent-Disposition: form-data; name=\"file\"", BOUNDARY));
+            if let Some(filename) = filename {
+                body.push_str(&format!("; filename=\"{}\"", filename));
+            }
+            body.push_str(&format!("\r\nContent-Type: {}\r\n\r\n{}\r\n", content_type, content));
+        }
+        body.push_str(&format!("--{}--\r\n", BOUNDARY));
+        body.into_bytes()
+    }
+
+    fn blob_count(dir: &Path) -> usize {
+        std::fs::read_dir(dir.join("uploads").join("blobs"))
+            .unwrap()
+            .map(|dir| std::fs::read_dir(dir.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    fn upload(token: &str, parts: &[(Option<&str>, &str, &str)]) -> TestRequest {
+        TestRequest::post()
+            .uri("/subjects/20/files")
+            .insert_header(bearer(token))
+            .insert_header(("Content-Type", format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(form(parts))
+    }
+
+    #[actix_web::test]
+    async fn upload_download_and_clean_up() {
+        let mut f = fixture();
+        f.config = web::Data::new(Config {
+            max_upload_bytes: 16,
+            ..Config::default()
+        });
+        let app = app!(f);
+        let (dir, alice, bob) = (&f.dir, f.alice_token.as_str(), f.bob_token.as_str());
+
+        let notes = (Some("apuntes.txt"), "text/plain", "0123456789");
+        let resp = call_service(&app, upload(bob, &[notes]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // The same bytes under two names are stored once
+        let copy = (Some("../copia.txt"), "text/plain", "0123456789");
+        let resp = call_service(&app, upload(alice, &[notes, copy]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let files: Vec<serde_json::Value> = read_body_json(resp).await;
+        let names: Vec<_> = files.iter().map(|file| file["filename"].as_str().unwrap()).collect();
+        assert_eq!(names, ["apuntes.txt", "copia.txt"]);
+        assert_eq!((files[0]["size"].as_i64(), files[0]["mime_type"].as_str()), (Some(10), Some("text/plain")));
+        assert_eq!(files[0]["sha256"], files[1]["sha256"]);
+        assert_eq!(blob_count(dir), 1);
+
+        let resp = call_service(&app, upload(alice, &[(None, "text/plain", "sin archivo")]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let big = (Some("grande.bin"), "application/octet-stream", "un archivo demasiado grande");
+        let small = (Some("chico.txt"), "text/plain", "chico");
+        let resp = call_service(&app, upload(alice, &[small, big]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(blob_count(dir), 1);
+        assert_eq!(std::fs::read_dir(dir.join("uploads").join("staging")).unwrap().count(), 0);
+
+        let req = TestRequest::get().uri("/subjects/20/files").insert_header(bearer(alice)).to_request();
+        let listed: Vec<serde_json::Value> = call_and_read_body_json(&app, req).await;
+        assert_eq!(listed.len(), 2);
+
+        let first = files[0]["id"].as_i64().unwrap();
+        let download = |token: &str| {
+            TestRequest::get()
+                .uri(&format!("/files/{}", first))
+                .insert_header(bearer(token))
+                .insert_header(("Range", "bytes=2-5"))
+                .to_request()
+        };
+        assert_eq!(call_service(&app, download(bob)).await.status(), StatusCode::FORBIDDEN);
+        let resp = call_service(&app, download(alice)).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let headers = resp.headers();
+        assert!(headers.get(header::CONTENT_TYPE).unwrap().to_str().unwrap().starts_with("text/plain"));
+        assert!(headers.get(header::CONTENT_DISPOSITION).unwrap().to_str().unwrap().starts_with("attachment"));
+        assert_eq!(read_body(resp).await, "2345");
+
+        // A range past the end keeps its status and Content-Range under the JSON error body
+        let req = TestRequest::get()
+            .uri(&format!("/files/{}", first))
+            .insert_header(bearer(alice))
+            .insert_header(("Range", "bytes=50-60"))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers().get(header::CONTENT_RANGE).unwrap(), "bytes */10");
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "range_not_satisfiable");
+
+        // The blob stays while another file points at it, and goes with the subject
+        let req = TestRequest::delete().uri(&format!("/files/{}", first)).insert_header(bearer(alice)).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(blob_count(dir), 1);
+        let req = TestRequest::delete().uri("/delete_subject/20").insert_header(bearer(alice)).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(blob_count(dir), 0);
+    }
+
+    #[actix_web::test]
+    async fn caps_the_files_of_one_request() {
+        let mut f = fixture();
+        f.config = web::Data::new(Config {
+            max_upload_bytes: 16,
+            max_upload_files: 2,
+            max_upload_request_bytes: 20,
+            ..Config::default()
+        });
+        let app = app!(f);
+
+        let part = |name| (Some(name), "text/plain", "chico");
+        let resp = call_service(&app, upload(&f.alice_token, &[part("a"), part("b"), part("c")]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "upload_too_many_files");
+        // Each fits on its own, not both together
+        let first = (Some("uno.txt"), "text/plain", "0123456789ab");
+        let second = (Some("dos.txt"), "text/plain", "ba9876543210");
+        let resp = call_service(&app, upload(&f.alice_token, &[first, second]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "upload_request_too_large");
+        assert_eq!(blob_count(&f.dir), 0);
+
+        let resp = call_service(&app, upload(&f.alice_token, &[part("a"), part("b")]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    // Hands every call to the disk store, but fails the second commit
+    struct FailingSecondCommit {
+        disk: std::sync::Arc<dyn BlobStore>,
+        commits: std::sync::atomic::AtomicUsize,
+    }
+
+    impl BlobStore for FailingSecondCommit {
+        fn stage(&self) -> io::Result<StagedBlob> {
+            self.disk.stage()
+        }
+
+        fn commit(&self, staged: StagedBlob) -> io::Result<String> {
+            if self.commits.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 1 {
+                return Err(io::Error::other("disk full"));
+            }
+            self.disk.commit(staged)
+        }
+
+        fn open(&self, sha256: &str) -> io::Result<std::fs::File> {
+            self.disk.open(sha256)
+        }
+
+        fn delete(&self, sha256: &str) -> io::Result<()> {
+            self.disk.delete(sha256)
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_failed_file_takes_back_the_ones_stored_before_it() {
+        let mut f = fixture();
+        let failing = FailingSecondCommit {
+            disk: f.blobs.clone().into_inner(),
+            commits: Default::default(),
+        };
+        f.blobs = web::Data::from(std::sync::Arc::new(failing) as std::sync::Arc<dyn BlobStore>);
+        let app = app!(f);
+
+        let first = (Some("uno.txt"), "text/plain", "uno");
+        let second = (Some("dos.txt"), "text/plain", "dos");
+        let resp = call_service(&app, upload(&f.alice_token, &[first, second]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let req = TestRequest::get().uri("/subjects/20/files").insert_header(bearer(&f.alice_token)).to_request();
+        let listed: Vec<serde_json::Value> = call_and_read_body_json(&app, req).await;
+        assert!(listed.is_empty());
+        assert_eq!(blob_count(&f.dir), 0);
+    }
+
+    #[actix_web::test]
+    async fn deleting_the_account_releases_its_blobs() {
+        let f = fixture();
+        let app = app!(f);
+        let tp = (Some("tp.pdf"), "application/pdf", "%PDF");
+        let resp = call_service(&app, upload(&f.alice_token, &[tp]).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(blob_count(&f.dir), 1);
+
+        let req = TestRequest::delete()
+            .uri("/delete_account")
+            .insert_header(bearer(&f.alice_token))
+            .set_json(serde_json::json!({ "password": PASSWORD }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(blob_count(&f.dir), 0);
+    }
+
+    // A blob left over from a deleted file is found already stored by a new upload of the same bytes;
+    // a release that starts before that upload wrote its row has to wait for it and keep the blob
+    #[actix_web::test]
+    async fn release_waits_for_an_upload_of_the_same_content() {
+        let f = fixture();
+        let repos = Repositories::sqlite(f.pool.clone());
+        let mut staged = f.blobs.stage().unwrap();
+        staged.write(b"apuntes").unwrap();
+        let sha256 = f.blobs.commit(staged).unwrap();
+
+        // As store_file holds it between committing and writing the row
+        let upload = blob_lock(&sha256).lock().await;
+        let mut release = Box::pin(release_blobs(&repos, &f.blobs, vec![sha256.clone()]));
+        let waited = select(&mut release, Box::pin(actix_web::rt::time::sleep(Duration::from_millis(100)))).await;
+        assert!(matches!(waited, Either::Right(_)));
+        let file = NewStoredFile {
+            filename: "apuntes.txt".to_string(),
+            mime_type: "text/plain".to_string(),
+            size: 7,
+            sha256: sha256.clone(),
+        };
+        repos.files.create(20, file).await.unwrap();
+        drop(upload);
+        release.await;
+        assert!(f.blobs.open(&sha256).is_ok());
+    }
+
+    #[test]
+    fn keeps_only_the_file_name() {
+        assert_eq!(clean_filename("TP 1.pdf").as_deref(), Some("TP 1.pdf"));
+        assert_eq!(clean_filename("C:\\Users\\ana\\parcial.docx").as_deref(), Some("parcial.docx"));
+        assert_eq!(clean_filename("../../etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(clean_filename("apuntes\r\n.txt").as_deref(), Some("apuntes.txt"));
+        assert_eq!(clean_filename("carpeta/"), None);
+        assert_eq!(clean_filename(".."), None);
+        assert_eq!(clean_filename(&"a".repeat(FILENAME_MAX_LEN + 1)), None);
+    }
+}
@@ -0,0 +1,310 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Deserializer};
+use url::Url;
+
+use crate::i18n::Locale;
+
+// Passwords that are rejected no matter how they score
+const COMMON_PASSWORDS: &str = include_str!("common_passwords.txt");
+
+const USERNAME_MIN_LEN: usize = 3;
+const USERNAME_MAX_LEN: usize = 32;
+const EMAIL_MAX_LEN: usize = 254;
+const PASSWORD_MIN_LEN: usize = 8;
+// bcrypt ignores everything after the 72nd byte
+const PASSWORD_MAX_BYTES: usize = 72;
+// Rough lower bound for the guessing entropy of a password
+const PASSWORD_MIN_BITS: f64 = 30.0;
+// Longest free-text label, such as a subject or professor name
+pub const LABEL_MAX_LEN: usize = 100;
+// What browsers reliably open
+const URL_MAX_LEN: usize = 2048;
+
+// A single rule a field failed; the code doubles as the key of its message
+#[derive(Debug)]
+pub struct FieldError {
+    field: &'static str,
+    code: &'static str,
+}
+
+// Every rule a request failed, answered as a 422 listing them under "errors"
+#[derive(Debug, Default)]
+pub struct ValidationErrors {
+    errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: &'static str, code: &'static str) {
+        self.errors.push(FieldError { field, code });
+    }
+
+    pub fn to_json(&self, locale: Locale) -> serde_json::Value {
+        self.errors
+            .iter()
+            .map(|error| {
+                serde_json::json!({
+                    "field": error.field,
+                    "code": error.code,
+                    "message": locale.text(error.code),
+                })
+            })
+            .collect()
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+// Implemented by request structures that must be checked before use
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+// For PATCH bodies: tells a field sent as null, Some(None), apart from one left out, None.
+// Use it with #[serde(default, deserialize_with = "nullable")].
+pub fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// Names and labels are stored trimmed and may not end up empty
+pub fn validate_label(field: &'static str, value: &str, code: &'static str, errors: &mut ValidationErrors) {
+    let len = value.trim().chars().count();
+    if len == 0 || len > LABEL_MAX_LEN {
+        errors.add(field, code);
+    }
+}
+
+pub fn validate_max_len(field: &'static str, value: &str, max: usize, code: &'static str, errors: &mut ValidationErrors) {
+    if value.trim().chars().count() > max {
+        errors.add(field, code);
+    }
+}
+
+// Colors are sent the way CSS writes them, as #rrggbb
+pub fn validate_color(field: &'static str, color: &str, errors: &mut ValidationErrors) {
+    let valid = color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit());
+    if !valid {
+        errors.add(field, "color_invalid");
+    }
+}
+
+// Only web addresses, so a stored link can never run script (javascript:) or reach local files (file:)
+pub fn validate_http_url(field: &'static str, url: &str, errors: &mut ValidationErrors) {
+    let url = url.trim();
+    let valid = url.len() <= URL_MAX_LEN
+        && Url::parse(url).is_ok_and(|parsed| {
+            matches!(parsed.scheme(), "http" | "https") && parsed.host_str().is_some_and(|host| !host.is_empty())
+        });
+    if !valid {
+        errors.add(field, "url_invalid");
+    }
+}
+
+// Local date and time the way they are stored, which sorts chronologically as text
+pub const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+// Accepts an ISO 8601 date ("2024-07-01", taken as midnight), a local date and time with or without
+// seconds, or one with an offset, which keeps the wall-clock time it was written with
+pub fn normalize_datetime(value: &str) -> Option<String> {
+    let value = value.trim();
+    let parsed = NaiveDateTime::parse_from_str(value, DATETIME_FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| DateTime::parse_from_rfc3339(value).map(|datetime| datetime.naive_local()))
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default()))
+        .ok()?;
+    Some(parsed.format(DATETIME_FORMAT).to_string())
+}
+
+pub fn validate_datetime(field: &'static str, value: &str, code: &'static str, errors: &mut ValidationErrors) {
+    if normalize_datetime(value).is_none() {
+        errors.add(field, code);
+    }
+}
+
+// Usernames are case-insensitive, so they are stored trimmed and lowercased
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+pub fn validate_username(field: &'static str, username: &str, errors: &mut ValidationErrors) {
+    let username = normalize_username(username);
+    let len = username.chars().count();
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+        errors.add(field, "username_length");
+    }
+    let valid_chars = username
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '-'));
+    let starts_alphanumeric = !username.starts_with(|c: char| !c.is_ascii_alphanumeric());
+    if !valid_chars || !starts_alphanumeric {
+        errors.add(field, "username_charset");
+    }
+}
+
+// Emails are compared case-insensitively and stored trimmed and lowercased
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// Only a sanity check; the reset email itself proves the address works
+pub fn validate_email(field: &'static str, email: &str, errors: &mut ValidationErrors) {
+    let email = normalize_email(email);
+    let well_formed = match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(char::is_whitespace)
+        }
+        None => false,
+    };
+    if !well_formed || email.len() > EMAIL_MAX_LEN {
+        errors.add(field, "email_invalid");
+    }
+}
+
+pub fn validate_password(field: &'static str, password: &str, username: &str, errors: &mut ValidationErrors) {
+    if password.chars().count() < PASSWORD_MIN_LEN {
+        errors.add(field, "password_too_short");
+    }
+    if password.len() > PASSWORD_MAX_BYTES {
+        errors.add(field, "password_too_long");
+    }
+    let lowered = password.to_lowercase();
+    if is_common_password(&lowered) {
+        errors.add(field, "password_common");
+    } else if password_entropy_bits(password) < PASSWORD_MIN_BITS {
+        errors.add(field, "password_too_weak");
+    }
+    if !username.trim().is_empty() && lowered.contains(&normalize_username(username)) {
+        errors.add(field, "password_contains_username");
+    }
+}
+
+fn is_common_password(lowered: &str) -> bool {
+    COMMON_PASSWORDS
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .any(|line| line.trim() == lowered)
+}
+
+// Entropy estimate from the character classes used and how many distinct characters appear
+fn password_entropy_bits(password: &str) -> f64 {
+    let mut pool = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password.chars().any(|c| c.is_ascii_punctuation() || c == ' ') {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+    if pool == 0 {
+        return 0.0;
+    }
+
+    let mut distinct: Vec<char> = password.chars().collect();
+    distinct.sort_unstable();
+    distinct.dedup();
+    distinct.len() as f64 * (pool as f64).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(errors: ValidationErrors) -> Vec<&'static str> {
+        errors.errors.into_iter().map(|error| error.code).collect()
+    }
+
+    #[test]
+    fn rejects_bad_usernames() {
+        let mut errors = ValidationErrors::default();
+        validate_username("username", "", &mut errors);
+        validate_username("username", "-juan", &mut errors);
+        validate_username("username", "juan perez", &mut errors);
+        assert_eq!(
+            codes(errors),
+            vec!["username_length", "username_charset", "username_charset"]
+        );
+
+        let mut errors = ValidationErrors::default();
+        validate_username("username", "  Juan.Perez_99 ", &mut errors);
+        assert!(errors.into_result().is_ok());
+        assert_eq!(normalize_username("  Juan.Perez_99 "), "juan.perez_99");
+    }
+
+    #[test]
+    fn rejects_malformed_emails() {
+        for email in ["", "alice", "alice@", "@example.com", "alice@example", "a b@example.com", "a@b@c.com"] {
+            let mut errors = ValidationErrors::default();
+            validate_email("email", email, &mut errors);
+            assert!(errors.into_result().is_err(), "{}", email);
+        }
+        let mut errors = ValidationErrors::default();
+        validate_email("email", " Alice@Example.com ", &mut errors);
+        assert!(errors.into_result().is_ok());
+    }
+
+    #[test]
+    fn accepts_only_web_urls() {
+        let rejected = ["javascript:alert(1)", "file:///etc/passwd", "ftp://example.com/a", "example.com", "https://", ""];
+        for url in rejected {
+            let mut errors = ValidationErrors::default();
+            validate_http_url("url", url, &mut errors);
+            assert_eq!(codes(errors), vec!["url_invalid"], "{}", url);
+        }
+        for url in ["https://campus.example.edu/apuntes.pdf", " http://localhost:8080/tp?id=1 "] {
+            let mut errors = ValidationErrors::default();
+            validate_http_url("url", url, &mut errors);
+            assert!(errors.into_result().is_ok(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn normalizes_iso_datetimes() {
+        assert_eq!(normalize_datetime("2024-07-01").as_deref(), Some("2024-07-01T00:00:00"));
+        assert_eq!(normalize_datetime(" 2024-07-01T09:30 ").as_deref(), Some("2024-07-01T09:30:00"));
+        assert_eq!(normalize_datetime("2024-07-01T09:30:15").as_deref(), Some("2024-07-01T09:30:15"));
+        assert_eq!(normalize_datetime("2024-07-01T09:30:00-03:00").as_deref(), Some("2024-07-01T09:30:00"));
+        for value in ["", "01/07/2024", "2024-02-30", "2024-07-01T25:00", "mañana"] {
+            assert_eq!(normalize_datetime(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn rejects_weak_passwords() {
+        for (password, code) in [
+            ("a", "password_too_short"),
+            ("Password1", "password_common"),
+            ("aaaaaaaaaaaa", "password_too_weak"),
+            ("xjuanx-2024!", "password_contains_username"),
+        ] {
+            let mut errors = ValidationErrors::default();
+            validate_password("password", password, "juan", &mut errors);
+            assert!(codes(errors).contains(&code), "{}", password);
+        }
+
+        let mut errors = ValidationErrors::default();
+        validate_password("password", "correcto-caballo-bateria", "juan", &mut errors);
+        assert!(errors.into_result().is_ok());
+    }
+}
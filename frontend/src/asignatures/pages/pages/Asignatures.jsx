@@ -24,7 +24,7 @@ const Subject = () => {
     }
     
     try {
-      const response = await axios.get(`http://127.0.0.1:8080/get_subjects/${user_id}`);
+      const response = await axios.get(`http://127.0.0.1:8080/get_subjects`);
       setSubjects(response.data);
     } catch (error) {
       console.error('Error fetching subjects:', error);
@@ -108,7 +108,7 @@ const Subject = () => {
     }
 
     try {
-      await axios.post('http://127.0.0.1:8080/add_subject', { name: newSubjectTitle, user_id: parseInt(user_id) });
+      await axios.post('http://127.0.0.1:8080/add_subject', { name: newSubjectTitle });
       setShowModal(false);
       setNewSubjectTitle('');
       fetchSubjects();
@@ -17,6 +17,8 @@ const Login = () => {
         password,
       });
       if (response.status === 200) {
+        localStorage.setItem('token', response.data.token);
+        localStorage.setItem('refresh_token', response.data.refresh_token);
         localStorage.setItem('user_id', response.data.user_id);
         localStorage.setItem('username', username);
         navigate('/dashboard');
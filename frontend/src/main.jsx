@@ -1,8 +1,41 @@
 import React from 'react';
 import { createRoot } from 'react-dom/client'; // Importa createRoot
+import axios from 'axios';
 import './index.css';
 import App from './App';
 
+// Envía el token de acceso en cada petición al backend
+axios.interceptors.request.use((config) => {
+  const token = localStorage.getItem('token');
+  if (token) {
+    config.headers.Authorization = `Bearer ${token}`;
+  }
+  return config;
+});
+
+// Renueva el token de acceso vencido con el refresh token y reintenta la petición una vez
+axios.interceptors.response.use(
+  (response) => response,
+  async (error) => {
+    const original = error.config;
+    const refreshToken = localStorage.getItem('refresh_token');
+    if (error.response?.status !== 401 || !refreshToken || original._retried || original.url.endsWith('/refresh')) {
+      return Promise.reject(error);
+    }
+    original._retried = true;
+    try {
+      const response = await axios.post('http://127.0.0.1:8080/refresh', { refresh_token: refreshToken });
+      localStorage.setItem('token', response.data.token);
+      localStorage.setItem('refresh_token', response.data.refresh_token);
+      return axios(original);
+    } catch (refreshError) {
+      localStorage.removeItem('token');
+      localStorage.removeItem('refresh_token');
+      return Promise.reject(refreshError);
+    }
+  }
+);
+
 const container = document.getElementById('root');
 const root = createRoot(container); // Crea el root
 
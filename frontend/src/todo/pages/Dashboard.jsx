@@ -1,5 +1,6 @@
 import React, { useEffect, useState } from 'react';
 import { useNavigate } from 'react-router-dom';
+import axios from 'axios';
 import Header from '../../ui/components/Header';
 import Navbar from '../../ui/components/Navbar';
 import todo from '../assets/Todo.png';
@@ -16,7 +17,14 @@ const Dashboard = () => {
     }
   }, []);
 
-  const handleLogout = () => {
+  const handleLogout = async () => {
+    try {
+      await axios.post('http://127.0.0.1:8080/logout');
+    } catch (error) {
+      console.error('Error logging out:', error);
+    }
+    localStorage.removeItem('token');
+    localStorage.removeItem('refresh_token');
     localStorage.removeItem('user_id');
     localStorage.removeItem('username');
     navigate('/login');
@@ -23,7 +23,7 @@ const ToDo = () => {
     }
 
     try {
-      const response = await axios.get(`http://127.0.0.1:8080/get_tasks/${user_id}`);
+      const response = await axios.get(`http://127.0.0.1:8080/get_tasks`);
       setTasks(response.data);
     } catch (error) {
       console.error('Error fetching tasks:', error);
@@ -51,7 +51,6 @@ const ToDo = () => {
       await axios.post('http://127.0.0.1:8080/add_task', {
         title: newTaskTitle,
         status: statusMap[currentColumn],
-        user_id: parseInt(user_id),
       });
 
       fetchTasks();